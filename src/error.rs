@@ -0,0 +1,123 @@
+use std::fmt;
+
+use rustpython_ast::text_size::{TextRange, TextSize};
+
+use crate::options::PythonVersion;
+use crate::version::Feature;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UnparseErrorKind {
+    /// An identifier contains non-ASCII characters but ASCII-only output was requested.
+    NonAsciiIdentifier(String),
+    /// An f-string can only be written with the syntax of PEP 701, e.g. because a replacement
+    /// field contains a string with both kinds of quotes or an escape sequence, but the target
+    /// version is older than 3.12 or not set.
+    FStringNeedsPep701,
+    /// The AST uses syntax that the target version can't parse.
+    UnsupportedFeature {
+        feature: Feature,
+        target: PythonVersion,
+    },
+    /// Downleveling can't rewrite a use of `feature` into equivalent code for the target version.
+    CannotDownlevel {
+        feature: Feature,
+        target: PythonVersion,
+        /// Why the use can't be rewritten, e.g. "it's inside a comprehension".
+        reason: &'static str,
+    },
+    /// A PEP 484 type comment can't be converted into annotations, e.g. because it isn't
+    /// valid syntax or doesn't match the targets or parameters it annotates.
+    InvalidTypeComment(String),
+}
+
+/// An AST that can't be unparsed with the requested options.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnparseError {
+    pub kind: UnparseErrorKind,
+    /// Range of the offending node in the original source.
+    pub range: TextRange,
+    /// Where `range` starts, if the unparser knows the original source.
+    pub location: Option<SourceLocation>,
+}
+
+/// A position in a source file. Lines and columns are counted from 1,
+/// and columns in characters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl SourceLocation {
+    /// The location of `offset` in `source`, `None` if it is out of bounds
+    /// or not at a character boundary.
+    pub fn find(source: &str, offset: TextSize) -> Option<Self> {
+        let before = source.get(..usize::from(offset))?;
+        let line_start = before.rfind('\n').map_or(0, |index| index + 1);
+        Some(SourceLocation {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        })
+    }
+}
+
+impl UnparseError {
+    /// Fills in the location of the error from the `source` its range refers to.
+    pub(crate) fn locate(&mut self, source: &str) {
+        if self.location.is_none() {
+            self.location = SourceLocation::find(source, self.range.start());
+        }
+    }
+}
+
+impl fmt::Display for UnparseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            UnparseErrorKind::NonAsciiIdentifier(name) => write!(
+                f,
+                "identifier `{}` can't be written in ASCII-only output",
+                name
+            )?,
+            UnparseErrorKind::FStringNeedsPep701 => write!(
+                f,
+                "f-string can't be written without the syntax of Python 3.12"
+            )?,
+            UnparseErrorKind::UnsupportedFeature { feature, target } => write!(
+                f,
+                "{} requires Python {}, but the target version is {}",
+                feature,
+                feature.version(),
+                target
+            )?,
+            UnparseErrorKind::CannotDownlevel {
+                feature,
+                target,
+                reason,
+            } => write!(
+                f,
+                "{} can't be rewritten for Python {} because {}",
+                feature, target, reason
+            )?,
+            UnparseErrorKind::InvalidTypeComment(type_comment) => write!(
+                f,
+                "type comment `{}` can't be converted into annotations",
+                type_comment
+            )?,
+        }
+        match self.location {
+            Some(location) => write!(
+                f,
+                " (at line {}, column {})",
+                location.line, location.column
+            ),
+            None => write!(
+                f,
+                " (at {}..{})",
+                u32::from(self.range.start()),
+                u32::from(self.range.end())
+            ),
+        }
+    }
+}
+
+impl std::error::Error for UnparseError {}
@@ -1,6 +1,24 @@
+pub mod error;
+mod literal;
+mod lower;
+pub mod options;
+mod stub;
 pub mod unparser;
+pub mod version;
 
+pub use crate::error::{SourceLocation, UnparseError, UnparseErrorKind};
+pub use crate::lower::lower;
+pub use crate::options::{
+    pep8_blank_lines, BlankLines, BlankLinesContext, FStringLowering, IntRadix,
+    NonAsciiIdentifiers, PositionalOnlyLowering, PythonVersion, QuoteStyle, StmtKind, TupleParens,
+    TypeAliasLowering, TypeCommentConversion, UnparserOptions,
+};
+pub use crate::stub::stub;
 pub use crate::unparser::Unparser;
+pub use crate::version::{
+    analyze_expr_version, analyze_version, analyze_version_with_source, Feature, VersionReport,
+    VersionRequirement,
+};
 
 #[cfg(test)]
 mod tests {
@@ -8,6 +26,7 @@ mod tests {
     use pretty_assertions::assert_eq;
     use rustpython_ast::text_size::TextRange;
     use rustpython_ast::Fold;
+    use rustpython_ast::Stmt;
     use rustpython_ast::TextSize;
     use rustpython_parser::ast::Suite;
     use rustpython_parser::Parse;
@@ -77,33 +96,1917 @@ mod tests {
                 let new_file_name = old_file_name.replace(".py", "_unparsed.py");
                 let new_entry_path_str = format!("{}/{}", results_folder, new_file_name);
                 let new_entry_path = Path::new(&new_entry_path_str);
-                fs::write(&new_entry_path, &new_source)?;
-                let new_stmts =
-                    Suite::parse(&new_source, new_entry_path.to_str().unwrap()).unwrap();
-                // erase range information
-                let mut eraser = RangesEraser {};
-                let mut erased_new_stmts = Vec::new();
-                for stmt in &new_stmts {
-                    erased_new_stmts.push(eraser.fold_stmt(stmt.to_owned()).unwrap());
-                }
+                fs::write(new_entry_path, &new_source)?;
+                assert_same_ast(&stmts, &new_source, new_entry_path.to_str().unwrap());
+            }
+        }
+        Ok(())
+    }
 
-                let mut erased_stmts = Vec::new();
-                for stmt in &stmts {
-                    erased_stmts.push(eraser.fold_stmt(stmt.to_owned()).unwrap());
-                }
+    fn assert_same_ast(stmts: &Suite, new_source: &str, new_source_path: &str) {
+        let new_stmts = Suite::parse(new_source, new_source_path).unwrap();
+        // erase range information
+        let mut eraser = RangesEraser {};
+        let mut erased_new_stmts = Vec::new();
+        for stmt in &new_stmts {
+            erased_new_stmts.push(eraser.fold_stmt(stmt.to_owned()).unwrap());
+        }
+
+        let mut erased_stmts = Vec::new();
+        for stmt in stmts {
+            erased_stmts.push(eraser.fold_stmt(stmt.to_owned()).unwrap());
+        }
+
+        for (stmt, new_stmt) in erased_stmts.iter().zip(erased_new_stmts.iter()) {
+            assert_eq!(stmt, new_stmt)
+        }
+    }
 
-                for (stmt, new_stmt) in erased_stmts.iter().zip(erased_new_stmts.iter()) {
-                    assert_eq!(stmt, new_stmt)
+    /// Unparses every file in `source_folder` with `options` and checks that the ASTs match,
+    /// without writing the results to disk.
+    fn run_tests_on_folder_with_options(
+        source_folder: &str,
+        options: &UnparserOptions,
+    ) -> io::Result<()> {
+        for entry in fs::read_dir(source_folder)? {
+            let entry_path = entry?.path();
+            if entry_path.extension().is_some_and(|ext| ext == "py") {
+                let file_content = fs::read_to_string(&entry_path)?;
+                let entry_path_str = entry_path.to_str().unwrap();
+                let mut unparser = Unparser::with_options(options.clone());
+                let stmts = Suite::parse(&file_content, entry_path_str).unwrap();
+                for stmt in &stmts {
+                    unparser.unparse_stmt(stmt);
                 }
+                assert_same_ast(&stmts, &unparser.source, entry_path_str);
             }
         }
         Ok(())
     }
 
+    fn unparse_with_options(source: &str, options: UnparserOptions) -> String {
+        let mut unparser = Unparser::with_options(options);
+        let stmts = Suite::parse(source, "<test>").unwrap();
+        unparser.unparse_suite(&stmts);
+        unparser.source
+    }
+
+    fn unparse_with_original_source(source: &str, options: UnparserOptions) -> String {
+        let mut unparser = Unparser::with_options(options).with_original_source(source);
+        let stmts = Suite::parse(source, "<test>").unwrap();
+        for stmt in &stmts {
+            unparser.unparse_stmt(stmt);
+        }
+        unparser.source
+    }
+
+    /// Unparses every file in `source_folder` with its original source and checks that the ASTs match.
+    fn run_tests_on_folder_with_original_source(source_folder: &str) {
+        for entry in fs::read_dir(source_folder).unwrap() {
+            let entry_path = entry.unwrap().path();
+            if entry_path.extension().is_some_and(|ext| ext == "py") {
+                let file_content = fs::read_to_string(&entry_path).unwrap();
+                let entry_path_str = entry_path.to_str().unwrap();
+                let stmts = Suite::parse(&file_content, entry_path_str).unwrap();
+                let new_source =
+                    unparse_with_original_source(&file_content, UnparserOptions::default());
+                assert_same_ast(&stmts, &new_source, entry_path_str);
+            }
+        }
+    }
+
     #[test]
     fn test_predefined_files() -> io::Result<()> {
         run_tests_on_folders("./test_files", "./test_files_unparsed")
     }
+
+    #[test]
+    fn test_tuple_parens() {
+        let source = "a, b = b, a\nfor k, v in x:\n    return k, v\nx[1, 2]\nx[1:2, 3]";
+        assert_eq!(
+            unparse_with_options(source, UnparserOptions::default()),
+            "a, b = b, a\nfor k, v in x:\n    return k, v\nx[1, 2]\nx[1:2, 3]"
+        );
+        let options = UnparserOptions {
+            tuple_parens: TupleParens::Always,
+            ..Default::default()
+        };
+        assert_eq!(
+            unparse_with_options(source, options.clone()),
+            "(a, b) = (b, a)\nfor (k, v) in x:\n    return (k, v)\nx[(1, 2)]\nx[1:2, 3]"
+        );
+        run_tests_on_folder_with_options("./test_files", &options).unwrap();
+    }
+
+    #[test]
+    fn test_fully_parenthesized() {
+        let options = UnparserOptions {
+            fully_parenthesized: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            unparse_with_options("a + b * c\nx = not a or -b ** c < d", options.clone()),
+            "(a + (b * c))\nx = ((not a) or ((-(b ** c)) < d))"
+        );
+        run_tests_on_folder_with_options("./test_files", &options).unwrap();
+    }
+
+    #[test]
+    fn test_quote_styles() {
+        // the first statement would be a docstring
+        let source = r#"pass
+'it\'s'
+'say "hi"'
+'plain'
+b'bytes'"#;
+        assert_eq!(
+            unparse_with_options(source, UnparserOptions::default()),
+            r#"pass
+"it's"
+'say "hi"'
+'plain'
+b'bytes'"#
+        );
+        let options = UnparserOptions {
+            quote_style: QuoteStyle::Double,
+            ..Default::default()
+        };
+        assert_eq!(
+            unparse_with_options(source, options.clone()),
+            r#"pass
+"it's"
+"say \"hi\""
+"plain"
+b"bytes""#
+        );
+        run_tests_on_folder_with_options("./test_files", &options).unwrap();
+    }
+
+    #[test]
+    fn test_triple_quoted_and_raw_strings() {
+        let options = UnparserOptions {
+            triple_quote_multiline: true,
+            raw_strings: true,
+            ..Default::default()
+        };
+        let source = r#"pass
+'a\nb'
+'a\n""'
+'C:\\dir'
+'a\\'
+'tab\t\\'
+u'\\'"#;
+        assert_eq!(
+            unparse_with_options(source, options.clone()),
+            r#"pass
+"""a
+b"""
+'''a
+""'''
+r'C:\dir'
+'a\\'
+'tab\t\\'
+u'\\'"#
+        );
+        run_tests_on_folder_with_options("./test_files", &options).unwrap();
+    }
+
+    #[test]
+    fn test_ascii_only() {
+        let options = UnparserOptions {
+            ascii_only: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            unparse_with_options(r#"x = "café \U0001f600" + f"é{x}""#, options.clone()),
+            r#"x = 'caf\xe9 \U0001f600' + f'\xe9{x}'"#
+        );
+        run_tests_on_folder_with_options("./test_files", &options).unwrap();
+
+        // escapes in replacement fields need Python 3.12
+        let stmts = Suite::parse(r#"f"{'é'}""#, "<test>").unwrap();
+        let mut unparser = Unparser::with_options(options.clone());
+        let error = &unparser.try_unparse_stmt(&stmts[0]).unwrap_err()[0];
+        assert_eq!(error.kind, UnparseErrorKind::FStringNeedsPep701);
+
+        let stmts = Suite::parse("café = 1", "<test>").unwrap();
+        let mut unparser = Unparser::with_options(options.clone());
+        let error = &unparser.try_unparse_stmt(&stmts[0]).unwrap_err()[0];
+        assert_eq!(
+            error.kind,
+            UnparseErrorKind::NonAsciiIdentifier("café".to_string())
+        );
+
+        let mut unparser = Unparser::with_options(UnparserOptions {
+            non_ascii_identifiers: NonAsciiIdentifiers::Mangle,
+            ..options
+        });
+        unparser.try_unparse_stmt(&stmts[0]).unwrap();
+        assert_eq!(unparser.source, "caf_u00e9_ = 1");
+
+        // the text of a self-documenting field is code, so it's written as a literal instead
+        let stmts = Suite::parse(r#"print(f"{é=}")"#, "<test>").unwrap();
+        unparser.source.clear();
+        unparser.try_unparse_stmt(&stmts[0]).unwrap();
+        assert_eq!(unparser.source, r#"print(f'\xe9={_u00e9_!r}')"#);
+        let mut unparser = Unparser::with_options(UnparserOptions {
+            ascii_only: true,
+            ..Default::default()
+        });
+        let error = &unparser.try_unparse_stmt(&stmts[0]).unwrap_err()[0];
+        assert_eq!(
+            error.kind,
+            UnparseErrorKind::NonAsciiIdentifier("é".to_string())
+        );
+    }
+
+    #[test]
+    fn test_normalize_docstrings() {
+        let options = UnparserOptions {
+            normalize_docstrings: true,
+            ..Default::default()
+        };
+        let source = r#"class A:
+  def f():
+   '''
+   Summary.
+
+     Details.
+   '''
+   'no docstring'"#;
+        assert_eq!(
+            unparse_with_options(source, options.clone()),
+            r#"class A:
+    def f():
+        """Summary.
+
+          Details.
+        """
+        'no docstring'"#
+        );
+
+        // a synthesized definition without a body doesn't make the next statement a docstring
+        let mut stmts = Suite::parse("def f():\n    pass\n'no\\ndocstring'", "<test>").unwrap();
+        if let Stmt::FunctionDef(data) = &mut stmts[0] {
+            data.body.clear();
+        }
+        let mut unparser = Unparser::new();
+        unparser.unparse_suite(&stmts);
+        assert_eq!(unparser.source, "def f():\n'no\\ndocstring'");
+
+        // only spaces and tabs are indentation
+        let source = "def f():\n    '''Summary.\n\n      \u{3000}wide\n    '''";
+        assert_eq!(
+            unparse_with_options(source, options),
+            "def f():\n    \"\"\"Summary.\n\n    \\u3000wide\n    \"\"\""
+        );
+    }
+
+    #[test]
+    fn test_blank_lines() {
+        let source = r#"import os
+from sys import path
+x = 1
+@decorator
+class A:
+    y = 2
+    def f(self):
+        def g():
+            pass
+        return g
+    def h(self):
+        pass
+def f():
+    pass
+x = 2"#;
+        let options = UnparserOptions {
+            blank_lines: BlankLines::Pep8,
+            ..Default::default()
+        };
+        assert_eq!(
+            unparse_with_options(source, options.clone()),
+            r#"import os
+from sys import path
+
+x = 1
+
+
+@decorator
+class A:
+    y = 2
+
+    def f(self):
+        def g():
+            pass
+
+        return g
+
+    def h(self):
+        pass
+
+
+def f():
+    pass
+
+
+x = 2"#
+        );
+        run_tests_on_folder_with_options("./test_files", &options).unwrap();
+
+        fn methods_only(context: &BlankLinesContext) -> usize {
+            if context.in_class && context.next == StmtKind::FunctionDef {
+                pep8_blank_lines(context)
+            } else {
+                0
+            }
+        }
+        let options = UnparserOptions {
+            blank_lines: BlankLines::Custom(methods_only),
+            ..Default::default()
+        };
+        assert_eq!(
+            unparse_with_options(
+                "class A:\n    def f(self): pass\n    def g(self): pass\nx = 1",
+                options
+            ),
+            "class A:\n    def f(self):\n        pass\n\n    def g(self):\n        pass\nx = 1"
+        );
+    }
+
+    #[test]
+    fn test_keep_blank_lines() {
+        let source = r#"import os
+x = 1
+
+
+
+y = 2  # comment
+
+# comment
+z = 3
+@decorator
+
+def f():
+    a = 1
+
+    return a; b
+
+
+
+class A: pass"#;
+        let options = UnparserOptions {
+            blank_lines: BlankLines::Pep8,
+            keep_blank_lines: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            unparse_with_original_source(source, options),
+            r#"import os
+
+x = 1
+
+y = 2
+
+z = 3
+
+
+@decorator
+def f():
+    a = 1
+
+    return a
+    b
+
+
+class A:
+    pass"#
+        );
+    }
+
+    #[test]
+    fn test_number_spelling() {
+        let source = "x = 0xFF_FF + 0o755 + 1_000_000 + 0b1010 + 1_0.5e-3 + 2J + 0XFF .real";
+        assert_eq!(
+            unparse_with_original_source(source, UnparserOptions::default()),
+            source
+        );
+        assert_eq!(
+            unparse_with_options(source, UnparserOptions::default()),
+            "x = 65535 + 493 + 1000000 + 10 + 0.0105 + 2.0j + 255 .real"
+        );
+        // NaN has no literal, so it's written as the difference of two infinities
+        let mut stmts = Suite::parse("x = 1.0\ny = 1j", "<test>").unwrap();
+        if let Stmt::Assign(data) = &mut stmts[0] {
+            if let rustpython_ast::Expr::Constant(constant) = data.value.as_mut() {
+                constant.value = rustpython_ast::Constant::Float(f64::NAN);
+            }
+        }
+        if let Stmt::Assign(data) = &mut stmts[1] {
+            if let rustpython_ast::Expr::Constant(constant) = data.value.as_mut() {
+                constant.value = rustpython_ast::Constant::Complex {
+                    real: 1.5,
+                    imag: f64::NAN,
+                };
+            }
+        }
+        let mut unparser = Unparser::new();
+        unparser.unparse_suite(&stmts);
+        assert_eq!(
+            unparser.source,
+            "x = (1e309-1e309)\ny = (1.5+(1e309j-1e309j))"
+        );
+        let options = UnparserOptions {
+            int_radix: IntRadix::Hexadecimal,
+            int_digit_grouping: Some(4),
+            ..Default::default()
+        };
+        assert_eq!(
+            unparse_with_options("x = 65535 + 1000000", options.clone()),
+            "x = 0xffff + 0xf_4240"
+        );
+        let options = UnparserOptions {
+            int_digit_grouping: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(
+            unparse_with_options("x = 100 + 1000000", options.clone()),
+            "x = 100 + 1_000_000"
+        );
+        run_tests_on_folder_with_options("./test_files", &options).unwrap();
+    }
+
+    #[test]
+    fn test_string_spelling() {
+        let source = r#""""Doc string."""
+x = r'\d+' + rb"\x" + b'\x00' + 'tab\t' + u"u"
+y = ("abc"  # comment
+     'def')
+z = "a" "b" \
+    'c'
+w = "\N{EM DASH}"
+f(f"{'x' 'y'}")
+g("(a"
+  'b',
+  ["c" '['
+   "d"])"#;
+        assert_eq!(
+            unparse_with_original_source(source, UnparserOptions::default()),
+            r#""""Doc string."""
+x = r'\d+' + rb"\x" + b'\x00' + 'tab\t' + u"u"
+y = ("abc"  # comment
+     'def')
+z = "a" "b" \
+    'c'
+w = '—'
+f(f"{'x' 'y'}")
+g("(a"
+  'b', ["c" '['
+   "d"])"#
+        );
+        run_tests_on_folder_with_original_source("./test_files");
+    }
+
+    #[test]
+    fn test_split_long_strings() {
+        let options = UnparserOptions {
+            max_line_length: Some(30),
+            ..Default::default()
+        };
+        let source = r#"x = "aaaa bbbb cccc dddd eeee ffff gggg"
+y = f"a value {x + 1:>{width}} and {y!r} of the result"
+z = b"tab\tseparated\tbytes that are long"
+short = "a b""#;
+        assert_eq!(
+            unparse_with_options(source, options.clone()),
+            r#"x = ('aaaa bbbb cccc dddd '
+     'eeee ffff gggg')
+y = (f'a value '
+     f'{x + 1:>{width}} and '
+     f'{y!r} of the result')
+z = (b'tab\tseparated\t'
+     b'bytes that are long')
+short = 'a b'"#
+        );
+
+        // all chunks use the same quotes, and values of patterns aren't split
+        let source = r#"x = "it's a string with \"quotes\" \"in\" it"
+match x:
+    case {"aaaa bbbb cccc dddd eeee ffff": _}:
+        pass"#;
+        assert_eq!(
+            unparse_with_options(source, options.clone()),
+            r#"x = ('it\'s a string with '
+     '"quotes" "in" it')
+match x:
+    case {'aaaa bbbb cccc dddd eeee ffff': _}:
+        pass"#
+        );
+        run_tests_on_folder_with_options("./test_files", &options).unwrap();
+
+        // long literals are split in one pass
+        let value = "a\\b 'c' ".repeat(50_000);
+        let source = format!("x = {:?}", value);
+        let options = UnparserOptions {
+            max_line_length: Some(2000),
+            raw_strings: true,
+            ..Default::default()
+        };
+        let unparsed = unparse_with_options(&source, options);
+        assert!(unparsed.lines().all(|line| line.chars().count() <= 2000));
+        let stmts = Suite::parse(&unparsed, "<test>").unwrap();
+        let Stmt::Assign(assign) = &stmts[0] else {
+            unreachable!()
+        };
+        let rustpython_ast::Expr::Constant(constant) = assign.value.as_ref() else {
+            unreachable!()
+        };
+        assert_eq!(constant.value, rustpython_ast::Constant::Str(value));
+    }
+
+    #[test]
+    fn test_fstring_quotes() {
+        let source = r#"f"{x['a']}"
+f"{f'{x!r}'}"
+f"""{x['it"s']}""""#;
+        assert_eq!(
+            unparse_with_options(source, UnparserOptions::default()),
+            r#"f'{x["a"]}'
+f'{f"{x!r}"}'
+f"{x['it"s']}""#
+        );
+
+        let stmts = Suite::parse(source, "<test>").unwrap();
+        let mut unparser = Unparser::with_options(UnparserOptions {
+            target_version: Some(PythonVersion::PY311),
+            ..Default::default()
+        });
+        unparser.try_unparse_stmt(&stmts[1]).unwrap();
+        let error = &unparser.try_unparse_stmt(&stmts[2]).unwrap_err()[0];
+        assert_eq!(error.kind, UnparseErrorKind::FStringNeedsPep701);
+        // without a target version, the output has to be valid before Python 3.12 too
+        let mut unparser = Unparser::new();
+        let error = &unparser.try_unparse_stmt(&stmts[2]).unwrap_err()[0];
+        assert_eq!(error.kind, UnparseErrorKind::FStringNeedsPep701);
+
+        let options = UnparserOptions {
+            target_version: Some(PythonVersion::PY312),
+            ..Default::default()
+        };
+        assert_eq!(
+            unparse_with_options(source, options.clone()),
+            r#"f'{x['a']}'
+f'{f'{x!r}'}'
+f'{x['it"s']}'"#
+        );
+
+        // replacement fields can span lines since Python 3.12
+        let source = r#"f'''{x + """a
+b"""}'''"#;
+        let options = UnparserOptions {
+            triple_quote_multiline: true,
+            ..options
+        };
+        assert_eq!(
+            unparse_with_options(source, options.clone()),
+            r#"f'{x + """a
+b"""}'"#
+        );
+        let stmts = Suite::parse(source, "<test>").unwrap();
+        let mut unparser = Unparser::with_options(UnparserOptions {
+            target_version: Some(PythonVersion::PY311),
+            ..options
+        });
+        let error = &unparser.try_unparse_stmt(&stmts[0]).unwrap_err()[0];
+        assert_eq!(error.kind, UnparseErrorKind::FStringNeedsPep701);
+    }
+
+    #[test]
+    fn test_self_documenting_fstrings() {
+        let source = r#"f"a{x=}"
+f"{x + 1 = }"
+f"{ x=:>10}"
+f"{x=!s:>{width}}"
+f"{x=!a}"
+f"x={x!r}"
+f"x={x}"
+f"{x+1=}""#;
+        assert_eq!(
+            unparse_with_options(source, UnparserOptions::default()),
+            r#"f'a{x=}'
+f'{x + 1 = }'
+f'{ x=:>10}'
+f'{x=!s:>{width}}'
+f'{x=!a}'
+f'{x=}'
+f'x={x}'
+f'x+1={x + 1!r}'"#
+        );
+        assert!(
+            unparse_with_original_source(source, UnparserOptions::default()).ends_with("f'{x+1=}'")
+        );
+    }
+
+    #[test]
+    fn test_fstring_format_specs() {
+        // braces can't be escaped in a format spec, so the parser never produces them literally
+        let mut stmts = Suite::parse(r#"f"{x!a:X>{width}}""#, "<test>").unwrap();
+        let rustpython_ast::Stmt::Expr(stmt) = &mut stmts[0] else {
+            unreachable!()
+        };
+        let rustpython_ast::Expr::JoinedStr(joined_str) = stmt.value.as_mut() else {
+            unreachable!()
+        };
+        let rustpython_ast::Expr::FormattedValue(formatted_value) = &mut joined_str.values[0]
+        else {
+            unreachable!()
+        };
+        let Some(rustpython_ast::Expr::JoinedStr(spec)) =
+            formatted_value.format_spec.as_deref_mut()
+        else {
+            unreachable!()
+        };
+        let rustpython_ast::Expr::Constant(constant) = &mut spec.values[0] else {
+            unreachable!()
+        };
+        constant.value = rustpython_ast::Constant::Str("{}>".to_string());
+
+        let mut unparser = Unparser::new();
+        unparser.unparse_stmt(&stmts[0]);
+        assert_eq!(unparser.source, r#"f'{x!a:{"{"}{"}"}>{width}}'"#);
+    }
+
+    #[test]
+    fn test_analyze_version() {
+        let source = r#"import os
+def f(a, /, b):
+    if (n := len(a)) > b:
+        return n
+@buttons[0].connect
+def g():
+    match x:
+        case 1 if (y := x):
+            pass
+try:
+    pass
+except* ValueError:
+    pass
+x[1:2, *a]
+x[*a, b]
+type X = int
+class A[T]:
+    pass"#;
+        let stmts = Suite::parse(source, "<test>").unwrap();
+        let report = analyze_version(&stmts);
+        let requirements: Vec<(Feature, &str)> = report
+            .requirements
+            .iter()
+            .map(|requirement| (requirement.feature, requirement.path.as_str()))
+            .collect();
+        assert_eq!(
+            requirements,
+            vec![
+                (Feature::PositionalOnlyParameters, "body[1].args"),
+                (Feature::AssignmentExpression, "body[1].body[0].test.left"),
+                (Feature::RelaxedDecorators, "body[2].decorator_list[0]"),
+                (Feature::MatchStatement, "body[2].body[0]"),
+                (
+                    Feature::AssignmentExpression,
+                    "body[2].body[0].cases[0].guard"
+                ),
+                (Feature::ExceptStar, "body[3]"),
+                (Feature::StarredSubscript, "body[4].value"),
+                (Feature::TypeAlias, "body[6]"),
+                (Feature::TypeParameters, "body[7].type_params[0]"),
+            ]
+        );
+        let bumps: Vec<Feature> = report
+            .bumps()
+            .iter()
+            .map(|requirement| requirement.feature)
+            .collect();
+        assert_eq!(
+            bumps,
+            vec![
+                Feature::PositionalOnlyParameters,
+                Feature::RelaxedDecorators,
+                Feature::MatchStatement,
+                Feature::ExceptStar,
+                Feature::TypeAlias,
+            ]
+        );
+        assert_eq!(report.minimum_version(), PythonVersion::PY312);
+        assert_eq!(
+            report.requirements[0].to_string(),
+            "positional-only parameters requires Python 3.8 (at body[1].args)"
+        );
+
+        let stmts = Suite::parse("f'{x}'\nf\"\"\"{x['it\"s']}\"\"\"", "<test>").unwrap();
+        let report = analyze_version(&stmts);
+        assert_eq!(report.requirements.len(), 1);
+        assert_eq!(report.requirements[0].feature, Feature::Pep701FString);
+        assert_eq!(report.requirements[0].path, "body[1].value");
+
+        let stmts = Suite::parse("x = 1", "<test>").unwrap();
+        assert_eq!(
+            analyze_version(&stmts).minimum_version(),
+            PythonVersion::PY37
+        );
+
+        let source = r#"with (a as b, c as d):
+    pass
+with (a, b):
+    pass
+with (
+    a,  # (
+    b,
+):
+    pass
+with ((a) as b):
+    pass
+with (a):
+    pass
+with (a) as b, (c):
+    pass
+with (a, b) as c:
+    pass"#;
+        let stmts = Suite::parse(source, "<test>").unwrap();
+        assert!(analyze_version(&stmts).requirements.is_empty());
+        let paths: Vec<String> = analyze_version_with_source(&stmts, source)
+            .requirements
+            .into_iter()
+            .map(|requirement| {
+                assert_eq!(requirement.feature, Feature::ParenthesizedContextManagers);
+                requirement.path
+            })
+            .collect();
+        assert_eq!(paths, vec!["body[0]", "body[1]", "body[2]", "body[3]"]);
+    }
+
+    #[test]
+    fn test_target_version() {
+        let cases = [
+            (
+                "match x:\n    case 1:\n        pass",
+                PythonVersion::PY39,
+                "match statement requires Python 3.10, but the target version is 3.9 (at 0..33)",
+            ),
+            (
+                "try:\n    pass\nexcept* ValueError:\n    pass",
+                PythonVersion::PY310,
+                "except* requires Python 3.11, but the target version is 3.10 (at 0..42)",
+            ),
+            (
+                "type X = int",
+                PythonVersion::PY311,
+                "type alias statement requires Python 3.12, but the target version is 3.11 (at 0..12)",
+            ),
+            (
+                "def f[T](x: T):\n    pass",
+                PythonVersion::PY311,
+                "type parameters requires Python 3.12, but the target version is 3.11 (at 6..7)",
+            ),
+            (
+                "if (n := 1):\n    pass",
+                PythonVersion::PY37,
+                "assignment expression requires Python 3.8, but the target version is 3.7 (at 4..10)",
+            ),
+            (
+                "def f(a, /):\n    pass",
+                PythonVersion::PY37,
+                "positional-only parameters requires Python 3.8, but the target version is 3.7 (at 6..7)",
+            ),
+        ];
+        for (source, target_version, message) in cases {
+            let stmts = Suite::parse(source, "<test>").unwrap();
+            let mut unparser = Unparser::with_options(UnparserOptions {
+                target_version: Some(target_version),
+                ..Default::default()
+            });
+            let error = &unparser.try_unparse_stmt(&stmts[0]).unwrap_err()[0];
+            assert_eq!(error.to_string(), message);
+
+            let mut unparser = Unparser::with_options(UnparserOptions {
+                target_version: Some(PythonVersion::PY312),
+                ..Default::default()
+            });
+            assert!(unparser.try_unparse_stmt(&stmts[0]).is_ok());
+        }
+
+        // starred subscripts are parenthesized unless they contain slices
+        let stmts = Suite::parse(
+            "x[*a, b]
+x[1:2, *a]",
+            "<test>",
+        )
+        .unwrap();
+        let mut unparser = Unparser::with_options(UnparserOptions {
+            target_version: Some(PythonVersion::PY37),
+            ..Default::default()
+        });
+        unparser.try_unparse_stmt(&stmts[0]).unwrap();
+        assert_eq!(unparser.source, "x[(*a, b)]");
+        let error = &unparser.try_unparse_stmt(&stmts[1]).unwrap_err()[0];
+        assert_eq!(unparser.source, "x[(*a, b)]");
+        assert_eq!(
+            error.kind,
+            UnparseErrorKind::UnsupportedFeature {
+                feature: Feature::StarredSubscript,
+                target: PythonVersion::PY37
+            }
+        );
+
+        // with the original source, errors point to a line and column
+        let source = "x = 1\nif (n := 'é') or (m := 2):\n    pass";
+        let stmts = Suite::parse(source, "<test>").unwrap();
+        let mut unparser = Unparser::with_options(UnparserOptions {
+            target_version: Some(PythonVersion::PY37),
+            ..Default::default()
+        })
+        .with_original_source(source);
+        // all errors are returned and nothing is written
+        let errors = unparser.try_unparse_suite(&stmts).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(unparser.source, "");
+        assert!(unparser.errors().is_empty());
+        let error = &errors[0];
+        assert_eq!(error.location, Some(SourceLocation { line: 2, column: 5 }));
+        assert_eq!(
+            error.to_string(),
+            "assignment expression requires Python 3.8, but the target version is 3.7 (at line 2, column 5)"
+        );
+        // columns count characters
+        let mut unparser = Unparser::with_options(UnparserOptions {
+            target_version: Some(PythonVersion::PY37),
+            ..Default::default()
+        })
+        .with_original_source(source);
+        unparser.unparse_suite(&stmts);
+        let locations: Vec<Option<SourceLocation>> = unparser
+            .errors()
+            .iter()
+            .map(|error| error.location)
+            .collect();
+        assert_eq!(
+            locations,
+            vec![
+                Some(SourceLocation { line: 2, column: 5 }),
+                Some(SourceLocation {
+                    line: 2,
+                    column: 19
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_downlevel_type_params() {
+        let source = r#""""Docstring."""
+from __future__ import annotations
+type Alias = int
+type Pair[T] = tuple[T, T]
+def first[T: (int, str), *Ts](x: T, *rest: *Ts) -> T:
+    return x
+class Box[T: Sized, **P](Base, metaclass=Meta):
+    def map[U](self, f: Callable[[T], U]) -> Box[U]:
+        pass"#;
+        let options = UnparserOptions {
+            target_version: Some(PythonVersion::PY311),
+            downlevel: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            unparse_with_options(source, options.clone()),
+            r#""""Docstring."""
+from __future__ import annotations
+from typing import Generic, ParamSpec, TypeAlias, TypeVarTuple
+from typing_extensions import TypeVar
+Alias: TypeAlias = 'int'
+T = TypeVar('T', infer_variance=True)
+Pair: TypeAlias = 'tuple[T, T]'
+T_2 = TypeVar('T_2', 'int', 'str', infer_variance=True)
+Ts = TypeVarTuple('Ts')
+def first(x: T_2, *rest: *Ts) -> T_2:
+    return x
+T_3 = TypeVar('T_3', bound='Sized', infer_variance=True)
+P = ParamSpec('P')
+class Box(Base, Generic[T_3, P], metaclass=Meta):
+    U = TypeVar('U', infer_variance=True)
+    def map(self, f: Callable[[T_3], U]) -> Box[U]:
+        pass"#
+        );
+
+        let source = "type Pair[T] = tuple[T, T]\nclass Row[*Ts]:\n    pass";
+        assert_eq!(
+            unparse_with_options(
+                source,
+                UnparserOptions {
+                    target_version: Some(PythonVersion::PY39),
+                    type_alias_lowering: TypeAliasLowering::TypeAliasType,
+                    ..options.clone()
+                }
+            ),
+            r#"from typing import Generic
+from typing_extensions import TypeAliasType, TypeVar, TypeVarTuple, Unpack
+T = TypeVar('T', infer_variance=True)
+Pair = TypeAliasType('Pair', 'tuple[T, T]', type_params=(T,))
+Ts = TypeVarTuple('Ts')
+class Row(Generic[Unpack[Ts]]):
+    pass"#
+        );
+
+        // hoisted type parameters don't overwrite other bindings,
+        // aliases can refer to themselves and unpacking needs `Unpack` before 3.11
+        let source = r#"T = 5
+type Tree = list[Tree] | Leaf
+def f[T](x: T) -> T:
+    def g[T](y: T) -> T:
+        return y
+    return g(x)
+def g[*Ts](*args: *Ts) -> tuple[int, *Ts]:
+    return (1, *args)
+class Leaf:
+    pass
+print(T, g(2))"#;
+        let lowered = unparse_with_options(
+            source,
+            UnparserOptions {
+                target_version: Some(PythonVersion::PY310),
+                ..options.clone()
+            },
+        );
+        assert_eq!(
+            lowered,
+            r#"from typing import TypeAlias
+from typing_extensions import TypeVar, TypeVarTuple, Unpack
+T = 5
+Tree: TypeAlias = 'list[Tree] | Leaf'
+T_2 = TypeVar('T_2', infer_variance=True)
+def f(x: T_2) -> T_2:
+    T_3 = TypeVar('T_3', infer_variance=True)
+    def g(y: T_3) -> T_3:
+        return y
+    return g(x)
+Ts = TypeVarTuple('Ts')
+def g(*args: Unpack[Ts]) -> tuple[int, Unpack[Ts]]:
+    return (1, *args)
+class Leaf:
+    pass
+print(T, g(2))"#
+        );
+        if let Some(output) = run_python(&lowered) {
+            assert_eq!(output, "5 (1, 2)\n");
+        }
+
+        // bounds and constraints can refer to names defined later
+        let source = r#"class Node[T: Tree, U: (Tree, "Leaf")]:
+    pass
+class Tree:
+    pass
+class Leaf:
+    pass
+bounded, constrained = Node.__parameters__
+print(bounded.__bound__, constrained.__constraints__)"#;
+        let lowered = unparse_with_options(
+            source,
+            UnparserOptions {
+                target_version: Some(PythonVersion::PY311),
+                ..options.clone()
+            },
+        );
+        assert_eq!(
+            lowered,
+            r#"from typing import Generic
+from typing_extensions import TypeVar
+T = TypeVar('T', bound='Tree', infer_variance=True)
+U = TypeVar('U', 'Tree', 'Leaf', infer_variance=True)
+class Node(Generic[T, U]):
+    pass
+class Tree:
+    pass
+class Leaf:
+    pass
+bounded, constrained = Node.__parameters__
+print(bounded.__bound__, constrained.__constraints__)"#
+        );
+        if let Some(output) = run_python(&lowered) {
+            assert_eq!(
+                output,
+                "ForwardRef('Tree') (ForwardRef('Tree'), ForwardRef('Leaf'))\n"
+            );
+        }
+
+        let source = "type Alias = int";
+        assert_eq!(
+            unparse_with_options(
+                source,
+                UnparserOptions {
+                    target_version: Some(PythonVersion::PY312),
+                    ..options
+                }
+            ),
+            source
+        );
+    }
+
+    /// Runs `source` with the local `python3`, `None` if there is none.
+    fn run_python(source: &str) -> Option<String> {
+        let output = std::process::Command::new("python3")
+            .args(["-c", source])
+            .output()
+            .ok()?;
+        assert!(
+            output.status.success(),
+            "{}\n{}",
+            source,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        Some(String::from_utf8(output.stdout).unwrap())
+    }
+
+    #[test]
+    fn test_downlevel_match() {
+        let source = r#"match command.split():
+    case [action]:
+        pass
+    case ["go", direction] | ["move", direction] if exits:
+        go(direction)
+    case {"x": x, **rest}:
+        pass
+    case Point(x, y=0) as point:
+        pass
+    case _:
+        pass"#;
+        let options = UnparserOptions {
+            target_version: Some(PythonVersion::PY39),
+            downlevel: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            unparse_with_options(source, options.clone()),
+            r#"import collections.abc
+_match_subject = command.split()
+_match_missing = object()
+_matched = False
+if isinstance(_match_subject, collections.abc.Sequence) and (not isinstance(_match_subject, (str, bytes, bytearray))) and (len(_match_subject) == 1):
+    _matched = True
+    action = _match_subject[0]
+    pass
+if not _matched and (isinstance(_match_subject, collections.abc.Sequence) and (not isinstance(_match_subject, (str, bytes, bytearray))) and (len(_match_subject) == 2) and (_match_subject[0] == 'go') or (isinstance(_match_subject, collections.abc.Sequence) and (not isinstance(_match_subject, (str, bytes, bytearray))) and (len(_match_subject) == 2) and (_match_subject[0] == 'move'))) and exits:
+    _matched = True
+    direction = _match_subject[1]
+    go(direction)
+if not _matched and isinstance(_match_subject, collections.abc.Mapping):
+    _match_item = _match_subject.get('x', _match_missing)
+    if _match_item is not _match_missing:
+        _matched = True
+        x = _match_item
+        rest = dict(_match_subject)
+        del rest['x']
+        pass
+if not _matched and isinstance(_match_subject, Point):
+    _match_args = getattr(Point, '__match_args__', ())
+    if len(_match_args) < 1:
+        raise TypeError('%s() accepts %d positional sub-patterns (%d given)' % (Point.__name__, len(_match_args), 1))
+    _match_item_2 = getattr(_match_subject, _match_args[0], _match_missing)
+    if _match_item_2 is not _match_missing:
+        _match_item_3 = getattr(_match_subject, 'y', _match_missing)
+        if _match_item_3 is not _match_missing and _match_item_3 == 0:
+            _matched = True
+            x = _match_item_2
+            point = _match_subject
+            pass
+if not _matched:
+    pass"#
+        );
+
+        let source = r#"match value:
+    case int(n) if n > 0:
+        positive(n)
+    case 0:
+        zero()
+    case other if other:
+        truthy(other)"#;
+        assert_eq!(
+            unparse_with_options(source, options.clone()),
+            r#"_matched = False
+if isinstance(value, int):
+    n = value
+    if n > 0:
+        _matched = True
+        positive(n)
+if not _matched and value == 0:
+    _matched = True
+    zero()
+if not _matched:
+    other = value
+    if other:
+        truthy(other)"#
+        );
+
+        let source = fs::read_to_string("test_files/match_semantics.py").unwrap();
+        let stmts = Suite::parse(&source, "match_semantics.py").unwrap();
+        let mut unparser = Unparser::with_options(options);
+        unparser.unparse_suite(&stmts);
+        assert!(unparser.errors().is_empty());
+        let lowered = unparser.source;
+        let lowered_stmts = Suite::parse(&lowered, "<lowered>").unwrap();
+        assert!(analyze_version(&lowered_stmts).minimum_version() < PythonVersion::PY310);
+        if let Some(expected) = run_python(&source) {
+            assert_eq!(run_python(&lowered).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_downlevel_named_exprs() {
+        let source = r#"if (match := pattern.search(line)) is not None:
+    print(match.group(0))
+elif (n := len(line)) > 10:
+    pass
+while (chunk := file.read(8192)):
+    process(chunk)
+else:
+    done()
+print(f((y := 2), y))
+value = [(z := i) for i in range(3)]
+if ready and (item := queue.pop()):
+    pass
+print(a + (b := bump()))"#;
+        let options = UnparserOptions {
+            target_version: Some(PythonVersion::PY37),
+            downlevel: true,
+            ..Default::default()
+        };
+        let stmts = Suite::parse(source, "<test>").unwrap();
+        let mut unparser = Unparser::with_options(options.clone());
+        unparser.unparse_suite(&stmts);
+        assert_eq!(
+            unparser.source,
+            r#"match = pattern.search(line)
+if match is not None:
+    print(match.group(0))
+else:
+    n = len(line)
+    if n > 10:
+        pass
+while True:
+    chunk = file.read(8192)
+    if not chunk:
+        done()
+        break
+    process(chunk)
+y = 2
+print(f(y, y))
+value = [(z := i) for i in range(3)]
+if ready and (item := queue.pop()):
+    pass
+print(a + (b := bump()))"#
+        );
+        let messages: Vec<String> = unparser
+            .errors()
+            .iter()
+            .map(|error| error.to_string())
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "assignment expression can't be rewritten for Python 3.7 because it's inside a comprehension (at 213..219)",
+                "assignment expression can't be rewritten for Python 3.7 because it's only evaluated depending on the expression before it (at 254..273)",
+                "assignment expression can't be rewritten for Python 3.7 because hoisting it would evaluate it before code it's written after (at 296..307)",
+            ]
+        );
+
+        let source = r#"def numbers():
+    yield from [3, 0, 7, 12]
+def run():
+    values = iter(numbers())
+    total = 0
+    while (value := next(values, None)) is not None:
+        if value == 0:
+            continue
+        total += value
+    else:
+        print("done", total)
+    if (double := total * 2) > 40:
+        print("big", double)
+    elif (half := total // 2) > 5:
+        print("half", half)
+    counter = 0
+    while (counter := counter + 1) < 3:
+        print(counter)
+run()"#;
+        let stmts = Suite::parse(source, "<test>").unwrap();
+        let mut unparser = Unparser::with_options(options);
+        unparser.unparse_suite(&stmts);
+        assert!(unparser.errors().is_empty());
+        let lowered_stmts = Suite::parse(&unparser.source, "<lowered>").unwrap();
+        assert_eq!(
+            analyze_version(&lowered_stmts).minimum_version(),
+            PythonVersion::PY37
+        );
+        if let Some(expected) = run_python(source) {
+            assert_eq!(run_python(&unparser.source).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_downlevel_try_star() {
+        let source = r#"try:
+    connect()
+except* (ConnectionError, TimeoutError) as group:
+    log(group)
+    raise
+except* ValueError:
+    pass
+else:
+    done()
+finally:
+    close()"#;
+        let options = UnparserOptions {
+            target_version: Some(PythonVersion::PY310),
+            downlevel: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            unparse_with_options(source, options.clone()),
+            r#"from exceptiongroup import BaseExceptionGroup
+try:
+    connect()
+except BaseException as _caught:
+    _group = _caught if isinstance(_caught, BaseExceptionGroup) else BaseExceptionGroup('', [_caught])
+    _unhandled = _group
+    _handled = False
+    _raised = []
+    _reraised = []
+    _matching, _unhandled = _unhandled.split((ConnectionError, TimeoutError))
+    if _matching is not None:
+        _handled = True
+        try:
+            group = _matching
+            log(group)
+            raise _matching
+        except BaseException as _handler_exception:
+            if _handler_exception is _matching:
+                _reraised.append(_handler_exception)
+            else:
+                _raised.append(_handler_exception)
+    _matching, _unhandled = _unhandled.split(ValueError) if _unhandled is not None else (None, None)
+    if _matching is not None:
+        _handled = True
+        try:
+            pass
+        except BaseException as _handler_exception:
+            if _handler_exception is _matching:
+                _reraised.append(_handler_exception)
+            else:
+                _raised.append(_handler_exception)
+    if not _handled:
+        raise 
+    if _unhandled is not None:
+        _reraised.append(_unhandled)
+    if _reraised:
+        _raised.append(_group.subgroup(lambda _leaf: not isinstance(_leaf, BaseExceptionGroup) and any((_part.subgroup(lambda _other: _other is _leaf) is not None for _part in _reraised))))
+    if len(_raised) == 1:
+        raise _raised[0]
+    if _raised:
+        raise BaseExceptionGroup('', _raised)
+else:
+    done()
+finally:
+    close()"#
+        );
+
+        let source = r#"def check(exception):
+    try:
+        if exception is not None:
+            raise exception
+    except* ValueError as group:
+        print("value", [str(e) for e in group.exceptions])
+    except* KeyError:
+        print("key")
+        raise RuntimeError("from handler")
+    except* TypeError:
+        raise
+    else:
+        print("no exception")
+    finally:
+        print("finally")
+for exception in [
+    None,
+    ValueError("a"),
+    ExceptionGroup("g", [ValueError("b"), ValueError("c")]),
+    ExceptionGroup("g", [ValueError("d"), OSError("e")]),
+    KeyError("f"),
+    TypeError("g"),
+    OSError("h"),
+    ExceptionGroup("g", [KeyError("i"), OSError("j")]),
+    ExceptionGroup("g", [TypeError("k"), ValueError("l"), ExceptionGroup("n", [OSError("m")])]),
+]:
+    try:
+        check(exception)
+    except BaseException as caught:
+        if isinstance(caught, BaseExceptionGroup):
+            print(type(caught).__name__, sorted(repr(e) for e in caught.exceptions))
+        else:
+            print(type(caught).__name__, caught)"#;
+        let stmts = Suite::parse(source, "<test>").unwrap();
+        let mut unparser = Unparser::with_options(options);
+        unparser.unparse_suite(&stmts);
+        assert!(unparser.errors().is_empty());
+        let lowered_stmts = Suite::parse(&unparser.source, "<lowered>").unwrap();
+        assert!(analyze_version(&lowered_stmts).minimum_version() < PythonVersion::PY311);
+        // Python 3.11 has the backport's exception groups built in.
+        let lowered = format!(
+            "import builtins, sys\nsys.modules['exceptiongroup'] = builtins\n{}",
+            unparser.source
+        );
+        if let Some(expected) = run_python(source) {
+            assert_eq!(run_python(&lowered).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_downlevel_fstrings() {
+        let source = r#"print(f"{name!r:>{width}} has {{braces}} and {count:,} items")
+print(f"{value=}", f"{f'{inner}'!s}", f"plain {{")
+print(f"{a!r} is 100% {b!a}", f"{c}")"#;
+        let options = UnparserOptions {
+            downlevel: true,
+            fstring_lowering: FStringLowering::Format,
+            ..Default::default()
+        };
+        assert_eq!(
+            unparse_with_options(source, options.clone()),
+            r#"print('{!r:>{}} has {{braces}} and {:,} items'.format(name, width, count))
+print('value={!r}'.format(value), '{!s}'.format('{}'.format(inner)), 'plain {')
+print('{!r} is 100% {!a}'.format(a, b), '{}'.format(c))"#
+        );
+        assert_eq!(
+            unparse_with_options(
+                source,
+                UnparserOptions {
+                    fstring_lowering: FStringLowering::PercentFormat,
+                    ..options.clone()
+                }
+            ),
+            r#"print('{!r:>{}} has {{braces}} and {:,} items'.format(name, width, count))
+print('value=%r' % (value,), '%s' % ('{}'.format(inner),), 'plain {')
+print('%r is 100%% %a' % (a, b), '{}'.format(c))"#
+        );
+
+        let source = r#"calls = []
+def track(value):
+    calls.append(value)
+    return value
+class Money:
+    def __format__(self, spec):
+        return "$" + spec
+    def __repr__(self):
+        return "Money()"
+width = 8
+print(f"{track('a')!r:>{track(width)}}|{track(3.14159):.{track(2)}f}|{track('b')}")
+print(f"{Money()} {Money():usd} {Money()!r} {(1, 2)!s} {'é'!a} 100% {{}}")
+print(f"{f'{track(1)}{track(2)}'}{track(3)}")
+print(calls)"#;
+        let expected = run_python(source);
+        for fstring_lowering in [FStringLowering::Format, FStringLowering::PercentFormat] {
+            let stmts = Suite::parse(source, "<test>").unwrap();
+            let mut unparser = Unparser::with_options(UnparserOptions {
+                fstring_lowering,
+                ..options.clone()
+            });
+            unparser.unparse_suite(&stmts);
+            assert!(!unparser.source.contains("f'") && !unparser.source.contains("f\""));
+            if let Some(expected) = &expected {
+                assert_eq!(&run_python(&unparser.source).unwrap(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_downlevel_positional_only() {
+        let source = r#"def clamp(value, low=0, /, high=10, *, strict=False):
+    """Clamp a value."""
+    def check(value):
+        return value
+    get = lambda: value
+    return max(low, min(value, high))
+def pair(first: int, second=(), /, **options):
+    return first, second, options
+key = lambda item, /: item[0]
+def scoped(name, /):
+    class Namespace:
+        value = name
+    return Namespace
+def show(item, /, detail):
+    return locals()
+def rebind(path, sep, error, /):
+    import posixpath as path
+    from os import sep
+    try:
+        pass
+    except OSError as error:
+        pass"#;
+        let options = UnparserOptions {
+            target_version: Some(PythonVersion::PY37),
+            downlevel: true,
+            ..Default::default()
+        };
+        let stmts = Suite::parse(source, "<test>").unwrap();
+        let mut unparser = Unparser::with_options(options.clone());
+        unparser.unparse_suite(&stmts);
+        assert_eq!(
+            unparser.source,
+            r#"def clamp(__value, __low=0, high=10, *, strict=False):
+    """Clamp a value."""
+    def check(value):
+        return value
+    get = lambda : __value
+    return max(__low, min(__value, high))
+def pair(__first: int, __second=(), **options):
+    return __first, __second, options
+key = lambda __item: __item[0]
+def scoped(*args):
+    if len(args) != 1:
+        raise TypeError('scoped() takes 1 positional argument but %d were given' % len(args))
+    name = args[0]
+    class Namespace:
+        value = name
+    return Namespace
+def show(item, /,detail):
+    return locals()
+def rebind(__path, __sep, __error):
+    import posixpath as __path
+    from os import sep as __sep
+    try:
+        pass
+    except OSError as __error:
+        pass"#
+        );
+        let messages: Vec<String> = unparser
+            .errors()
+            .iter()
+            .map(|error| error.to_string())
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "positional-only parameters can't be rewritten for Python 3.7 because the function looks up its local variables by name (at 388..392)",
+            ]
+        );
+
+        let stmts = Suite::parse(source, "<test>").unwrap();
+        let mut unparser = Unparser::with_options(UnparserOptions {
+            positional_only_lowering: PositionalOnlyLowering::ArgsShim,
+            ..options.clone()
+        });
+        unparser.unparse_suite(&stmts);
+        assert_eq!(
+            unparser.source,
+            r#"def clamp(__value, __low=0, high=10, *, strict=False):
+    """Clamp a value."""
+    def check(value):
+        return value
+    get = lambda : __value
+    return max(__low, min(__value, high))
+def pair(*args, **options):
+    if not 1 <= len(args) <= 2:
+        raise TypeError('pair() takes from 1 to 2 positional arguments but %d were given' % len(args))
+    first: int = args[0]
+    second = args[1] if len(args) > 1 else ()
+    return first, second, options
+key = lambda __item: __item[0]
+def scoped(*args):
+    if len(args) != 1:
+        raise TypeError('scoped() takes 1 positional argument but %d were given' % len(args))
+    name = args[0]
+    class Namespace:
+        value = name
+    return Namespace
+def show(item, /,detail):
+    return locals()
+def rebind(*args):
+    if len(args) != 3:
+        raise TypeError('rebind() takes 3 positional arguments but %d were given' % len(args))
+    path = args[0]
+    sep = args[1]
+    error = args[2]
+    import posixpath as path
+    from os import sep
+    try:
+        pass
+    except OSError as error:
+        pass"#
+        );
+
+        let source = r#"def pair(first, second=(), /, **options):
+    return first, second, options
+def clamp(value, low=0, /, high=10):
+    return max(low, min(value, high))
+def rebind(path, error, item, /):
+    import posixpath as path
+    try:
+        raise ValueError(error)
+    except ValueError as error:
+        message = str(error)
+    match item:
+        case [item, *rest]:
+            pass
+    return path.basename(path.sep + 'b'), message, item, rest
+def plain(sep, /):
+    from os import sep
+    return sep
+def named(helper, /):
+    def helper():
+        return 'nested'
+    return helper.__name__, helper()
+def dotted(os, /):
+    import os.path
+    return os.path.sep
+for call in [
+    lambda: pair(1),
+    lambda: pair(1, 2, first=3, second=4),
+    lambda: pair(),
+    lambda: pair(1, 2, 3),
+    lambda: clamp(50),
+    lambda: clamp(-5, 1, high=3),
+    lambda: clamp(value=1),
+    lambda: (lambda x, /: x * 2)(4),
+    lambda: rebind('/a/b', 'boom', [1, 2]),
+    lambda: plain(0),
+    lambda: named(0),
+    lambda: dotted(0),
+]:
+    try:
+        print(call())
+    except TypeError as error:
+        print(type(error).__name__)"#;
+        let expected = run_python(source);
+        for positional_only_lowering in [
+            PositionalOnlyLowering::DunderPrefix,
+            PositionalOnlyLowering::ArgsShim,
+        ] {
+            let stmts = Suite::parse(source, "<test>").unwrap();
+            let mut unparser = Unparser::with_options(UnparserOptions {
+                positional_only_lowering,
+                ..options.clone()
+            });
+            unparser.unparse_suite(&stmts);
+            assert!(unparser.errors().is_empty());
+            if let Some(expected) = &expected {
+                assert_eq!(&run_python(&unparser.source).unwrap(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_with_items_line_length() {
+        let source = r#"with open(source) as src, open(destination, "w") as dst, lock:
+    dst.write(src.read())
+async def copy():
+    async with session.get(url) as response, session.post(other) as upload:
+        pass
+with a, b:
+    pass"#;
+        let options = UnparserOptions {
+            max_line_length: Some(50),
+            target_version: Some(PythonVersion::PY39),
+            ..Default::default()
+        };
+        assert_eq!(
+            unparse_with_options(source, options.clone()),
+            r#"with open(source) as src:
+    with open(destination, 'w') as dst, lock:
+        dst.write(src.read())
+async def copy():
+    async with session.get(url) as response:
+        async with session.post(other) as upload:
+            pass
+with a, b:
+    pass"#
+        );
+        assert_eq!(
+            unparse_with_options(
+                source,
+                UnparserOptions {
+                    target_version: Some(PythonVersion::PY310),
+                    ..options
+                }
+            ),
+            r#"with (
+    open(source) as src,
+    open(destination, 'w') as dst,
+    lock,
+):
+    dst.write(src.read())
+async def copy():
+    async with (
+        session.get(url) as response,
+        session.post(other) as upload,
+    ):
+        pass
+with a, b:
+    pass"#
+        );
+    }
+
+    #[test]
+    fn test_type_comments() {
+        // rustpython's parser doesn't keep type comments, so they're added to the parsed AST
+        let source = r#"x = []
+a, (b, *c) = 1, (2, 3)
+for i in range(3):
+    pass
+with open(path) as f, lock:
+    pass
+def add(self, value, *rest, **options):
+    return value
+def first(items: list, default):
+    pass
+y = z = None
+w = 1"#;
+        let mut stmts = Suite::parse(source, "<test>").unwrap();
+        let type_comments = [
+            "list[int]",
+            "int, (str, list[int])",
+            "int",
+            "IO[str]",
+            "(int, *str, **bool) -> int",
+            "(List[int], Optional[int]) -> str",
+            "Optional[int]",
+            "ignore[assignment]",
+        ];
+        for (stmt, type_comment) in stmts.iter_mut().zip(type_comments) {
+            let type_comment = Some(type_comment.to_string());
+            match stmt {
+                Stmt::Assign(data) => data.type_comment = type_comment,
+                Stmt::For(data) => data.type_comment = type_comment,
+                Stmt::With(data) => data.type_comment = type_comment,
+                Stmt::FunctionDef(data) => data.type_comment = type_comment,
+                _ => unreachable!(),
+            }
+        }
+        let unparse = |type_comments| {
+            let mut unparser = Unparser::with_options(UnparserOptions {
+                downlevel: true,
+                type_comments,
+                ..Default::default()
+            });
+            unparser.unparse_suite(&stmts);
+            unparser.source
+        };
+        assert_eq!(
+            unparse(TypeCommentConversion::Keep),
+            r#"x = []  # type: list[int]
+a, (b, *c) = 1, (2, 3)  # type: int, (str, list[int])
+for i in range(3):  # type: int
+    pass
+with open(path) as f, lock:  # type: IO[str]
+    pass
+def add(self, value, *rest, **options):  # type: (int, *str, **bool) -> int
+    return value
+def first(items: list, default):  # type: (List[int], Optional[int]) -> str
+    pass
+y = z = None  # type: Optional[int]
+w = 1  # type: ignore[assignment]"#
+        );
+        // converting them into annotations parses them, which needs rustpython's parser
+        #[cfg(feature = "type-comments")]
+        {
+            assert_eq!(
+                unparse(TypeCommentConversion::ToAnnotations),
+                r#"x: list[int] = []
+a: int
+b: str
+c: list[int]
+a, (b, *c) = 1, (2, 3)
+i: int
+for i in range(3):
+    pass
+f: IO[str]
+with open(path) as f, lock:
+    pass
+def add(self, value: int, *rest: str, **options: bool) -> int:
+    return value
+def first(items: list, default: Optional[int]) -> str:
+    pass
+y: Optional[int]
+z: Optional[int]
+y = z = None
+w = 1  # type: ignore[assignment]"#
+            );
+
+            let mut stmts = Suite::parse("x = 1\ndef f(a, b):\n    pass", "<test>").unwrap();
+            if let Stmt::Assign(data) = &mut stmts[0] {
+                data.type_comment = Some("list[".to_string());
+            }
+            if let Stmt::FunctionDef(data) = &mut stmts[1] {
+                data.type_comment = Some("(int, int, int) -> None".to_string());
+            }
+            let mut unparser = Unparser::with_options(UnparserOptions {
+                downlevel: true,
+                type_comments: TypeCommentConversion::ToAnnotations,
+                ..Default::default()
+            });
+            unparser.unparse_suite(&stmts);
+            assert_eq!(
+                unparser.source,
+                "x = 1  # type: list[\ndef f(a, b):  # type: (int, int, int) -> None\n    pass"
+            );
+            assert!(matches!(
+                unparser.errors(),
+                [
+                    UnparseError {
+                        kind: UnparseErrorKind::InvalidTypeComment(_),
+                        ..
+                    },
+                    UnparseError {
+                        kind: UnparseErrorKind::InvalidTypeComment(_),
+                        ..
+                    },
+                ]
+            ));
+        }
+
+        let source = r#"x: list[int] = []
+y: int
+class C:
+    def __init__(self, value: int, *args, flag: bool = False):
+        self.value: int = value
+    def get(self):
+        return self.value
+async def fetch(url: str) -> bytes:
+    pass"#;
+        assert_eq!(
+            unparse_with_options(
+                source,
+                UnparserOptions {
+                    downlevel: true,
+                    type_comments: TypeCommentConversion::ToComments,
+                    ..Default::default()
+                }
+            ),
+            r#"from typing import Any
+x = []  # type: list[int]
+y: int
+class C:
+    def __init__(self, value, *args, flag=False):  # type: (int, *Any, bool) -> None
+        self.value = value  # type: int
+    def get(self):
+        return self.value
+async def fetch(url):  # type: (str) -> bytes
+    pass"#
+        );
+        let options = UnparserOptions {
+            downlevel: true,
+            type_comments: TypeCommentConversion::ToComments,
+            ..Default::default()
+        };
+        assert_eq!(
+            unparse_with_options(
+                "from typing import Any as Anything\ndef f(x, y: int):\n    pass",
+                options.clone()
+            ),
+            "from typing import Any as Anything\ndef f(x, y):  # type: (Anything, int) -> Anything\n    pass"
+        );
+        assert_eq!(
+            unparse_with_options(
+                "Any = 1\ndef f(x, y: int):\n    pass",
+                options.clone()
+            ),
+            "from typing import Any as Any_2\nAny = 1\ndef f(x, y):  # type: (Any_2, int) -> Any_2\n    pass"
+        );
+
+        // functions without annotations keep their type comments
+        let mut stmts = Suite::parse("def f(a, b):\n    pass", "<test>").unwrap();
+        if let Stmt::FunctionDef(data) = &mut stmts[0] {
+            data.type_comment = Some("(int, int) -> None".to_string());
+        }
+        let mut unparser = Unparser::with_options(options);
+        unparser.unparse_suite(&stmts);
+        assert_eq!(
+            unparser.source,
+            "def f(a, b):  # type: (int, int) -> None\n    pass"
+        );
+    }
+
+    #[test]
+    fn test_stub() {
+        let source = r#""""A module."""
+import os
+from typing import TypeVar, TYPE_CHECKING
+if TYPE_CHECKING:
+    from collections.abc import Iterator
+_T = TypeVar("_T")
+type Pair[T] = tuple[T, T]
+VERSION: str = "1.0"
+_cache: dict = {}
+counter = 0
+def walk(path: str = ".", *, follow: bool = False) -> "Iterator[str]":
+    """Walk a tree."""
+    yield from os.walk(path, followlinks=follow)
+def _helper(x):
+    return x
+class Node:
+    """A node."""
+    kind: str = "node"
+    def __init__(self, value: int = 0) -> None:
+        self.value = value
+    @property
+    def double(self) -> int:
+        return self.value * 2
+    def _check(self):
+        pass
+class Empty:
+    pass
+print(walk())
+from enum import Enum
+from typing import Union
+class _Base:
+    def describe(self) -> str:
+        return ""
+_Alias = Union[int, "_Base"]
+_unused = Union[int, str]
+class Public(_Base):
+    LIMIT = -10
+    default = None
+    def get(self) -> _Alias:
+        return 1
+class Color(Enum):
+    RED = 1
+    GREEN = "green"
+try:
+    from ujson import loads
+except ImportError:
+    from json import loads
+    FAST = False"#;
+        let options = UnparserOptions {
+            stub: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            unparse_with_options(source, options.clone()),
+            r#"import os
+from typing import TypeVar, TYPE_CHECKING
+if TYPE_CHECKING:
+    from collections.abc import Iterator
+_T = TypeVar('_T')
+type Pair[T] = tuple[T, T]
+VERSION: str = ...
+counter: int
+def walk(path: str=..., *, follow: bool=...) -> 'Iterator[str]': ...
+class Node:
+    kind: str = ...
+    def __init__(self, value: int=...) -> None: ...
+    @property
+    def double(self) -> int: ...
+class Empty: ...
+from enum import Enum
+from typing import Union
+class _Base:
+    def describe(self) -> str: ...
+_Alias = Union[int, '_Base']
+class Public(_Base):
+    LIMIT: int
+    default = ...
+    def get(self) -> _Alias: ...
+class Color(Enum):
+    RED = 1
+    GREEN = 'green'
+try:
+    from ujson import loads
+except ImportError:
+    from json import loads
+    FAST: bool"#
+        );
+        assert!(unparse_with_options(
+            source,
+            UnparserOptions {
+                stub_keep_private: true,
+                ..options.clone()
+            }
+        )
+        .contains("_cache: dict = ...\ncounter: int\ndef walk"));
+
+        let source = r#"from .nodes import Node, Leaf
+import json
+__all__ = ["Node", "load", "_internal"]
+__all__ += ["json"]
+def load(path): ...
+def dump(path): ...
+def _internal(): ...
+class Tree:
+    def _walk(self): ..."#;
+        assert_eq!(
+            unparse_with_options(source, options),
+            r#"from .nodes import Node as Node, Leaf
+import json as json
+__all__ = ['Node', 'load', '_internal']
+__all__ += ['json']
+def load(path): ...
+def _internal(): ..."#
+        );
+    }
+
     #[test]
     #[ignore = "Fuzzy tests are unstable and should only be used to explore new test cases"]
     fn test_fuzzy_files() -> io::Result<()> {
@@ -116,7 +2019,7 @@ mod tests {
                 .arg("-m")
                 .arg("pysource_codegen")
                 .arg("--seed")
-                .arg(&seed.to_string())
+                .arg(seed.to_string())
                 .output()
                 .expect("failed to execute process");
             fs::write(&file_name, &file_content.stdout)?;
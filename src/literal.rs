@@ -0,0 +1,553 @@
+use rustpython_ast::bigint::BigInt;
+use rustpython_ast::Constant;
+use rustpython_literal::char::is_printable;
+
+use crate::options::{IntRadix, QuoteStyle, UnparserOptions};
+
+/// Returns the source of an `int` literal with the radix and digit grouping of `options`.
+pub(crate) fn int_literal(value: &BigInt, options: &UnparserOptions) -> String {
+    let (prefix, radix) = match options.int_radix {
+        IntRadix::Decimal => ("", 10),
+        IntRadix::Hexadecimal => ("0x", 16),
+        IntRadix::Octal => ("0o", 8),
+        IntRadix::Binary => ("0b", 2),
+    };
+    let digits = value.to_str_radix(radix);
+    let (sign, digits) = match digits.strip_prefix('-') {
+        Some(digits) => ("-", digits),
+        None => ("", digits.as_str()),
+    };
+    let digits = match options.int_digit_grouping {
+        Some(group_size) if group_size > 0 => group_digits(digits, group_size),
+        _ => digits.to_string(),
+    };
+    format!("{}{}{}", sign, prefix, digits)
+}
+
+/// Separates groups of `group_size` digits, counted from the right, with underscores.
+fn group_digits(digits: &str, group_size: usize) -> String {
+    let mut grouped = String::new();
+    for (index, ch) in digits.chars().enumerate() {
+        if index > 0 && (digits.len() - index).is_multiple_of(group_size) {
+            grouped.push('_');
+        }
+        grouped.push(ch);
+    }
+    grouped
+}
+
+/// Whether `text` is a numeric literal token that evaluates to `constant`.
+pub(crate) fn is_number_token_for(text: &str, constant: &Constant) -> bool {
+    if !text.starts_with(|ch: char| ch.is_ascii_digit() || ch == '.')
+        || !text
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || "._+-".contains(ch))
+    {
+        return false;
+    }
+    let text = text.replace('_', "").to_ascii_lowercase();
+    match constant {
+        Constant::Int(value) => {
+            let (digits, radix) = if let Some(digits) = text.strip_prefix("0x") {
+                (digits, 16)
+            } else if let Some(digits) = text.strip_prefix("0o") {
+                (digits, 8)
+            } else if let Some(digits) = text.strip_prefix("0b") {
+                (digits, 2)
+            } else {
+                (text.as_str(), 10)
+            };
+            BigInt::parse_bytes(digits.as_bytes(), radix).is_some_and(|parsed| parsed == *value)
+        }
+        Constant::Float(value) => !text.contains('x') && text.parse::<f64>() == Ok(*value),
+        Constant::Complex { real, imag } => {
+            *real == 0.0
+                && text
+                    .strip_suffix('j')
+                    .is_some_and(|imag_text| imag_text.parse::<f64>() == Ok(*imag))
+        }
+        _ => false,
+    }
+}
+
+/// Returns the source of a `str` literal with the given value.
+/// `prefix` is either empty or `u`, which rules out raw strings.
+pub(crate) fn str_literal(value: &str, prefix: &str, options: &UnparserOptions) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    let triple = options.triple_quote_multiline && chars.contains(&'\n');
+    string_literal(&chars, prefix, triple, options)
+}
+
+/// Returns the source of a docstring, which is always triple-quoted.
+pub(crate) fn docstring_literal(value: &str, prefix: &str, options: &UnparserOptions) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    string_literal(&chars, prefix, true, options)
+}
+
+/// Returns the source of a `bytes` literal with the given value.
+pub(crate) fn bytes_literal(value: &[u8], options: &UnparserOptions) -> String {
+    let chars: Vec<char> = value.iter().map(|byte| *byte as char).collect();
+    let triple = options.triple_quote_multiline && chars.contains(&'\n');
+    string_literal(&chars, "b", triple, options)
+}
+
+/// Trims a docstring like PEP 257 describes and indents it with `indentation`,
+/// putting the closing quotes of multi-line docstrings on their own line.
+pub(crate) fn normalize_docstring(value: &str, indentation: &str) -> String {
+    let lines: Vec<String> = value
+        .lines()
+        .map(|line| line.replace('\t', "        "))
+        .collect();
+    // tabs are expanded, so the indentation is the leading spaces, one byte each
+    let indent = |line: &str| line.len() - line.trim_start_matches(' ').len();
+    let common_indent = lines
+        .iter()
+        .skip(1)
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| indent(line))
+        .min()
+        .unwrap_or(0);
+    let mut trimmed: Vec<&str> = lines
+        .iter()
+        .enumerate()
+        .map(|(index, line)| {
+            if index == 0 {
+                line.trim()
+            } else {
+                line[common_indent.min(indent(line))..].trim_end()
+            }
+        })
+        .collect();
+    while trimmed.last().is_some_and(|line| line.is_empty()) {
+        trimmed.pop();
+    }
+    while trimmed.first().is_some_and(|line| line.is_empty()) {
+        trimmed.remove(0);
+    }
+
+    let mut normalized = String::new();
+    for (index, line) in trimmed.iter().enumerate() {
+        if index > 0 {
+            normalized.push('\n');
+            if !line.is_empty() {
+                normalized += indentation;
+            }
+        }
+        normalized += line;
+    }
+    if trimmed.len() > 1 {
+        normalized.push('\n');
+        normalized += indentation;
+    }
+    normalized
+}
+
+fn string_literal(chars: &[char], prefix: &str, triple: bool, options: &UnparserOptions) -> String {
+    let is_bytes = prefix == "b";
+    let quote = choose_quote(chars, options.quote_style, triple);
+    let raw = options.raw_strings
+        && prefix != "u"
+        && can_be_raw(chars, quote, triple, is_bytes || options.ascii_only);
+
+    let delimiter = if triple {
+        quote.to_string().repeat(3)
+    } else {
+        quote.to_string()
+    };
+    let mut literal = prefix.to_string();
+    if raw {
+        literal.push('r');
+    }
+    literal += &delimiter;
+    for (index, ch) in chars.iter().enumerate() {
+        if raw {
+            literal.push(*ch);
+        } else if *ch == quote {
+            if !triple || needs_triple_quote_escape(chars, index, quote) {
+                literal.push('\\');
+            }
+            literal.push(*ch);
+        } else if *ch == '\n' && triple {
+            literal.push('\n');
+        } else {
+            escape_char(*ch, is_bytes || options.ascii_only, &mut literal);
+        }
+    }
+    literal += &delimiter;
+    literal
+}
+
+/// How many brackets `code` leaves open, skipping string literals and comments.
+pub(crate) fn open_brackets(code: &str) -> usize {
+    let chars: Vec<char> = code.chars().collect();
+    let mut depth = 0usize;
+    let mut index = 0;
+    while index < chars.len() {
+        match chars[index] {
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth = depth.saturating_sub(1),
+            '#' => {
+                while index < chars.len() && chars[index] != '\n' {
+                    index += 1;
+                }
+            }
+            quote @ ('\'' | '"') => {
+                let delimiter = if chars[index..].starts_with(&[quote; 3]) {
+                    3
+                } else {
+                    1
+                };
+                let closing = vec![quote; delimiter];
+                index += delimiter;
+                while index < chars.len() && !chars[index..].starts_with(&closing) {
+                    // a backslash escapes the next character, even in raw strings
+                    index += if chars[index] == '\\' { 2 } else { 1 };
+                }
+                index += delimiter - 1;
+            }
+            _ => {}
+        }
+        index += 1;
+    }
+    depth
+}
+
+/// If `text` consists of string literal tokens that evaluate to `constant`, possibly implicitly
+/// concatenated and separated by comments, returns whether the tokens are separated by line breaks,
+/// in which case they have to be wrapped in parentheses.
+pub(crate) fn match_string_tokens(text: &str, constant: &Constant) -> Option<bool> {
+    let is_bytes = match constant {
+        Constant::Str(_) => false,
+        Constant::Bytes(_) => true,
+        _ => return None,
+    };
+    let chars: Vec<char> = text.chars().collect();
+    let mut value = Vec::new();
+    let mut multi_line = false;
+    let mut index = 0;
+    let mut has_tokens = false;
+    while index < chars.len() {
+        match chars[index] {
+            ' ' | '\t' | '\x0c' | '\r' => index += 1,
+            '\n' => {
+                multi_line = true;
+                index += 1;
+            }
+            '\\' if chars.get(index + 1) == Some(&'\n') => index += 2,
+            '\\' if chars.get(index + 1) == Some(&'\r') && chars.get(index + 2) == Some(&'\n') => {
+                index += 3
+            }
+            '#' => {
+                while index < chars.len() && chars[index] != '\n' {
+                    index += 1;
+                }
+            }
+            _ => {
+                index = read_string_token(&chars, index, is_bytes, &mut value)?;
+                has_tokens = true;
+            }
+        }
+    }
+    if !has_tokens {
+        return None;
+    }
+    let matches = match constant {
+        Constant::Str(expected) => expected.chars().eq(value.iter().copied()),
+        Constant::Bytes(expected) => expected.iter().map(|byte| *byte as char).eq(value),
+        _ => false,
+    };
+    matches.then_some(multi_line)
+}
+
+/// Reads the string literal token starting at `start` and appends its value to `value`,
+/// with bytes as the characters of the same code point.
+/// Returns the index after the token, or `None` if there is no matching token.
+fn read_string_token(
+    chars: &[char],
+    start: usize,
+    is_bytes: bool,
+    value: &mut Vec<char>,
+) -> Option<usize> {
+    let mut index = start;
+    let mut raw = false;
+    let mut bytes = false;
+    while let Some(ch) = chars.get(index).filter(|ch| ch.is_ascii_alphabetic()) {
+        match ch.to_ascii_lowercase() {
+            'r' => raw = true,
+            'b' => bytes = true,
+            'u' => {}
+            _ => return None,
+        }
+        index += 1;
+    }
+    if bytes != is_bytes || index - start > 2 {
+        return None;
+    }
+    let quote = *chars.get(index).filter(|ch| **ch == '\'' || **ch == '"')?;
+    let triple = chars.get(index + 1) == Some(&quote) && chars.get(index + 2) == Some(&quote);
+    let delimiter_len = if triple { 3 } else { 1 };
+    index += delimiter_len;
+
+    let body_start = index;
+    loop {
+        match chars.get(index)? {
+            '\\' => index += 2,
+            '\n' if !triple => return None,
+            ch if *ch == quote
+                && chars[index..]
+                    .iter()
+                    .take(delimiter_len)
+                    .filter(|ch| **ch == quote)
+                    .count()
+                    == delimiter_len =>
+            {
+                break
+            }
+            _ => index += 1,
+        }
+    }
+    // line breaks in the source are normalized to `\n`
+    let body: Vec<char> = chars[body_start..index]
+        .iter()
+        .collect::<String>()
+        .replace("\r\n", "\n")
+        .chars()
+        .collect();
+    if raw {
+        value.extend(body);
+    } else {
+        unescape(&body, bytes, value)?;
+    }
+    Some(index + delimiter_len)
+}
+
+/// Decodes the escape sequences of a non-raw literal body. Fails on `\N{...}`,
+/// which would need the Unicode name database.
+fn unescape(body: &[char], bytes: bool, value: &mut Vec<char>) -> Option<()> {
+    let mut index = 0;
+    while index < body.len() {
+        if body[index] != '\\' {
+            value.push(body[index]);
+            index += 1;
+            continue;
+        }
+        let escaped = *body.get(index + 1)?;
+        index += 2;
+        let hex_escape = |len: usize, index: &mut usize| {
+            let digits: String = body.get(*index..*index + len)?.iter().collect();
+            *index += len;
+            char::from_u32(u32::from_str_radix(&digits, 16).ok()?)
+        };
+        match escaped {
+            '\n' => {}
+            '\\' | '\'' | '"' => value.push(escaped),
+            'a' => value.push('\x07'),
+            'b' => value.push('\x08'),
+            'f' => value.push('\x0c'),
+            'n' => value.push('\n'),
+            'r' => value.push('\r'),
+            't' => value.push('\t'),
+            'v' => value.push('\x0b'),
+            '0'..='7' => {
+                let mut code = escaped.to_digit(8)?;
+                let mut digits = 1;
+                while digits < 3 {
+                    match body.get(index).and_then(|ch| ch.to_digit(8)) {
+                        Some(digit) => code = code * 8 + digit,
+                        None => break,
+                    }
+                    index += 1;
+                    digits += 1;
+                }
+                if bytes {
+                    code &= 0xff;
+                }
+                value.push(char::from_u32(code)?);
+            }
+            'x' => value.push(hex_escape(2, &mut index)?),
+            'u' if !bytes => value.push(hex_escape(4, &mut index)?),
+            'U' if !bytes => value.push(hex_escape(8, &mut index)?),
+            'N' if !bytes => return None,
+            _ => {
+                value.push('\\');
+                value.push(escaped);
+            }
+        }
+    }
+    Some(())
+}
+
+/// Splits `items` after every run of whitespace, so each word keeps the whitespace following it.
+pub(crate) fn split_words<T>(items: &[T], is_whitespace: impl Fn(&T) -> bool) -> Vec<&[T]> {
+    let mut words = Vec::new();
+    let mut start = 0;
+    for index in 1..items.len() {
+        if is_whitespace(&items[index - 1]) && !is_whitespace(&items[index]) {
+            words.push(&items[start..index]);
+            start = index;
+        }
+    }
+    if start < items.len() {
+        words.push(&items[start..]);
+    }
+    words
+}
+
+/// A part of an f-string body.
+#[derive(Clone)]
+pub(crate) enum FStringPart {
+    /// Literal text with its braces already doubled that still has to be escaped.
+    Literal(String),
+    Field(Vec<FieldPart>),
+}
+
+/// A piece of a replacement field.
+#[derive(Clone)]
+pub(crate) enum FieldPart {
+    /// Unparsed source like the expression or conversion, which is written as is.
+    Code(String),
+    /// Literal text of a format spec, which is escaped like other literal text.
+    Spec(String),
+}
+
+/// Returns the quote an f-string with the given body is preferably written with,
+/// only considering its literal text.
+pub(crate) fn fstring_quote(parts: &[FStringPart], quote_style: QuoteStyle) -> char {
+    let mut literal_chars = Vec::new();
+    let mut spec_chars = Vec::new();
+    for part in parts {
+        match part {
+            FStringPart::Literal(text) => literal_chars.extend(text.chars()),
+            FStringPart::Field(field_parts) => {
+                for field_part in field_parts {
+                    if let FieldPart::Spec(text) = field_part {
+                        spec_chars.extend(text.chars());
+                    }
+                }
+            }
+        }
+    }
+    let quote = choose_quote(
+        &[&literal_chars[..], &spec_chars[..]].concat(),
+        quote_style,
+        false,
+    );
+    // escapes in format specs are better avoided, not every parser handles them
+    if spec_chars.contains(&quote) && !spec_chars.contains(&other_quote(quote)) {
+        other_quote(quote)
+    } else {
+        quote
+    }
+}
+
+pub(crate) fn other_quote(quote: char) -> char {
+    if quote == '"' {
+        '\''
+    } else {
+        '"'
+    }
+}
+
+/// Returns the source of an f-string literal with the given body and quote.
+pub(crate) fn fstring_literal(
+    parts: &[FStringPart],
+    quote: char,
+    options: &UnparserOptions,
+) -> String {
+    let mut literal = format!("f{}", quote);
+    for part in parts {
+        match part {
+            FStringPart::Literal(text) => escape_fstring_text(text, quote, options, &mut literal),
+            FStringPart::Field(field_parts) => {
+                for field_part in field_parts {
+                    match field_part {
+                        FieldPart::Code(code) => literal += code,
+                        FieldPart::Spec(text) => {
+                            escape_fstring_text(text, quote, options, &mut literal)
+                        }
+                    }
+                }
+            }
+        }
+    }
+    literal.push(quote);
+    literal
+}
+
+fn escape_fstring_text(text: &str, quote: char, options: &UnparserOptions, literal: &mut String) {
+    for ch in text.chars() {
+        if ch == quote {
+            literal.push('\\');
+            literal.push(ch);
+        } else {
+            escape_char(ch, options.ascii_only, literal);
+        }
+    }
+}
+
+/// Inside of triple quotes a quote only has to be escaped when it is followed
+/// by another one or would merge with the closing delimiter.
+fn needs_triple_quote_escape(chars: &[char], index: usize, quote: char) -> bool {
+    chars
+        .get(index + 1)
+        .is_none_or(|next_char| *next_char == quote)
+}
+
+fn count_escaped_quotes(chars: &[char], quote: char, triple: bool) -> usize {
+    (0..chars.len())
+        .filter(|index| {
+            chars[*index] == quote && (!triple || needs_triple_quote_escape(chars, *index, quote))
+        })
+        .count()
+}
+
+fn choose_quote(chars: &[char], quote_style: QuoteStyle, triple: bool) -> char {
+    match quote_style {
+        QuoteStyle::Single => '\'',
+        QuoteStyle::Double => '"',
+        QuoteStyle::FewestEscapes => {
+            let single_escapes = count_escaped_quotes(chars, '\'', triple);
+            let double_escapes = count_escaped_quotes(chars, '"', triple);
+            // ties go to single quotes like `repr` does, except for
+            // triple-quoted strings which are conventionally written with `"""`
+            if double_escapes < single_escapes || (triple && double_escapes == single_escapes) {
+                '"'
+            } else {
+                '\''
+            }
+        }
+    }
+}
+
+/// A raw literal is only used when it is needed to avoid escaping backslashes
+/// and every other character can be written as is.
+fn can_be_raw(chars: &[char], quote: char, triple: bool, ascii_only: bool) -> bool {
+    let trailing_backslashes = chars.iter().rev().take_while(|ch| **ch == '\\').count();
+    chars.contains(&'\\')
+        && trailing_backslashes % 2 == 0
+        && chars.iter().all(|ch| match ch {
+            '\\' => true,
+            '\n' => triple,
+            ch if *ch == quote => false,
+            ' '..='~' => true,
+            ch if ascii_only || ch.is_ascii() => false,
+            ch => is_printable(*ch),
+        })
+}
+
+/// Escapes `ch` the way `repr` does. With `ascii_only` every non-ASCII character is escaped,
+/// which is also how bytes are written.
+fn escape_char(ch: char, ascii_only: bool, literal: &mut String) {
+    match ch {
+        '\\' => literal.push_str("\\\\"),
+        '\n' => literal.push_str("\\n"),
+        '\t' => literal.push_str("\\t"),
+        '\r' => literal.push_str("\\r"),
+        ' '..='~' => literal.push(ch),
+        ch if ch.is_ascii() => literal.push_str(&format!("\\x{:02x}", ch as u32)),
+        ch if !ascii_only && is_printable(ch) => literal.push(ch),
+        '\0'..='\u{ff}' => literal.push_str(&format!("\\x{:02x}", ch as u32)),
+        '\0'..='\u{ffff}' => literal.push_str(&format!("\\u{:04x}", ch as u32)),
+        _ => literal.push_str(&format!("\\U{:08x}", ch as u32)),
+    }
+}
@@ -0,0 +1,598 @@
+//! Rewrites syntax that the target version can't parse into equivalent code for it.
+
+mod fstring;
+mod match_stmt;
+mod named_expr;
+mod positional_only;
+mod try_star;
+mod type_comments;
+mod type_params;
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::convert::Infallible;
+
+use rustpython_ast::{
+    bigint::BigInt, fold, text_size::TextRange, Alias, Arg, BoolOp, CmpOp, Constant, Expr,
+    ExprAttribute, ExprBoolOp, ExprCall, ExprCompare, ExprConstant, ExprContext, ExprName,
+    ExprSubscript, ExprTuple, ExprUnaryOp, Fold, Identifier, Int, Keyword, Pattern, PatternMatchAs,
+    PatternMatchMapping, PatternMatchStar, Stmt, StmtAssign, StmtIf, StmtImport, StmtImportFrom,
+    UnaryOp,
+};
+
+use crate::error::{UnparseError, UnparseErrorKind};
+use crate::options::{FStringLowering, PythonVersion, TypeCommentConversion, UnparserOptions};
+use crate::unparser::Unparser;
+use crate::version::Feature;
+
+/// Rewrites the statements of a module that use syntax `options.target_version` can't parse
+/// into equivalent code for it, inserting the imports the rewritten code needs.
+/// Without a target version, only f-strings and type comments are rewritten
+/// as `options.fstring_lowering` and `options.type_comments` say.
+///
+/// Returns errors for syntax that can't be rewritten, which is left unchanged.
+pub fn lower(body: &mut Vec<Stmt<TextRange>>, options: &UnparserOptions) -> Vec<UnparseError> {
+    if options.fstring_lowering != FStringLowering::Keep {
+        fstring::lower_fstrings(body, options.fstring_lowering);
+    }
+    let mut lowering = Lowering {
+        options,
+        target: options.target_version,
+        used_names: names(body),
+        module_imports: BTreeSet::new(),
+        imports: BTreeMap::new(),
+        match_missing: None,
+        typing_any: None,
+        errors: Vec::new(),
+    };
+    match options.type_comments {
+        TypeCommentConversion::Keep => {}
+        #[cfg(feature = "type-comments")]
+        TypeCommentConversion::ToAnnotations => lowering.type_comments_to_annotations(body),
+        TypeCommentConversion::ToComments => lowering.annotations_to_type_comments(body),
+    }
+    if lowering.targets_before(PythonVersion::PY38) {
+        lowering.lower_positional_only(body);
+    }
+    if lowering.targets_before(PythonVersion::PY312) {
+        lowering.rename_type_params(body);
+    }
+    lowering.lower_body(body);
+    lowering.insert_imports(body);
+    lowering.errors
+}
+
+pub(crate) struct Lowering<'a> {
+    pub(crate) options: &'a UnparserOptions,
+    /// The version to rewrite syntax for, `None` if only the version-independent
+    /// rewrites are applied.
+    pub(crate) target: Option<PythonVersion>,
+    /// Every name the module uses, including the ones the lowering introduced.
+    used_names: HashSet<String>,
+    /// Modules to import at the top of the module.
+    module_imports: BTreeSet<&'static str>,
+    /// Names to import from each module at the top of the module,
+    /// with the name each is imported as.
+    imports: BTreeMap<&'static str, BTreeMap<&'static str, String>>,
+    /// The name of the object that stands for a missing attribute or mapping key
+    /// in the `match` statement being lowered, once it's needed.
+    match_missing: Option<String>,
+    /// The name the module already imported `typing.Any` as, if it did.
+    typing_any: Option<String>,
+    pub(crate) errors: Vec<UnparseError>,
+}
+
+impl Lowering<'_> {
+    /// Imports `name` from `typing` if it's there since `version` or the target is newer,
+    /// otherwise from the `typing_extensions` backport.
+    pub(crate) fn typing(&mut self, name: &'static str, version: PythonVersion) -> Expr<TextRange> {
+        let module = if self.targets_before(version) {
+            "typing_extensions"
+        } else {
+            "typing"
+        };
+        self.import(module, name)
+    }
+
+    /// Imports `name` from `module` and returns an expression that loads it.
+    pub(crate) fn import(&mut self, module: &'static str, name: &'static str) -> Expr<TextRange> {
+        let imported = self.imports.entry(module).or_default();
+        load(imported.entry(name).or_insert_with(|| name.to_string()))
+    }
+
+    /// Like [`Lowering::import`], but imports `name` under a fresh name
+    /// if the module already binds or loads it.
+    pub(crate) fn import_unshadowed(
+        &mut self,
+        module: &'static str,
+        name: &'static str,
+    ) -> Expr<TextRange> {
+        if let Some(imported) = self.imports.get(module).and_then(|names| names.get(name)) {
+            return load(imported);
+        }
+        let imported = self.fresh_name(name);
+        self.imports
+            .entry(module)
+            .or_default()
+            .insert(name, imported.clone());
+        load(&imported)
+    }
+
+    /// Imports `module` and returns an expression that loads it, e.g. `collections.abc`.
+    pub(crate) fn import_module(&mut self, module: &'static str) -> Expr<TextRange> {
+        self.module_imports.insert(module);
+        let mut parts = module.split('.');
+        let first = load(parts.next().unwrap_or(module));
+        parts.fold(first, attribute)
+    }
+
+    /// Whether the target version is older than `version`.
+    pub(crate) fn targets_before(&self, version: PythonVersion) -> bool {
+        self.target.is_some_and(|target| target < version)
+    }
+
+    /// Records that a use of `feature` at `range` can't be rewritten for the target version.
+    pub(crate) fn cannot_downlevel(
+        &mut self,
+        feature: Feature,
+        reason: &'static str,
+        range: TextRange,
+    ) {
+        let Some(target) = self.target else {
+            return;
+        };
+        self.errors.push(UnparseError {
+            kind: UnparseErrorKind::CannotDownlevel {
+                feature,
+                target,
+                reason,
+            },
+            range,
+            location: None,
+        });
+    }
+
+    /// The source of a type expression, e.g. for a type comment or a string annotation.
+    pub(crate) fn unparse_type(&self, annotation: &Expr<TextRange>) -> String {
+        let mut unparser = Unparser::with_options(self.options.clone());
+        unparser.unparse_expr(annotation);
+        unparser.source
+    }
+
+    /// A name based on `base` that the module doesn't use yet.
+    pub(crate) fn fresh_name(&mut self, base: &str) -> String {
+        unused_name(base, &mut self.used_names)
+    }
+
+    fn lower_body(&mut self, body: &mut Vec<Stmt<TextRange>>) {
+        let stmts = std::mem::take(body);
+        for mut stmt in stmts {
+            for_each_body(&mut stmt, |nested_body| self.lower_body(nested_body));
+            body.extend(self.lower_stmt(stmt));
+        }
+    }
+
+    /// Rewrites `node`, whose nested statements are already rewritten.
+    fn lower_stmt(&mut self, node: Stmt<TextRange>) -> Vec<Stmt<TextRange>> {
+        let mut stmts = vec![node];
+        if self.targets_before(PythonVersion::PY310) {
+            stmts = stmts
+                .into_iter()
+                .flat_map(|stmt| self.lower_match(stmt))
+                .collect();
+        }
+        if self.targets_before(PythonVersion::PY311) {
+            stmts = stmts
+                .into_iter()
+                .flat_map(|stmt| self.lower_try_star(stmt))
+                .collect();
+        }
+        if self.targets_before(PythonVersion::PY38) {
+            stmts = stmts
+                .into_iter()
+                .flat_map(|stmt| self.lower_named_exprs(stmt))
+                .collect();
+        }
+        if self.targets_before(PythonVersion::PY311) {
+            stmts = stmts
+                .into_iter()
+                .map(|stmt| self.lower_unpacks(stmt))
+                .collect();
+        }
+        if self.targets_before(PythonVersion::PY312) {
+            stmts = stmts
+                .into_iter()
+                .flat_map(|stmt| self.lower_type_params(stmt))
+                .collect();
+        }
+        stmts
+    }
+
+    /// Inserts the collected imports after the docstring and `__future__` imports of `body`.
+    fn insert_imports(&mut self, body: &mut Vec<Stmt<TextRange>>) {
+        let mut position = usize::from(body.first().is_some_and(is_docstring));
+        while body.get(position).is_some_and(is_future_import) {
+            position += 1;
+        }
+        let module_imports = std::mem::take(&mut self.module_imports)
+            .into_iter()
+            .map(|module| {
+                Stmt::Import(StmtImport {
+                    range: TextRange::default(),
+                    names: vec![Alias {
+                        range: TextRange::default(),
+                        name: Identifier::new(module),
+                        asname: None,
+                    }],
+                })
+            });
+        let imports = std::mem::take(&mut self.imports)
+            .into_iter()
+            .map(|(module, names)| {
+                Stmt::ImportFrom(StmtImportFrom {
+                    range: TextRange::default(),
+                    module: Some(Identifier::new(module)),
+                    names: names
+                        .into_iter()
+                        .map(|(name, imported)| Alias {
+                            range: TextRange::default(),
+                            name: Identifier::new(name),
+                            asname: (imported != name).then(|| Identifier::new(imported)),
+                        })
+                        .collect(),
+                    level: Some(Int::new(0)),
+                })
+            });
+        body.splice(position..position, module_imports.chain(imports));
+    }
+}
+
+/// A name based on `base` that isn't in `used_names`, which it's added to.
+pub(crate) fn unused_name(base: &str, used_names: &mut HashSet<String>) -> String {
+    let mut name = base.to_string();
+    let mut counter = 1;
+    while used_names.contains(&name) {
+        counter += 1;
+        name = format!("{}_{}", base, counter);
+    }
+    used_names.insert(name.clone());
+    name
+}
+
+pub(crate) fn is_docstring(node: &Stmt<TextRange>) -> bool {
+    node.as_expr_stmt().is_some_and(|expr| {
+        expr.value
+            .as_constant_expr()
+            .is_some_and(|constant| constant.value.is_str())
+    })
+}
+
+fn is_future_import(node: &Stmt<TextRange>) -> bool {
+    node.as_import_from_stmt().is_some_and(|import| {
+        import
+            .module
+            .as_ref()
+            .is_some_and(|module| module.as_str() == "__future__")
+    })
+}
+
+/// Collects the names an AST binds or loads.
+#[derive(Default)]
+struct NameCollector {
+    names: HashSet<String>,
+    /// Whether only the names the AST binds are collected.
+    bindings_only: bool,
+}
+
+impl NameCollector {
+    fn add(&mut self, name: &Identifier) {
+        self.names.insert(name.to_string());
+    }
+}
+
+impl Fold<TextRange> for NameCollector {
+    type TargetU = TextRange;
+    type Error = Infallible;
+    type UserContext = ();
+
+    fn will_map_user(&mut self, _user: &TextRange) -> Self::UserContext {}
+
+    fn map_user(
+        &mut self,
+        user: TextRange,
+        _context: Self::UserContext,
+    ) -> Result<Self::TargetU, Self::Error> {
+        Ok(user)
+    }
+
+    fn fold_stmt(&mut self, node: Stmt<TextRange>) -> Result<Stmt<TextRange>, Infallible> {
+        match &node {
+            Stmt::FunctionDef(data) => self.add(&data.name),
+            Stmt::AsyncFunctionDef(data) => self.add(&data.name),
+            Stmt::ClassDef(data) => self.add(&data.name),
+            Stmt::Global(data) => data.names.iter().for_each(|name| self.add(name)),
+            Stmt::Nonlocal(data) => data.names.iter().for_each(|name| self.add(name)),
+            _ => {}
+        }
+        fold::fold_stmt(self, node)
+    }
+
+    fn fold_expr_name(
+        &mut self,
+        node: ExprName<TextRange>,
+    ) -> Result<ExprName<TextRange>, Infallible> {
+        if !self.bindings_only || node.ctx != ExprContext::Load {
+            self.add(&node.id);
+        }
+        Ok(node)
+    }
+
+    fn fold_arg(&mut self, node: Arg<TextRange>) -> Result<Arg<TextRange>, Infallible> {
+        self.add(&node.arg);
+        fold::fold_arg(self, node)
+    }
+
+    fn fold_alias(&mut self, node: Alias<TextRange>) -> Result<Alias<TextRange>, Infallible> {
+        let bound_name = node.asname.as_ref().unwrap_or(&node.name);
+        let bound_name = bound_name.split('.').next().unwrap_or_default().to_string();
+        self.names.insert(bound_name);
+        Ok(node)
+    }
+
+    fn fold_excepthandler_except_handler(
+        &mut self,
+        node: rustpython_ast::ExceptHandlerExceptHandler<TextRange>,
+    ) -> Result<rustpython_ast::ExceptHandlerExceptHandler<TextRange>, Infallible> {
+        if let Some(name) = &node.name {
+            self.add(name);
+        }
+        fold::fold_excepthandler_except_handler(self, node)
+    }
+
+    fn fold_pattern(&mut self, node: Pattern<TextRange>) -> Result<Pattern<TextRange>, Infallible> {
+        match &node {
+            Pattern::MatchAs(PatternMatchAs {
+                name: Some(name), ..
+            })
+            | Pattern::MatchStar(PatternMatchStar {
+                name: Some(name), ..
+            })
+            | Pattern::MatchMapping(PatternMatchMapping {
+                rest: Some(name), ..
+            }) => self.add(name),
+            _ => {}
+        }
+        fold::fold_pattern(self, node)
+    }
+}
+
+/// The names `body` binds or loads.
+pub(crate) fn names(body: &[Stmt<TextRange>]) -> HashSet<String> {
+    let mut collector = NameCollector::default();
+    for stmt in body {
+        let Ok(_) = collector.fold_stmt(stmt.clone());
+    }
+    collector.names
+}
+
+/// The names `body` binds, in any of its scopes.
+pub(crate) fn bound_names(body: &[Stmt<TextRange>]) -> HashSet<String> {
+    let mut collector = NameCollector {
+        bindings_only: true,
+        ..Default::default()
+    };
+    for stmt in body {
+        let Ok(_) = collector.fold_stmt(stmt.clone());
+    }
+    collector.names
+}
+
+/// The names `node` binds or loads.
+pub(crate) fn expr_names(node: &Expr<TextRange>) -> HashSet<String> {
+    let mut collector = NameCollector::default();
+    let Ok(_) = collector.fold_expr(node.clone());
+    collector.names
+}
+
+/// Calls `f` with every block of statements directly nested in `node`.
+fn for_each_body<F>(node: &mut Stmt<TextRange>, mut f: F)
+where
+    F: FnMut(&mut Vec<Stmt<TextRange>>),
+{
+    match node {
+        Stmt::FunctionDef(data) => f(&mut data.body),
+        Stmt::AsyncFunctionDef(data) => f(&mut data.body),
+        Stmt::ClassDef(data) => f(&mut data.body),
+        Stmt::For(data) => {
+            f(&mut data.body);
+            f(&mut data.orelse);
+        }
+        Stmt::AsyncFor(data) => {
+            f(&mut data.body);
+            f(&mut data.orelse);
+        }
+        Stmt::While(data) => {
+            f(&mut data.body);
+            f(&mut data.orelse);
+        }
+        Stmt::If(data) => {
+            f(&mut data.body);
+            f(&mut data.orelse);
+        }
+        Stmt::With(data) => f(&mut data.body),
+        Stmt::AsyncWith(data) => f(&mut data.body),
+        Stmt::Match(data) => {
+            for case in &mut data.cases {
+                f(&mut case.body);
+            }
+        }
+        Stmt::Try(data) => {
+            f(&mut data.body);
+            for handler in &mut data.handlers {
+                let rustpython_ast::ExceptHandler::ExceptHandler(handler) = handler;
+                f(&mut handler.body);
+            }
+            f(&mut data.orelse);
+            f(&mut data.finalbody);
+        }
+        Stmt::TryStar(data) => {
+            f(&mut data.body);
+            for handler in &mut data.handlers {
+                let rustpython_ast::ExceptHandler::ExceptHandler(handler) = handler;
+                f(&mut handler.body);
+            }
+            f(&mut data.orelse);
+            f(&mut data.finalbody);
+        }
+        _ => {}
+    }
+}
+
+pub(crate) fn name(id: &str, ctx: ExprContext) -> Expr<TextRange> {
+    Expr::Name(ExprName {
+        range: TextRange::default(),
+        id: Identifier::new(id),
+        ctx,
+    })
+}
+
+pub(crate) fn load(id: &str) -> Expr<TextRange> {
+    name(id, ExprContext::Load)
+}
+
+pub(crate) fn int_constant(value: usize) -> Expr<TextRange> {
+    Expr::Constant(ExprConstant {
+        range: TextRange::default(),
+        value: Constant::Int(BigInt::from(value)),
+        kind: None,
+    })
+}
+
+/// `-value`
+pub(crate) fn negative_int_constant(value: usize) -> Expr<TextRange> {
+    Expr::UnaryOp(ExprUnaryOp {
+        range: TextRange::default(),
+        op: UnaryOp::USub,
+        operand: Box::new(int_constant(value)),
+    })
+}
+
+pub(crate) fn constant(value: Constant) -> Expr<TextRange> {
+    Expr::Constant(ExprConstant {
+        range: TextRange::default(),
+        value,
+        kind: None,
+    })
+}
+
+pub(crate) fn attribute(value: Expr<TextRange>, attr: &str) -> Expr<TextRange> {
+    Expr::Attribute(ExprAttribute {
+        range: TextRange::default(),
+        value: Box::new(value),
+        attr: Identifier::new(attr),
+        ctx: ExprContext::Load,
+    })
+}
+
+pub(crate) fn compare(left: Expr<TextRange>, op: CmpOp, right: Expr<TextRange>) -> Expr<TextRange> {
+    Expr::Compare(ExprCompare {
+        range: TextRange::default(),
+        left: Box::new(left),
+        ops: vec![op],
+        comparators: vec![right],
+    })
+}
+
+pub(crate) fn not(operand: Expr<TextRange>) -> Expr<TextRange> {
+    Expr::UnaryOp(ExprUnaryOp {
+        range: TextRange::default(),
+        op: UnaryOp::Not,
+        operand: Box::new(operand),
+    })
+}
+
+/// `values` combined with `op`, `None` if there are none.
+pub(crate) fn bool_op(op: BoolOp, mut values: Vec<Expr<TextRange>>) -> Option<Expr<TextRange>> {
+    match values.len() {
+        0 => None,
+        1 => values.pop(),
+        _ => Some(Expr::BoolOp(ExprBoolOp {
+            range: TextRange::default(),
+            op,
+            values,
+        })),
+    }
+}
+
+pub(crate) fn if_stmt(
+    test: Expr<TextRange>,
+    body: Vec<Stmt<TextRange>>,
+    orelse: Vec<Stmt<TextRange>>,
+) -> Stmt<TextRange> {
+    Stmt::If(StmtIf {
+        range: TextRange::default(),
+        test: Box::new(test),
+        body,
+        orelse,
+    })
+}
+
+pub(crate) fn str_constant(value: &str) -> Expr<TextRange> {
+    Expr::Constant(ExprConstant {
+        range: TextRange::default(),
+        value: Constant::Str(value.to_string()),
+        kind: None,
+    })
+}
+
+pub(crate) fn call(
+    func: Expr<TextRange>,
+    args: Vec<Expr<TextRange>>,
+    keywords: Vec<(&str, Expr<TextRange>)>,
+) -> Expr<TextRange> {
+    Expr::Call(ExprCall {
+        range: TextRange::default(),
+        func: Box::new(func),
+        args,
+        keywords: keywords
+            .into_iter()
+            .map(|(arg, value)| Keyword {
+                range: TextRange::default(),
+                arg: Some(Identifier::new(arg)),
+                value,
+            })
+            .collect(),
+    })
+}
+
+pub(crate) fn tuple(elts: Vec<Expr<TextRange>>) -> Expr<TextRange> {
+    Expr::Tuple(ExprTuple {
+        range: TextRange::default(),
+        elts,
+        ctx: ExprContext::Load,
+    })
+}
+
+/// `value[elts]`, with a tuple slice if there's more than one element.
+pub(crate) fn subscript(value: Expr<TextRange>, mut elts: Vec<Expr<TextRange>>) -> Expr<TextRange> {
+    let slice = if elts.len() == 1 {
+        elts.remove(0)
+    } else {
+        tuple(elts)
+    };
+    Expr::Subscript(ExprSubscript {
+        range: TextRange::default(),
+        value: Box::new(value),
+        slice: Box::new(slice),
+        ctx: ExprContext::Load,
+    })
+}
+
+/// `target = value`
+pub(crate) fn assign(target: &str, value: Expr<TextRange>, range: TextRange) -> Stmt<TextRange> {
+    Stmt::Assign(StmtAssign {
+        range,
+        targets: vec![name(target, ExprContext::Store)],
+        value: Box::new(value),
+        type_comment: None,
+    })
+}
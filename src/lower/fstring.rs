@@ -0,0 +1,179 @@
+//! f-strings, lowered to `str.format` calls or `%` formatting for interpreters that predate them.
+
+use std::convert::Infallible;
+
+use rustpython_ast::{
+    fold, text_size::TextRange, Constant, ConversionFlag, Expr, ExprBinOp, ExprCall, ExprConstant,
+    ExprJoinedStr, Fold, Operator, Stmt,
+};
+
+use super::{attribute, constant, tuple};
+use crate::options::FStringLowering;
+
+/// Rewrites every f-string in `body` as `lowering` describes.
+pub(crate) fn lower_fstrings(body: &mut [Stmt<TextRange>], lowering: FStringLowering) {
+    let mut folder = FStringFolder { lowering };
+    for stmt in body {
+        let Ok(lowered) = folder.fold_stmt(stmt.clone());
+        *stmt = lowered;
+    }
+}
+
+struct FStringFolder {
+    lowering: FStringLowering,
+}
+
+/// The template and arguments of a formatting expression, e.g. `'{!r:>{}}'` and `(x, width)`.
+#[derive(Default)]
+struct Template {
+    text: String,
+    args: Vec<Expr<TextRange>>,
+}
+
+impl FStringFolder {
+    /// Whether `values` can be written with `%` formatting without changing the result,
+    /// which is only the case if every replacement field converts its value to a string
+    /// and has no format spec.
+    fn can_use_percent(&self, values: &[Expr<TextRange>]) -> bool {
+        self.lowering == FStringLowering::PercentFormat
+            && values.iter().all(|value| match value {
+                Expr::FormattedValue(data) => {
+                    data.conversion != ConversionFlag::None && data.format_spec.is_none()
+                }
+                _ => true,
+            })
+    }
+
+    /// Appends `values`, the parts of an f-string or of a format spec, to a `str.format` template.
+    /// Replacement fields are numbered automatically, so the arguments are evaluated
+    /// in the order the f-string evaluates them, with the fields of a format spec
+    /// right after the value they format.
+    fn format_template(&mut self, values: Vec<Expr<TextRange>>, template: &mut Template) {
+        for value in values {
+            match value {
+                Expr::Constant(data) => {
+                    if let Constant::Str(text) = data.value {
+                        template.text += &text.replace('{', "{{").replace('}', "}}");
+                    }
+                }
+                Expr::FormattedValue(data) => {
+                    template.text.push('{');
+                    if let Some(conversion) = data.conversion.to_char() {
+                        template.text.push('!');
+                        template.text.push(conversion);
+                    }
+                    let Ok(value) = self.fold_expr(*data.value);
+                    template.args.push(value);
+                    if let Some(format_spec) = data.format_spec {
+                        template.text.push(':');
+                        self.format_template(joined_values(*format_spec), template);
+                    }
+                    template.text.push('}');
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Appends `values`, the parts of an f-string, to a `%` formatting template.
+    fn percent_template(&mut self, values: Vec<Expr<TextRange>>, template: &mut Template) {
+        for value in values {
+            match value {
+                Expr::Constant(data) => {
+                    if let Constant::Str(text) = data.value {
+                        template.text += &text.replace('%', "%%");
+                    }
+                }
+                Expr::FormattedValue(data) => {
+                    template.text.push('%');
+                    template.text.extend(data.conversion.to_char());
+                    let Ok(value) = self.fold_expr(*data.value);
+                    template.args.push(value);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Rewrites the f-string made of `values`, folding the expressions in its replacement fields.
+    fn lower_joined_str(
+        &mut self,
+        range: TextRange,
+        values: Vec<Expr<TextRange>>,
+    ) -> Expr<TextRange> {
+        if !values.iter().any(Expr::is_formatted_value_expr) {
+            // An f-string without replacement fields is just a string.
+            let text = values
+                .into_iter()
+                .filter_map(|value| match value {
+                    Expr::Constant(ExprConstant {
+                        value: Constant::Str(text),
+                        ..
+                    }) => Some(text),
+                    _ => None,
+                })
+                .collect();
+            return Expr::Constant(ExprConstant {
+                range,
+                value: Constant::Str(text),
+                kind: None,
+            });
+        }
+        let mut template = Template::default();
+        if self.can_use_percent(&values) {
+            self.percent_template(values, &mut template);
+            // `'%s' % (x,)`, since a tuple `x` would be taken as the arguments.
+            Expr::BinOp(ExprBinOp {
+                range,
+                left: Box::new(constant(Constant::Str(template.text))),
+                op: Operator::Mod,
+                right: Box::new(tuple(template.args)),
+            })
+        } else {
+            self.format_template(values, &mut template);
+            Expr::Call(ExprCall {
+                range,
+                func: Box::new(attribute(constant(Constant::Str(template.text)), "format")),
+                args: template.args,
+                keywords: Vec::new(),
+            })
+        }
+    }
+}
+
+impl Fold<TextRange> for FStringFolder {
+    type TargetU = TextRange;
+    type Error = Infallible;
+    type UserContext = ();
+
+    fn will_map_user(&mut self, _user: &TextRange) -> Self::UserContext {}
+
+    fn map_user(
+        &mut self,
+        user: TextRange,
+        _context: Self::UserContext,
+    ) -> Result<Self::TargetU, Self::Error> {
+        Ok(user)
+    }
+
+    fn fold_expr(&mut self, node: Expr<TextRange>) -> Result<Expr<TextRange>, Infallible> {
+        match node {
+            Expr::JoinedStr(ExprJoinedStr { range, values }) => {
+                Ok(self.lower_joined_str(range, values))
+            }
+            Expr::FormattedValue(data) => {
+                let range = data.range;
+                Ok(self.lower_joined_str(range, vec![Expr::FormattedValue(data)]))
+            }
+            node => fold::fold_expr(self, node),
+        }
+    }
+}
+
+/// The parts of a format spec, which the parser represents as an f-string.
+fn joined_values(format_spec: Expr<TextRange>) -> Vec<Expr<TextRange>> {
+    match format_spec {
+        Expr::JoinedStr(data) => data.values,
+        format_spec => vec![format_spec],
+    }
+}
@@ -0,0 +1,661 @@
+//! `match` statements, lowered to `if`/`elif` chains for Python 3.9 and older.
+
+use std::collections::HashSet;
+
+use rustpython_ast::{
+    text_size::TextRange, BoolOp, CmpOp, Constant, Expr, ExprBinOp, ExprContext, ExprSlice,
+    ExprSubscript, MatchCase, Operator, Pattern, Stmt, StmtDelete, StmtMatch, StmtRaise,
+};
+
+use super::{
+    assign, attribute, bool_op, call, compare, constant, expr_names, if_stmt, int_constant, load,
+    negative_int_constant, not, str_constant, tuple, Lowering,
+};
+
+/// Classes whose instances a single positional class pattern matches as a whole,
+/// e.g. `case int(x)`.
+const SELF_MATCHING_CLASSES: &[&str] = &[
+    "bool",
+    "bytearray",
+    "bytes",
+    "dict",
+    "float",
+    "frozenset",
+    "int",
+    "list",
+    "set",
+    "str",
+    "tuple",
+];
+
+/// A step of matching a pattern.
+#[derive(Clone)]
+enum Step {
+    /// A condition that has to be true.
+    Check(Expr<TextRange>),
+    /// Statements that run before the following checks,
+    /// e.g. to bind a part of the subject to a temporary name.
+    Run(Vec<Stmt<TextRange>>),
+}
+
+/// What a pattern needs to match a subject.
+#[derive(Default)]
+struct Match {
+    /// The steps that all have to succeed, in order.
+    steps: Vec<Step>,
+    /// Statements that bind the names the pattern captures once it matched.
+    bindings: Vec<Stmt<TextRange>>,
+    /// The names the pattern captures.
+    captures: HashSet<String>,
+}
+
+impl Match {
+    fn check(&mut self, check: Expr<TextRange>) {
+        self.steps.push(Step::Check(check));
+    }
+
+    /// Whether matching the pattern runs statements, so it can't be a single condition.
+    fn runs_statements(&self) -> bool {
+        self.steps.iter().any(|step| matches!(step, Step::Run(_)))
+    }
+
+    /// The condition of a pattern that doesn't run statements, `None` if it's irrefutable.
+    fn test(&self) -> Option<Expr<TextRange>> {
+        let checks = self
+            .steps
+            .iter()
+            .filter_map(|step| match step {
+                Step::Check(check) => Some(check.clone()),
+                Step::Run(_) => None,
+            })
+            .collect();
+        bool_op(BoolOp::And, checks)
+    }
+}
+
+/// A `case` with its pattern lowered.
+struct Case {
+    pattern: Match,
+    guard: Option<Expr<TextRange>>,
+    body: Vec<Stmt<TextRange>>,
+}
+
+impl Case {
+    /// Whether the guard uses names the pattern captures,
+    /// so it can only be evaluated after binding them.
+    fn guard_needs_bindings(&self) -> bool {
+        self.guard
+            .as_ref()
+            .is_some_and(|guard| !expr_names(guard).is_disjoint(&self.pattern.captures))
+    }
+
+    /// Whether the case can only be the last branch of an `if`/`elif` chain.
+    fn ends_chain(&self) -> bool {
+        self.guard_needs_bindings() || self.pattern.runs_statements()
+    }
+
+    /// The steps of the pattern, with the guard if it doesn't need the bindings.
+    fn steps(&self) -> Vec<Step> {
+        let mut steps = self.pattern.steps.clone();
+        if !self.guard_needs_bindings() {
+            steps.extend(self.guard.clone().map(Step::Check));
+        }
+        steps
+    }
+
+    /// The bindings followed by the body, which is guarded if the guard needs the bindings.
+    fn branch_body(self, before_body: Vec<Stmt<TextRange>>) -> Vec<Stmt<TextRange>> {
+        let needs_bindings = self.guard_needs_bindings();
+        let mut stmts = self.pattern.bindings;
+        let body = before_body.into_iter().chain(self.body).collect();
+        match self.guard {
+            Some(guard) if needs_bindings => stmts.push(if_stmt(guard, body, Vec::new())),
+            _ => stmts.extend(body),
+        }
+        stmts
+    }
+}
+
+impl Lowering<'_> {
+    /// Rewrites a `match` statement into an `if`/`elif` chain that checks the patterns
+    /// with `isinstance`, `len` and `in` and binds their captures at the start of the branch.
+    ///
+    /// Cases whose guard uses the captures of their pattern or whose pattern binds parts
+    /// of the subject to temporary names can't be part of the chain unless they're
+    /// the last one, so a flag tracks whether a case matched instead.
+    pub(crate) fn lower_match(&mut self, node: Stmt<TextRange>) -> Vec<Stmt<TextRange>> {
+        let Stmt::Match(StmtMatch {
+            range,
+            subject,
+            cases,
+        }) = node
+        else {
+            return vec![node];
+        };
+        let mut stmts = Vec::new();
+        let captures_subject = match subject.as_ref() {
+            Expr::Name(name) => cases
+                .iter()
+                .any(|case| pattern_captures(&case.pattern).contains(name.id.as_str())),
+            _ => true,
+        };
+        let subject = if captures_subject {
+            let subject_name = self.fresh_name("_match_subject");
+            stmts.push(assign(&subject_name, *subject, range));
+            load(&subject_name)
+        } else {
+            *subject
+        };
+        let cases: Vec<_> = cases
+            .into_iter()
+            .map(
+                |MatchCase {
+                     pattern,
+                     guard,
+                     body,
+                     ..
+                 }| Case {
+                    pattern: self.lower_pattern(pattern, &subject),
+                    guard: guard.map(|guard| *guard),
+                    body,
+                },
+            )
+            .collect();
+
+        if let Some(missing) = self.match_missing.take() {
+            let sentinel = call(load("object"), Vec::new(), Vec::new());
+            stmts.push(assign(&missing, sentinel, TextRange::default()));
+        }
+        let needs_flag = cases.iter().rev().skip(1).any(Case::ends_chain);
+        if needs_flag {
+            stmts.extend(self.flagged_cases(cases));
+        } else {
+            stmts.extend(case_chain(cases));
+        }
+        if let Some(first) = stmts.first_mut() {
+            set_range(first, range);
+        }
+        stmts
+    }
+
+    /// One `if` statement for each case that only runs if no previous case matched.
+    fn flagged_cases(&mut self, cases: Vec<Case>) -> Vec<Stmt<TextRange>> {
+        let flag = self.fresh_name("_matched");
+        let mut stmts = vec![assign(
+            &flag,
+            constant(Constant::Bool(false)),
+            TextRange::default(),
+        )];
+        let last = cases.len() - 1;
+        for (index, case) in cases.into_iter().enumerate() {
+            let mut steps = Vec::new();
+            if index > 0 {
+                steps.push(Step::Check(not(load(&flag))));
+            }
+            steps.extend(case.steps());
+            let mut set_flag = Vec::new();
+            if index < last {
+                set_flag.push(assign(
+                    &flag,
+                    constant(Constant::Bool(true)),
+                    TextRange::default(),
+                ));
+            }
+            let body = if case.guard_needs_bindings() {
+                case.branch_body(set_flag)
+            } else {
+                set_flag
+                    .into_iter()
+                    .chain(case.branch_body(Vec::new()))
+                    .collect()
+            };
+            stmts.extend(nest(steps, body));
+        }
+        stmts
+    }
+
+    fn lower_pattern(&mut self, pattern: Pattern<TextRange>, subject: &Expr<TextRange>) -> Match {
+        let mut lowered = Match::default();
+        self.lower_subpattern(pattern, subject.clone(), &mut lowered);
+        lowered
+    }
+
+    /// `value` as the subject of `pattern`, which is bound to a temporary name first
+    /// if the pattern uses its subject more than once.
+    fn subpattern_subject(
+        &mut self,
+        pattern: &Pattern<TextRange>,
+        value: Expr<TextRange>,
+        lowered: &mut Match,
+    ) -> Expr<TextRange> {
+        let uses_subject_once = match pattern {
+            Pattern::MatchValue(_) | Pattern::MatchSingleton(_) | Pattern::MatchStar(_) => true,
+            Pattern::MatchAs(data) => data.pattern.is_none(),
+            _ => false,
+        };
+        if uses_subject_once {
+            return value;
+        }
+        let temp = self.fresh_name("_match_item");
+        lowered
+            .steps
+            .push(Step::Run(vec![assign(&temp, value, TextRange::default())]));
+        load(&temp)
+    }
+
+    fn lower_subpattern(
+        &mut self,
+        pattern: Pattern<TextRange>,
+        subject: Expr<TextRange>,
+        lowered: &mut Match,
+    ) {
+        match pattern {
+            Pattern::MatchValue(data) => {
+                lowered.check(compare(subject, CmpOp::Eq, *data.value));
+            }
+            Pattern::MatchSingleton(data) => {
+                lowered.check(compare(subject, CmpOp::Is, constant(data.value)));
+            }
+            Pattern::MatchSequence(data) => {
+                let collections_abc = self.import_module("collections.abc");
+                lowered.check(call(
+                    load("isinstance"),
+                    vec![subject.clone(), attribute(collections_abc, "Sequence")],
+                    Vec::new(),
+                ));
+                let strings = tuple(vec![load("str"), load("bytes"), load("bytearray")]);
+                lowered.check(not(call(
+                    load("isinstance"),
+                    vec![subject.clone(), strings],
+                    Vec::new(),
+                )));
+
+                let length = data.patterns.len();
+                let star = data
+                    .patterns
+                    .iter()
+                    .position(|pattern| pattern.is_match_star());
+                let len = call(load("len"), vec![subject.clone()], Vec::new());
+                lowered.check(match star {
+                    Some(_) => compare(len, CmpOp::GtE, int_constant(length - 1)),
+                    None => compare(len, CmpOp::Eq, int_constant(length)),
+                });
+                for (index, pattern) in data.patterns.into_iter().enumerate() {
+                    let item = match star {
+                        Some(star) if index > star => {
+                            index_expr(subject.clone(), negative_int_constant(length - index))
+                        }
+                        _ => index_expr(subject.clone(), int_constant(index)),
+                    };
+                    match pattern {
+                        Pattern::MatchStar(star_pattern) => {
+                            if let Some(name) = star_pattern.name {
+                                let after_star = length - index - 1;
+                                let slice = ExprSlice {
+                                    range: TextRange::default(),
+                                    lower: (index > 0).then(|| Box::new(int_constant(index))),
+                                    upper: (after_star > 0)
+                                        .then(|| Box::new(negative_int_constant(after_star))),
+                                    step: None,
+                                };
+                                let items = index_expr(subject.clone(), Expr::Slice(slice));
+                                let value = call(load("list"), vec![items], Vec::new());
+                                self.bind(lowered, name.as_str(), value);
+                            }
+                        }
+                        pattern => {
+                            let item = self.subpattern_subject(&pattern, item, lowered);
+                            self.lower_subpattern(pattern, item, lowered);
+                        }
+                    }
+                }
+            }
+            Pattern::MatchMapping(data) => {
+                let collections_abc = self.import_module("collections.abc");
+                lowered.check(call(
+                    load("isinstance"),
+                    vec![subject.clone(), attribute(collections_abc, "Mapping")],
+                    Vec::new(),
+                ));
+                let mut keys = Vec::new();
+                for (key, pattern) in data.keys.into_iter().zip(data.patterns) {
+                    // value patterns like `Color.RED` are only looked up once
+                    let key = match key {
+                        Expr::Attribute(_) => {
+                            let temp = self.fresh_name("_match_key");
+                            lowered.steps.push(Step::Run(vec![assign(
+                                &temp,
+                                key,
+                                TextRange::default(),
+                            )]));
+                            load(&temp)
+                        }
+                        key => key,
+                    };
+                    let missing = self.missing();
+                    let get = attribute(subject.clone(), "get");
+                    let value = call(get, vec![key.clone(), load(&missing)], Vec::new());
+                    let temp = self.fresh_name("_match_item");
+                    lowered
+                        .steps
+                        .push(Step::Run(vec![assign(&temp, value, TextRange::default())]));
+                    lowered.check(compare(load(&temp), CmpOp::IsNot, load(&missing)));
+                    self.lower_subpattern(pattern, load(&temp), lowered);
+                    keys.push(key);
+                }
+                if let Some(rest) = data.rest {
+                    let rest = rest.as_str();
+                    let rest_dict = call(load("dict"), vec![subject], Vec::new());
+                    self.bind(lowered, rest, rest_dict);
+                    if !keys.is_empty() {
+                        lowered.bindings.push(Stmt::Delete(StmtDelete {
+                            range: TextRange::default(),
+                            targets: keys
+                                .into_iter()
+                                .map(|key| {
+                                    let mut target = index_expr(load(rest), key);
+                                    if let Expr::Subscript(target) = &mut target {
+                                        target.ctx = ExprContext::Del;
+                                    }
+                                    target
+                                })
+                                .collect(),
+                        }));
+                    }
+                }
+            }
+            Pattern::MatchClass(data) => {
+                lowered.check(call(
+                    load("isinstance"),
+                    vec![subject.clone(), (*data.cls).clone()],
+                    Vec::new(),
+                ));
+                let matches_self = data.patterns.len() == 1
+                    && data
+                        .cls
+                        .as_name_expr()
+                        .is_some_and(|name| SELF_MATCHING_CLASSES.contains(&name.id.as_str()));
+                if matches_self {
+                    for pattern in data.patterns {
+                        self.lower_subpattern(pattern, subject.clone(), lowered);
+                    }
+                } else if !data.patterns.is_empty() {
+                    let match_args = self.match_args(&data.cls, data.patterns.len(), lowered);
+                    for (index, pattern) in data.patterns.into_iter().enumerate() {
+                        let attr = index_expr(load(&match_args), int_constant(index));
+                        let value = self.class_pattern_attribute(&subject, attr, lowered);
+                        self.lower_subpattern(pattern, value, lowered);
+                    }
+                }
+                for (attr, pattern) in data.kwd_attrs.iter().zip(data.kwd_patterns) {
+                    let attr = str_constant(attr.as_str());
+                    let value = self.class_pattern_attribute(&subject, attr, lowered);
+                    self.lower_subpattern(pattern, value, lowered);
+                }
+            }
+            // only valid in sequence patterns, which handle it
+            Pattern::MatchStar(_) => {}
+            Pattern::MatchAs(data) => {
+                if let Some(pattern) = data.pattern {
+                    self.lower_subpattern(*pattern, subject.clone(), lowered);
+                }
+                if let Some(name) = data.name {
+                    self.bind(lowered, name.as_str(), subject);
+                }
+            }
+            Pattern::MatchOr(data) => {
+                let alternatives: Vec<_> = data
+                    .patterns
+                    .into_iter()
+                    .map(|pattern| self.lower_pattern(pattern, &subject))
+                    .collect();
+                if alternatives.iter().any(Match::runs_statements) {
+                    self.lower_alternatives(alternatives, lowered);
+                    return;
+                }
+                // an irrefutable alternative makes the whole pattern irrefutable
+                let tests: Option<Vec<_>> = alternatives.iter().map(Match::test).collect();
+                if let Some(tests) = tests {
+                    lowered
+                        .steps
+                        .extend(bool_op(BoolOp::Or, tests).map(Step::Check));
+                }
+                let binds_names = alternatives
+                    .iter()
+                    .any(|alternative| !alternative.bindings.is_empty());
+                let same_bindings = alternatives
+                    .windows(2)
+                    .all(|pair| pair[0].bindings == pair[1].bindings);
+                if binds_names && same_bindings {
+                    // e.g. `["go", direction] | ["move", direction]`
+                    let mut alternatives = alternatives.into_iter();
+                    if let Some(alternative) = alternatives.next() {
+                        lowered.captures.extend(alternative.captures);
+                        lowered.bindings.extend(alternative.bindings);
+                    }
+                } else if binds_names {
+                    let mut bindings = Vec::new();
+                    for alternative in alternatives.into_iter().rev() {
+                        lowered
+                            .captures
+                            .extend(alternative.captures.iter().cloned());
+                        bindings = match alternative.test() {
+                            Some(test) if !bindings.is_empty() => {
+                                vec![if_stmt(test, alternative.bindings, bindings)]
+                            }
+                            _ => alternative.bindings,
+                        };
+                    }
+                    lowered.bindings.extend(bindings);
+                }
+            }
+        }
+    }
+
+    /// Binds the positional attribute names of `cls` for a class pattern with `count`
+    /// positional sub-patterns to a temporary name, raising a `TypeError` like `match` does
+    /// if `cls` doesn't have that many.
+    fn match_args(&mut self, cls: &Expr<TextRange>, count: usize, lowered: &mut Match) -> String {
+        let match_args = self.fresh_name("_match_args");
+        let default = tuple(Vec::new());
+        let value = call(
+            load("getattr"),
+            vec![cls.clone(), str_constant("__match_args__"), default],
+            Vec::new(),
+        );
+        let len = call(load("len"), vec![load(&match_args)], Vec::new());
+        let message = Expr::BinOp(ExprBinOp {
+            range: TextRange::default(),
+            left: Box::new(str_constant(
+                "%s() accepts %d positional sub-patterns (%d given)",
+            )),
+            op: Operator::Mod,
+            right: Box::new(tuple(vec![
+                attribute(cls.clone(), "__name__"),
+                len.clone(),
+                int_constant(count),
+            ])),
+        });
+        let raise = Stmt::Raise(StmtRaise {
+            range: TextRange::default(),
+            exc: Some(Box::new(call(load("TypeError"), vec![message], Vec::new()))),
+            cause: None,
+        });
+        lowered.steps.push(Step::Run(vec![
+            assign(&match_args, value, TextRange::default()),
+            if_stmt(
+                compare(len, CmpOp::Lt, int_constant(count)),
+                vec![raise],
+                Vec::new(),
+            ),
+        ]));
+        match_args
+    }
+
+    /// Looks up the attribute named `attr` of `subject` once, like a class pattern does,
+    /// and checks that it exists. Returns the temporary name it's bound to.
+    fn class_pattern_attribute(
+        &mut self,
+        subject: &Expr<TextRange>,
+        attr: Expr<TextRange>,
+        lowered: &mut Match,
+    ) -> Expr<TextRange> {
+        let missing = self.missing();
+        let temp = self.fresh_name("_match_item");
+        let value = call(
+            load("getattr"),
+            vec![subject.clone(), attr, load(&missing)],
+            Vec::new(),
+        );
+        lowered
+            .steps
+            .push(Step::Run(vec![assign(&temp, value, TextRange::default())]));
+        lowered.check(compare(load(&temp), CmpOp::IsNot, load(&missing)));
+        load(&temp)
+    }
+
+    /// The name of the sentinel that stands for missing attributes and mapping keys.
+    fn missing(&mut self) -> String {
+        match &self.match_missing {
+            Some(missing) => missing.clone(),
+            None => {
+                let missing = self.fresh_name("_match_missing");
+                self.match_missing = Some(missing.clone());
+                missing
+            }
+        }
+    }
+
+    /// Matches an or-pattern whose alternatives run statements by trying them one after
+    /// another until one of them sets a flag, binding its captures when it does.
+    fn lower_alternatives(&mut self, alternatives: Vec<Match>, lowered: &mut Match) {
+        let flag = self.fresh_name("_match_alternative");
+        let mut stmts = vec![assign(
+            &flag,
+            constant(Constant::Bool(false)),
+            TextRange::default(),
+        )];
+        for (index, alternative) in alternatives.into_iter().enumerate() {
+            let mut steps = Vec::new();
+            if index > 0 {
+                steps.push(Step::Check(not(load(&flag))));
+            }
+            steps.extend(alternative.steps);
+            let mut body = vec![assign(
+                &flag,
+                constant(Constant::Bool(true)),
+                TextRange::default(),
+            )];
+            body.extend(alternative.bindings);
+            stmts.extend(nest(steps, body));
+            lowered.captures.extend(alternative.captures);
+        }
+        lowered.steps.push(Step::Run(stmts));
+        lowered.check(load(&flag));
+    }
+
+    fn bind(&mut self, lowered: &mut Match, name: &str, value: Expr<TextRange>) {
+        lowered.captures.insert(name.to_string());
+        lowered
+            .bindings
+            .push(assign(name, value, TextRange::default()));
+    }
+}
+
+/// Statements that run `body` if all `steps` succeed: nested `if` statements
+/// for the checks, with the statements of the steps between them.
+fn nest(steps: Vec<Step>, body: Vec<Stmt<TextRange>>) -> Vec<Stmt<TextRange>> {
+    let mut stmts = body;
+    let mut checks = Vec::new();
+    for step in steps.into_iter().rev() {
+        match step {
+            Step::Check(check) => checks.insert(0, check),
+            Step::Run(run) => {
+                if let Some(test) = bool_op(BoolOp::And, std::mem::take(&mut checks)) {
+                    stmts = vec![if_stmt(test, stmts, Vec::new())];
+                }
+                stmts = run.into_iter().chain(stmts).collect();
+            }
+        }
+    }
+    match bool_op(BoolOp::And, checks) {
+        Some(test) => vec![if_stmt(test, stmts, Vec::new())],
+        None => stmts,
+    }
+}
+
+/// An `if`/`elif` chain with a branch for each case.
+/// The last case must be the only one whose guard needs the bindings.
+fn case_chain(cases: Vec<Case>) -> Vec<Stmt<TextRange>> {
+    let mut orelse = Vec::new();
+    for case in cases.into_iter().rev() {
+        if case.pattern.runs_statements() {
+            let steps = case.steps();
+            orelse = nest(steps, case.branch_body(Vec::new()));
+            continue;
+        }
+        orelse = match bool_op(BoolOp::And, step_checks(case.steps())) {
+            Some(test) => vec![if_stmt(test, case.branch_body(Vec::new()), orelse)],
+            // cases after an irrefutable one are unreachable
+            None => case.branch_body(Vec::new()),
+        };
+    }
+    orelse
+}
+
+/// The checks of steps that don't run statements.
+fn step_checks(steps: Vec<Step>) -> Vec<Expr<TextRange>> {
+    steps
+        .into_iter()
+        .filter_map(|step| match step {
+            Step::Check(check) => Some(check),
+            Step::Run(_) => None,
+        })
+        .collect()
+}
+
+fn index_expr(value: Expr<TextRange>, index: Expr<TextRange>) -> Expr<TextRange> {
+    Expr::Subscript(ExprSubscript {
+        range: TextRange::default(),
+        value: Box::new(value),
+        slice: Box::new(index),
+        ctx: ExprContext::Load,
+    })
+}
+
+/// The names `pattern` captures.
+fn pattern_captures(pattern: &Pattern<TextRange>) -> HashSet<String> {
+    let mut captures = HashSet::new();
+    let mut patterns = vec![pattern];
+    while let Some(pattern) = patterns.pop() {
+        match pattern {
+            Pattern::MatchValue(_) | Pattern::MatchSingleton(_) => {}
+            Pattern::MatchSequence(data) => patterns.extend(&data.patterns),
+            Pattern::MatchMapping(data) => {
+                patterns.extend(&data.patterns);
+                captures.extend(data.rest.as_ref().map(|rest| rest.to_string()));
+            }
+            Pattern::MatchClass(data) => {
+                patterns.extend(&data.patterns);
+                patterns.extend(&data.kwd_patterns);
+            }
+            Pattern::MatchStar(data) => {
+                captures.extend(data.name.as_ref().map(|name| name.to_string()));
+            }
+            Pattern::MatchAs(data) => {
+                patterns.extend(data.pattern.as_deref());
+                captures.extend(data.name.as_ref().map(|name| name.to_string()));
+            }
+            Pattern::MatchOr(data) => patterns.extend(&data.patterns),
+        }
+    }
+    captures
+}
+
+fn set_range(node: &mut Stmt<TextRange>, range: TextRange) {
+    match node {
+        Stmt::If(data) => data.range = range,
+        Stmt::Assign(data) => data.range = range,
+        _ => {}
+    }
+}
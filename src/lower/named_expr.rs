@@ -0,0 +1,417 @@
+//! Assignment expressions, hoisted into assignments for Python 3.7.
+
+use std::collections::HashSet;
+
+use rustpython_ast::{
+    text_size::TextRange, Arguments, Comprehension, Constant, Expr, ExprContext, Stmt, StmtBreak,
+    StmtWhile,
+};
+
+use super::{assign, constant, if_stmt, name, not, Lowering};
+use crate::version::Feature;
+
+/// Why an assignment expression can't be hoisted out of where it is.
+const CONDITIONAL: &str = "it's only evaluated depending on the expression before it";
+const REORDERED: &str = "hoisting it would evaluate it before code it's written after";
+const COMPREHENSION: &str = "it's inside a comprehension";
+const LAMBDA: &str = "it's inside a lambda";
+const ASSERT: &str = "assert statements aren't run with optimizations enabled";
+const LOOP_ELSE: &str = "the else block of its while loop contains break or continue";
+
+/// The assignment expressions hoisted out of the expressions of a statement.
+#[derive(Default)]
+struct Hoisting {
+    assignments: Vec<Stmt<TextRange>>,
+    /// Whether the code evaluated so far can have side effects.
+    has_side_effects: bool,
+    /// Names loaded by the code evaluated so far.
+    loaded_names: HashSet<String>,
+}
+
+impl Lowering<'_> {
+    /// Moves assignment expressions out of the expressions of `node` into assignments before it,
+    /// as long as that doesn't change what's evaluated or in which order.
+    /// The test of a `while` loop is evaluated again at the start of each iteration instead.
+    pub(crate) fn lower_named_exprs(&mut self, node: Stmt<TextRange>) -> Vec<Stmt<TextRange>> {
+        let mut hoisting = Hoisting::default();
+        let mut node = node;
+        match &mut node {
+            Stmt::FunctionDef(data) => {
+                self.hoist_exprs(&mut data.decorator_list, &mut hoisting);
+                self.hoist_arguments(&mut data.args, &mut hoisting);
+            }
+            Stmt::AsyncFunctionDef(data) => {
+                self.hoist_exprs(&mut data.decorator_list, &mut hoisting);
+                self.hoist_arguments(&mut data.args, &mut hoisting);
+            }
+            Stmt::ClassDef(data) => {
+                self.hoist_exprs(&mut data.decorator_list, &mut hoisting);
+                self.hoist_exprs(&mut data.bases, &mut hoisting);
+                for keyword in &mut data.keywords {
+                    self.hoist(&mut keyword.value, &mut hoisting, None);
+                }
+            }
+            Stmt::Return(data) => {
+                if let Some(value) = &mut data.value {
+                    self.hoist(value, &mut hoisting, None);
+                }
+            }
+            Stmt::Delete(data) => self.hoist_exprs(&mut data.targets, &mut hoisting),
+            Stmt::Assign(data) => {
+                self.hoist(&mut data.value, &mut hoisting, None);
+                self.hoist_exprs(&mut data.targets, &mut hoisting);
+            }
+            Stmt::AugAssign(data) => {
+                self.hoist(&mut data.target, &mut hoisting, None);
+                self.hoist(&mut data.value, &mut hoisting, None);
+            }
+            Stmt::AnnAssign(data) => {
+                if let Some(value) = &mut data.value {
+                    self.hoist(value, &mut hoisting, None);
+                }
+                self.hoist(&mut data.target, &mut hoisting, None);
+                self.hoist(&mut data.annotation, &mut hoisting, None);
+            }
+            Stmt::For(data) => self.hoist(&mut data.iter, &mut hoisting, None),
+            Stmt::AsyncFor(data) => self.hoist(&mut data.iter, &mut hoisting, None),
+            Stmt::While(data) => {
+                let mut loop_hoisting = Hoisting::default();
+                self.hoist(&mut data.test, &mut loop_hoisting, None);
+                if !loop_hoisting.assignments.is_empty() {
+                    if data.orelse.iter().any(breaks_or_continues) {
+                        self.cannot_downlevel(Feature::AssignmentExpression, LOOP_ELSE, data.range);
+                    } else {
+                        return vec![hoisted_while(
+                            std::mem::replace(data, empty_while()),
+                            loop_hoisting.assignments,
+                        )];
+                    }
+                }
+            }
+            Stmt::If(data) => self.hoist(&mut data.test, &mut hoisting, None),
+            Stmt::With(data) => {
+                for item in &mut data.items {
+                    self.hoist(&mut item.context_expr, &mut hoisting, None);
+                    // entering the context manager can have side effects
+                    hoisting.has_side_effects = true;
+                }
+            }
+            Stmt::AsyncWith(data) => {
+                for item in &mut data.items {
+                    self.hoist(&mut item.context_expr, &mut hoisting, None);
+                    hoisting.has_side_effects = true;
+                }
+            }
+            Stmt::Raise(data) => {
+                if let Some(exc) = &mut data.exc {
+                    self.hoist(exc, &mut hoisting, None);
+                }
+                if let Some(cause) = &mut data.cause {
+                    self.hoist(cause, &mut hoisting, None);
+                }
+            }
+            Stmt::Assert(data) => {
+                self.hoist(&mut data.test, &mut hoisting, Some(ASSERT));
+                if let Some(msg) = &mut data.msg {
+                    self.hoist(msg, &mut hoisting, Some(ASSERT));
+                }
+            }
+            Stmt::Expr(data) => self.hoist(&mut data.value, &mut hoisting, None),
+            _ => {}
+        }
+        let mut stmts = hoisting.assignments;
+        stmts.push(node);
+        stmts
+    }
+
+    fn hoist_exprs(&mut self, exprs: &mut [Expr<TextRange>], hoisting: &mut Hoisting) {
+        for expr in exprs {
+            self.hoist(expr, hoisting, None);
+        }
+    }
+
+    /// Default values of parameters, which are evaluated when the function is defined.
+    fn hoist_arguments(&mut self, arguments: &mut Arguments<TextRange>, hoisting: &mut Hoisting) {
+        let parameters = arguments
+            .posonlyargs
+            .iter_mut()
+            .chain(&mut arguments.args)
+            .chain(&mut arguments.kwonlyargs);
+        for parameter in parameters {
+            if let Some(default) = &mut parameter.default {
+                self.hoist(default, hoisting, None);
+            }
+        }
+    }
+
+    /// Goes through `node` in evaluation order and replaces the assignment expressions
+    /// that can be hoisted with their target. `not_hoistable` is why the assignment
+    /// expressions in `node` can't be hoisted, if they can't.
+    fn hoist(
+        &mut self,
+        node: &mut Expr<TextRange>,
+        hoisting: &mut Hoisting,
+        not_hoistable: Option<&'static str>,
+    ) {
+        let conditional = not_hoistable.or(Some(CONDITIONAL));
+        match node {
+            Expr::NamedExpr(data) => {
+                let Expr::Name(target) = data.target.as_ref() else {
+                    return;
+                };
+                let target = target.id.to_string();
+                // the value could rebind the names loaded before it, e.g. `a + (b := bump())`
+                let reason = not_hoistable.or_else(|| {
+                    (hoisting.has_side_effects
+                        || hoisting.loaded_names.contains(&target)
+                        || !hoisting.loaded_names.is_empty() && !is_side_effect_free(&data.value))
+                    .then_some(REORDERED)
+                });
+                let loaded_names = hoisting.loaded_names.clone();
+                self.hoist(&mut data.value, hoisting, not_hoistable);
+                if let Some(reason) = reason {
+                    self.cannot_downlevel(Feature::AssignmentExpression, reason, data.range);
+                    hoisting.has_side_effects = true;
+                    return;
+                }
+                // the value is now evaluated before everything that comes after it
+                hoisting.has_side_effects = false;
+                hoisting.loaded_names = loaded_names;
+                hoisting.loaded_names.insert(target.clone());
+                let value = std::mem::replace(&mut *data.value, constant(Constant::None));
+                hoisting
+                    .assignments
+                    .push(assign(&target, value, data.range));
+                *node = name(&target, ExprContext::Load);
+            }
+            Expr::BoolOp(data) => {
+                let mut values = data.values.iter_mut();
+                if let Some(first) = values.next() {
+                    self.hoist(first, hoisting, not_hoistable);
+                }
+                for value in values {
+                    self.hoist(value, hoisting, conditional);
+                }
+            }
+            Expr::BinOp(data) => {
+                self.hoist(&mut data.left, hoisting, not_hoistable);
+                self.hoist(&mut data.right, hoisting, not_hoistable);
+                hoisting.has_side_effects = true;
+            }
+            Expr::UnaryOp(data) => {
+                self.hoist(&mut data.operand, hoisting, not_hoistable);
+                hoisting.has_side_effects = true;
+            }
+            Expr::Lambda(data) => {
+                self.hoist_arguments(&mut data.args, hoisting);
+                self.hoist(&mut data.body, hoisting, Some(LAMBDA));
+            }
+            Expr::IfExp(data) => {
+                self.hoist(&mut data.test, hoisting, not_hoistable);
+                self.hoist(&mut data.body, hoisting, conditional);
+                self.hoist(&mut data.orelse, hoisting, conditional);
+            }
+            Expr::Dict(data) => {
+                for (key, value) in data.keys.iter_mut().zip(&mut data.values) {
+                    if let Some(key) = key {
+                        self.hoist(key, hoisting, not_hoistable);
+                    }
+                    self.hoist(value, hoisting, not_hoistable);
+                }
+            }
+            Expr::Set(data) => self.hoist_all(&mut data.elts, hoisting, not_hoistable),
+            Expr::List(data) => self.hoist_all(&mut data.elts, hoisting, not_hoistable),
+            Expr::Tuple(data) => self.hoist_all(&mut data.elts, hoisting, not_hoistable),
+            Expr::ListComp(data) => {
+                self.hoist_comprehension(&mut data.generators, hoisting, not_hoistable);
+                self.hoist(&mut data.elt, hoisting, Some(COMPREHENSION));
+            }
+            Expr::SetComp(data) => {
+                self.hoist_comprehension(&mut data.generators, hoisting, not_hoistable);
+                self.hoist(&mut data.elt, hoisting, Some(COMPREHENSION));
+            }
+            Expr::DictComp(data) => {
+                self.hoist_comprehension(&mut data.generators, hoisting, not_hoistable);
+                self.hoist(&mut data.key, hoisting, Some(COMPREHENSION));
+                self.hoist(&mut data.value, hoisting, Some(COMPREHENSION));
+            }
+            Expr::GeneratorExp(data) => {
+                self.hoist_comprehension(&mut data.generators, hoisting, not_hoistable);
+                self.hoist(&mut data.elt, hoisting, Some(COMPREHENSION));
+            }
+            Expr::Await(data) => {
+                self.hoist(&mut data.value, hoisting, not_hoistable);
+                hoisting.has_side_effects = true;
+            }
+            Expr::Yield(data) => {
+                if let Some(value) = &mut data.value {
+                    self.hoist(value, hoisting, not_hoistable);
+                }
+                hoisting.has_side_effects = true;
+            }
+            Expr::YieldFrom(data) => {
+                self.hoist(&mut data.value, hoisting, not_hoistable);
+                hoisting.has_side_effects = true;
+            }
+            Expr::Compare(data) => {
+                self.hoist(&mut data.left, hoisting, not_hoistable);
+                for (index, comparator) in data.comparators.iter_mut().enumerate() {
+                    // `a < b < c` only evaluates `c` if `a < b`
+                    let not_hoistable = if index == 0 {
+                        not_hoistable
+                    } else {
+                        conditional
+                    };
+                    self.hoist(comparator, hoisting, not_hoistable);
+                }
+                hoisting.has_side_effects = true;
+            }
+            Expr::Call(data) => {
+                self.hoist(&mut data.func, hoisting, not_hoistable);
+                self.hoist_all(&mut data.args, hoisting, not_hoistable);
+                for keyword in &mut data.keywords {
+                    self.hoist(&mut keyword.value, hoisting, not_hoistable);
+                }
+                hoisting.has_side_effects = true;
+            }
+            Expr::FormattedValue(data) => {
+                self.hoist(&mut data.value, hoisting, not_hoistable);
+                if let Some(format_spec) = &mut data.format_spec {
+                    self.hoist(format_spec, hoisting, not_hoistable);
+                }
+                hoisting.has_side_effects = true;
+            }
+            Expr::JoinedStr(data) => self.hoist_all(&mut data.values, hoisting, not_hoistable),
+            Expr::Constant(_) => {}
+            // looking up attributes is assumed to be free of side effects
+            Expr::Attribute(data) => self.hoist(&mut data.value, hoisting, not_hoistable),
+            Expr::Subscript(data) => {
+                self.hoist(&mut data.value, hoisting, not_hoistable);
+                self.hoist(&mut data.slice, hoisting, not_hoistable);
+                hoisting.has_side_effects = true;
+            }
+            Expr::Starred(data) => self.hoist(&mut data.value, hoisting, not_hoistable),
+            Expr::Name(data) => {
+                hoisting.loaded_names.insert(data.id.to_string());
+            }
+            Expr::Slice(data) => {
+                for bound in [&mut data.lower, &mut data.upper, &mut data.step]
+                    .into_iter()
+                    .flatten()
+                {
+                    self.hoist(bound, hoisting, not_hoistable);
+                }
+            }
+        }
+    }
+
+    fn hoist_all(
+        &mut self,
+        exprs: &mut [Expr<TextRange>],
+        hoisting: &mut Hoisting,
+        not_hoistable: Option<&'static str>,
+    ) {
+        for expr in exprs {
+            self.hoist(expr, hoisting, not_hoistable);
+        }
+    }
+
+    /// Only the iterable of the first generator is evaluated outside of the comprehension.
+    fn hoist_comprehension(
+        &mut self,
+        generators: &mut [Comprehension<TextRange>],
+        hoisting: &mut Hoisting,
+        not_hoistable: Option<&'static str>,
+    ) {
+        for (index, generator) in generators.iter_mut().enumerate() {
+            let not_hoistable = if index == 0 {
+                not_hoistable
+            } else {
+                Some(COMPREHENSION)
+            };
+            self.hoist(&mut generator.iter, hoisting, not_hoistable);
+            self.hoist(&mut generator.target, hoisting, Some(COMPREHENSION));
+            self.hoist_all(&mut generator.ifs, hoisting, Some(COMPREHENSION));
+        }
+    }
+}
+
+/// `while test: body else: orelse` as `while True:` that starts with the hoisted assignments,
+/// then runs `orelse` and breaks if `test` is false.
+fn hoisted_while(node: StmtWhile<TextRange>, assignments: Vec<Stmt<TextRange>>) -> Stmt<TextRange> {
+    let mut exit = node.orelse;
+    exit.push(Stmt::Break(StmtBreak {
+        range: TextRange::default(),
+    }));
+    let mut body = assignments;
+    body.push(if_stmt(not(*node.test), exit, Vec::new()));
+    body.extend(node.body);
+    Stmt::While(StmtWhile {
+        range: node.range,
+        test: Box::new(constant(Constant::Bool(true))),
+        body,
+        orelse: Vec::new(),
+    })
+}
+
+fn empty_while() -> StmtWhile<TextRange> {
+    StmtWhile {
+        range: TextRange::default(),
+        test: Box::new(constant(Constant::Bool(true))),
+        body: Vec::new(),
+        orelse: Vec::new(),
+    }
+}
+
+/// Whether evaluating `node` can't run any code other than looking up names.
+fn is_side_effect_free(node: &Expr<TextRange>) -> bool {
+    match node {
+        Expr::Constant(_) | Expr::Name(_) => true,
+        Expr::Tuple(data) => data.elts.iter().all(is_side_effect_free),
+        Expr::List(data) => data.elts.iter().all(is_side_effect_free),
+        Expr::NamedExpr(data) => is_side_effect_free(&data.value),
+        _ => false,
+    }
+}
+
+/// Whether `node` contains a `break` or `continue` for the loop it's in.
+fn breaks_or_continues(node: &Stmt<TextRange>) -> bool {
+    match node {
+        Stmt::Break(_) | Stmt::Continue(_) => true,
+        Stmt::If(data) => data
+            .body
+            .iter()
+            .chain(&data.orelse)
+            .any(breaks_or_continues),
+        Stmt::With(data) => data.body.iter().any(breaks_or_continues),
+        Stmt::AsyncWith(data) => data.body.iter().any(breaks_or_continues),
+        Stmt::Match(data) => data
+            .cases
+            .iter()
+            .any(|case| case.body.iter().any(breaks_or_continues)),
+        Stmt::Try(data) => {
+            data.body
+                .iter()
+                .chain(&data.orelse)
+                .chain(&data.finalbody)
+                .any(breaks_or_continues)
+                || data.handlers.iter().any(|handler| {
+                    let rustpython_ast::ExceptHandler::ExceptHandler(handler) = handler;
+                    handler.body.iter().any(breaks_or_continues)
+                })
+        }
+        Stmt::TryStar(data) => {
+            data.body
+                .iter()
+                .chain(&data.orelse)
+                .chain(&data.finalbody)
+                .any(breaks_or_continues)
+                || data.handlers.iter().any(|handler| {
+                    let rustpython_ast::ExceptHandler::ExceptHandler(handler) = handler;
+                    handler.body.iter().any(breaks_or_continues)
+                })
+        }
+        // loops and definitions have their own scope for `break` and `continue`
+        _ => false,
+    }
+}
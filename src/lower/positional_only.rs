@@ -0,0 +1,554 @@
+//! Positional-only parameters, turned into regular ones for Python 3.7.
+
+use std::collections::HashSet;
+use std::convert::Infallible;
+
+use rustpython_ast::{
+    fold, text_size::TextRange, Alias, Arg, ArgWithDefault, Arguments, CmpOp,
+    ExceptHandlerExceptHandler, Expr, ExprBinOp, ExprCompare, ExprContext, ExprIfExp, ExprName,
+    Fold, Identifier, Operator, PatternMatchAs, PatternMatchMapping, PatternMatchStar, Stmt,
+    StmtAnnAssign, StmtAssign, StmtRaise, UnaryOp,
+};
+
+use super::{
+    call, compare, expr_names, if_stmt, int_constant, is_docstring, load, name, names, not,
+    str_constant, subscript, unused_name, Lowering,
+};
+use crate::options::PositionalOnlyLowering;
+use crate::version::Feature;
+
+/// Why positional-only parameters can't be renamed.
+const GLOBAL: &str = "a nested function declares the name of one of them global";
+const NESTED_CLASS: &str = "a nested class uses one of them, whose renamed name would be mangled";
+const LOCALS: &str = "the function looks up its local variables by name";
+const DEFINITION: &str =
+    "a nested function or class has the name of one of them, whose `__name__` would change";
+const PACKAGE: &str = "the function imports a package with the name of one of them";
+/// Why positional-only parameters can't be unpacked from `*args`.
+const OTHER_PARAMETERS: &str = "regular parameters or *args follow them";
+const DEFAULT: &str = "a default value isn't a constant and would be evaluated on each call";
+const SUPER: &str = "the function calls super() without arguments";
+
+impl Lowering<'_> {
+    /// Turns the positional-only parameters of every function and lambda in `body`
+    /// into regular parameters as `options.positional_only_lowering` says.
+    /// A function that can't be rewritten that way is rewritten the other way if possible.
+    /// Lambdas always get `__`-prefixed parameters.
+    pub(crate) fn lower_positional_only(&mut self, body: &mut [Stmt<TextRange>]) {
+        let mut folder = PositionalOnlyFolder { lowering: self };
+        for stmt in body {
+            let Ok(lowered) = folder.fold_stmt(stmt.clone());
+            *stmt = lowered;
+        }
+    }
+
+    fn lower_function_parameters(
+        &mut self,
+        args: &mut Arguments<TextRange>,
+        body: &mut Vec<Stmt<TextRange>>,
+        function: &str,
+    ) {
+        let Some(first) = args.posonlyargs.first() else {
+            return;
+        };
+        let range = first.def.range;
+        let mut scope = Scope::default();
+        for stmt in body.iter() {
+            let Ok(_) = scope.fold_stmt(stmt.clone());
+        }
+        let rename_problem = scope.rename_problem(args);
+        let shim_problem = scope.shim_problem(args);
+        let shim = match (
+            self.options.positional_only_lowering,
+            rename_problem,
+            shim_problem,
+        ) {
+            (PositionalOnlyLowering::DunderPrefix, None, _) => false,
+            (PositionalOnlyLowering::ArgsShim, _, None) => true,
+            (_, None, _) => false,
+            (_, _, None) => true,
+            (PositionalOnlyLowering::DunderPrefix, Some(reason), _)
+            | (PositionalOnlyLowering::ArgsShim, _, Some(reason)) => {
+                self.cannot_downlevel(Feature::PositionalOnlyParameters, reason, range);
+                return;
+            }
+        };
+        let mut used_names = names(body);
+        used_names.extend(parameter_names(args));
+        if shim {
+            unpack_args(args, body, function, &mut used_names);
+        } else {
+            for mut renamer in rename_parameters(args, &mut used_names) {
+                let Ok(renamed) = std::mem::take(body)
+                    .into_iter()
+                    .map(|stmt| renamer.fold_stmt(stmt))
+                    .collect();
+                *body = renamed;
+            }
+        }
+    }
+
+    fn lower_lambda_parameters(
+        &mut self,
+        args: &mut Arguments<TextRange>,
+        body: &mut Expr<TextRange>,
+    ) {
+        let Some(first) = args.posonlyargs.first() else {
+            return;
+        };
+        let mut scope = Scope::default();
+        let Ok(_) = scope.fold_expr(body.clone());
+        if let Some(reason) = scope.rename_problem(args) {
+            self.cannot_downlevel(Feature::PositionalOnlyParameters, reason, first.def.range);
+            return;
+        }
+        let mut used_names = expr_names(body);
+        used_names.extend(parameter_names(args));
+        for mut renamer in rename_parameters(args, &mut used_names) {
+            let Ok(renamed) = renamer.fold_expr(body.clone());
+            *body = renamed;
+        }
+    }
+}
+
+struct PositionalOnlyFolder<'l, 'o> {
+    lowering: &'l mut Lowering<'o>,
+}
+
+impl Fold<TextRange> for PositionalOnlyFolder<'_, '_> {
+    type TargetU = TextRange;
+    type Error = Infallible;
+    type UserContext = ();
+
+    fn will_map_user(&mut self, _user: &TextRange) -> Self::UserContext {}
+
+    fn map_user(
+        &mut self,
+        user: TextRange,
+        _context: Self::UserContext,
+    ) -> Result<Self::TargetU, Self::Error> {
+        Ok(user)
+    }
+
+    fn fold_stmt(&mut self, node: Stmt<TextRange>) -> Result<Stmt<TextRange>, Infallible> {
+        let mut node = fold::fold_stmt(self, node)?;
+        match &mut node {
+            Stmt::FunctionDef(data) => {
+                self.lowering
+                    .lower_function_parameters(&mut data.args, &mut data.body, &data.name)
+            }
+            Stmt::AsyncFunctionDef(data) => {
+                self.lowering
+                    .lower_function_parameters(&mut data.args, &mut data.body, &data.name)
+            }
+            _ => {}
+        }
+        Ok(node)
+    }
+
+    fn fold_expr(&mut self, node: Expr<TextRange>) -> Result<Expr<TextRange>, Infallible> {
+        let mut node = fold::fold_expr(self, node)?;
+        if let Expr::Lambda(data) = &mut node {
+            self.lowering
+                .lower_lambda_parameters(&mut data.args, &mut data.body);
+        }
+        Ok(node)
+    }
+}
+
+/// What a function body does that keeps its parameters from being rewritten.
+#[derive(Default)]
+struct Scope {
+    /// Names declared global by nested functions.
+    globals: HashSet<String>,
+    /// Names used by nested classes.
+    class_names: HashSet<String>,
+    /// Names of nested functions and classes.
+    definitions: HashSet<String>,
+    /// Top-level packages bound by dotted imports like `import os.path`.
+    packages: HashSet<String>,
+    looks_up_locals: bool,
+    calls_super: bool,
+}
+
+impl Scope {
+    fn rename_problem(&self, args: &Arguments<TextRange>) -> Option<&'static str> {
+        let mut names = args.posonlyargs.iter().map(|arg| arg.def.arg.as_str());
+        if self.looks_up_locals {
+            Some(LOCALS)
+        } else if names.clone().any(|name| self.globals.contains(name)) {
+            Some(GLOBAL)
+        } else if names.clone().any(|name| self.class_names.contains(name)) {
+            Some(NESTED_CLASS)
+        } else if names.clone().any(|name| self.definitions.contains(name)) {
+            Some(DEFINITION)
+        } else if names.any(|name| self.packages.contains(name)) {
+            Some(PACKAGE)
+        } else {
+            None
+        }
+    }
+
+    fn shim_problem(&self, args: &Arguments<TextRange>) -> Option<&'static str> {
+        if !args.args.is_empty() || args.vararg.is_some() {
+            Some(OTHER_PARAMETERS)
+        } else if args
+            .posonlyargs
+            .iter()
+            .filter_map(|arg| arg.default.as_deref())
+            .any(|default| !is_constant(default))
+        {
+            Some(DEFAULT)
+        } else if self.looks_up_locals {
+            Some(LOCALS)
+        } else if self.calls_super {
+            Some(SUPER)
+        } else {
+            None
+        }
+    }
+}
+
+impl Fold<TextRange> for Scope {
+    type TargetU = TextRange;
+    type Error = Infallible;
+    type UserContext = ();
+
+    fn will_map_user(&mut self, _user: &TextRange) -> Self::UserContext {}
+
+    fn map_user(
+        &mut self,
+        user: TextRange,
+        _context: Self::UserContext,
+    ) -> Result<Self::TargetU, Self::Error> {
+        Ok(user)
+    }
+
+    fn fold_stmt(&mut self, node: Stmt<TextRange>) -> Result<Stmt<TextRange>, Infallible> {
+        match &node {
+            Stmt::Global(data) => self
+                .globals
+                .extend(data.names.iter().map(|name| name.to_string())),
+            Stmt::FunctionDef(data) => {
+                self.definitions.insert(data.name.to_string());
+            }
+            Stmt::AsyncFunctionDef(data) => {
+                self.definitions.insert(data.name.to_string());
+            }
+            Stmt::ClassDef(data) => {
+                self.definitions.insert(data.name.to_string());
+                self.class_names.extend(names(std::slice::from_ref(&node)));
+            }
+            Stmt::Import(data) => self.packages.extend(
+                data.names
+                    .iter()
+                    .filter(|alias| alias.asname.is_none())
+                    .filter_map(|alias| alias.name.split_once('.'))
+                    .map(|(package, _)| package.to_string()),
+            ),
+            _ => {}
+        }
+        fold::fold_stmt(self, node)
+    }
+
+    fn fold_expr(&mut self, node: Expr<TextRange>) -> Result<Expr<TextRange>, Infallible> {
+        if let Expr::Call(data) = &node {
+            if let Expr::Name(func) = data.func.as_ref() {
+                match func.id.as_str() {
+                    "locals" | "vars" | "eval" | "exec" => self.looks_up_locals = true,
+                    "super" if data.args.is_empty() && data.keywords.is_empty() => {
+                        self.calls_super = true
+                    }
+                    _ => {}
+                }
+            }
+        }
+        fold::fold_expr(self, node)
+    }
+}
+
+/// Renames positional-only parameters `name` to `__name`, the convention PEP 484
+/// uses for them, and moves them to the regular parameters.
+/// Note that callers can still pass them by the new name.
+///
+/// Returns the renamers that rename their uses.
+fn rename_parameters(
+    args: &mut Arguments<TextRange>,
+    used_names: &mut HashSet<String>,
+) -> Vec<Renamer> {
+    let mut renamers = Vec::new();
+    for arg in &mut args.posonlyargs {
+        let old_name = arg.def.arg.to_string();
+        if old_name.starts_with("__") {
+            continue;
+        }
+        let new_name = unused_name(&format!("__{}", old_name), used_names);
+        arg.def.arg = Identifier::new(new_name.clone());
+        renamers.push(Renamer {
+            from: old_name,
+            to: new_name,
+        });
+    }
+    let mut posonlyargs = std::mem::take(&mut args.posonlyargs);
+    posonlyargs.append(&mut args.args);
+    args.args = posonlyargs;
+    renamers
+}
+
+/// Replaces positional-only parameters with `*args` and assigns them from it
+/// at the start of the function, raising a `TypeError` for the wrong number of arguments.
+fn unpack_args(
+    args: &mut Arguments<TextRange>,
+    body: &mut Vec<Stmt<TextRange>>,
+    function: &str,
+    used_names: &mut HashSet<String>,
+) {
+    let args_name = unused_name("args", used_names);
+    let posonlyargs = std::mem::take(&mut args.posonlyargs);
+    let required = posonlyargs
+        .iter()
+        .take_while(|arg| arg.default.is_none())
+        .count();
+    let count = call(load("len"), vec![load(&args_name)], Vec::new());
+    let message = if required == posonlyargs.len() {
+        format!(
+            "{}() takes {} positional argument{} but %d were given",
+            function,
+            required,
+            if required == 1 { "" } else { "s" }
+        )
+    } else {
+        format!(
+            "{}() takes from {} to {} positional arguments but %d were given",
+            function,
+            required,
+            posonlyargs.len()
+        )
+    };
+    let wrong_count = if required == posonlyargs.len() {
+        compare(count.clone(), CmpOp::NotEq, int_constant(required))
+    } else {
+        not(Expr::Compare(ExprCompare {
+            range: TextRange::default(),
+            left: Box::new(int_constant(required)),
+            ops: vec![CmpOp::LtE, CmpOp::LtE],
+            comparators: vec![count.clone(), int_constant(posonlyargs.len())],
+        }))
+    };
+    let mut shim = vec![if_stmt(
+        wrong_count,
+        vec![Stmt::Raise(StmtRaise {
+            range: TextRange::default(),
+            exc: Some(Box::new(call(
+                load("TypeError"),
+                vec![Expr::BinOp(ExprBinOp {
+                    range: TextRange::default(),
+                    left: Box::new(str_constant(&message)),
+                    op: Operator::Mod,
+                    right: Box::new(count.clone()),
+                })],
+                Vec::new(),
+            ))),
+            cause: None,
+        })],
+        Vec::new(),
+    )];
+    for (index, arg) in posonlyargs.into_iter().enumerate() {
+        let ArgWithDefault { def, default, .. } = arg;
+        let mut value = subscript(load(&args_name), vec![int_constant(index)]);
+        if let Some(default) = default {
+            value = Expr::IfExp(ExprIfExp {
+                range: TextRange::default(),
+                test: Box::new(compare(count.clone(), CmpOp::Gt, int_constant(index))),
+                body: Box::new(value),
+                orelse: default,
+            });
+        }
+        let target = name(def.arg.as_str(), ExprContext::Store);
+        shim.push(match def.annotation {
+            Some(annotation) => Stmt::AnnAssign(StmtAnnAssign {
+                range: def.range,
+                target: Box::new(target),
+                annotation,
+                value: Some(Box::new(value)),
+                simple: true,
+            }),
+            None => Stmt::Assign(StmtAssign {
+                range: def.range,
+                targets: vec![target],
+                value: Box::new(value),
+                type_comment: None,
+            }),
+        });
+    }
+    let position = usize::from(body.first().is_some_and(is_docstring));
+    body.splice(position..position, shim);
+    args.vararg = Some(Box::new(Arg {
+        range: TextRange::default(),
+        arg: Identifier::new(args_name),
+        annotation: None,
+        type_comment: None,
+    }));
+}
+
+/// Renames a local variable in a function body, except in nested functions and lambdas
+/// that have a parameter with its name. Besides its uses, this renames the imports,
+/// exception handlers and patterns that bind it; functions and classes can't be renamed,
+/// see `DEFINITION`.
+struct Renamer {
+    from: String,
+    to: String,
+}
+
+impl Renamer {
+    fn binds(&self, args: &Arguments<TextRange>) -> bool {
+        parameter_names(args).any(|name| name == self.from)
+    }
+
+    fn rename(&self, name: &mut Identifier) {
+        if name.as_str() == self.from {
+            *name = Identifier::new(self.to.clone());
+        }
+    }
+
+    fn rename_optional(&self, name: &mut Option<Identifier>) {
+        if let Some(name) = name {
+            self.rename(name);
+        }
+    }
+}
+
+impl Fold<TextRange> for Renamer {
+    type TargetU = TextRange;
+    type Error = Infallible;
+    type UserContext = ();
+
+    fn will_map_user(&mut self, _user: &TextRange) -> Self::UserContext {}
+
+    fn map_user(
+        &mut self,
+        user: TextRange,
+        _context: Self::UserContext,
+    ) -> Result<Self::TargetU, Self::Error> {
+        Ok(user)
+    }
+
+    fn fold_stmt(&mut self, node: Stmt<TextRange>) -> Result<Stmt<TextRange>, Infallible> {
+        let mut node = node;
+        // The body of a nested function with a parameter of the same name refers to that,
+        // but its decorators, defaults and annotations refer to the renamed parameter.
+        let shadowed_body = match &mut node {
+            Stmt::FunctionDef(data) if self.binds(&data.args) => {
+                Some(std::mem::take(&mut data.body))
+            }
+            Stmt::AsyncFunctionDef(data) if self.binds(&data.args) => {
+                Some(std::mem::take(&mut data.body))
+            }
+            Stmt::Nonlocal(data) => {
+                for name in &mut data.names {
+                    self.rename(name);
+                }
+                None
+            }
+            _ => None,
+        };
+        let mut node = fold::fold_stmt(self, node)?;
+        if let Some(body) = shadowed_body {
+            match &mut node {
+                Stmt::FunctionDef(data) => data.body = body,
+                Stmt::AsyncFunctionDef(data) => data.body = body,
+                _ => {}
+            }
+        }
+        Ok(node)
+    }
+
+    fn fold_expr(&mut self, node: Expr<TextRange>) -> Result<Expr<TextRange>, Infallible> {
+        match node {
+            Expr::Lambda(mut data) if self.binds(&data.args) => {
+                let Ok(args) = self.fold_arguments(*data.args);
+                data.args = Box::new(args);
+                Ok(Expr::Lambda(data))
+            }
+            node => fold::fold_expr(self, node),
+        }
+    }
+
+    fn fold_expr_name(
+        &mut self,
+        mut node: ExprName<TextRange>,
+    ) -> Result<ExprName<TextRange>, Infallible> {
+        self.rename(&mut node.id);
+        Ok(node)
+    }
+
+    fn fold_alias(&mut self, mut node: Alias<TextRange>) -> Result<Alias<TextRange>, Infallible> {
+        match &mut node.asname {
+            Some(asname) => self.rename(asname),
+            // dotted imports bind their package, see `PACKAGE`
+            None if node.name.as_str() == self.from => {
+                node.asname = Some(Identifier::new(self.to.clone()))
+            }
+            None => {}
+        }
+        Ok(node)
+    }
+
+    fn fold_excepthandler_except_handler(
+        &mut self,
+        node: ExceptHandlerExceptHandler<TextRange>,
+    ) -> Result<ExceptHandlerExceptHandler<TextRange>, Infallible> {
+        let mut node = fold::fold_excepthandler_except_handler(self, node)?;
+        self.rename_optional(&mut node.name);
+        Ok(node)
+    }
+
+    fn fold_pattern_match_as(
+        &mut self,
+        node: PatternMatchAs<TextRange>,
+    ) -> Result<PatternMatchAs<TextRange>, Infallible> {
+        let mut node = fold::fold_pattern_match_as(self, node)?;
+        self.rename_optional(&mut node.name);
+        Ok(node)
+    }
+
+    fn fold_pattern_match_star(
+        &mut self,
+        mut node: PatternMatchStar<TextRange>,
+    ) -> Result<PatternMatchStar<TextRange>, Infallible> {
+        self.rename_optional(&mut node.name);
+        Ok(node)
+    }
+
+    fn fold_pattern_match_mapping(
+        &mut self,
+        node: PatternMatchMapping<TextRange>,
+    ) -> Result<PatternMatchMapping<TextRange>, Infallible> {
+        let mut node = fold::fold_pattern_match_mapping(self, node)?;
+        self.rename_optional(&mut node.rest);
+        Ok(node)
+    }
+}
+
+fn parameter_names(args: &Arguments<TextRange>) -> impl Iterator<Item = String> + '_ {
+    args.posonlyargs
+        .iter()
+        .chain(&args.args)
+        .chain(&args.kwonlyargs)
+        .map(|arg| &arg.def)
+        .chain(args.vararg.as_deref())
+        .chain(args.kwarg.as_deref())
+        .map(|arg| arg.arg.to_string())
+}
+
+/// Whether evaluating `node` again gives an equal value, e.g. `-1` or `None`.
+fn is_constant(node: &Expr<TextRange>) -> bool {
+    match node {
+        Expr::Constant(_) => true,
+        Expr::UnaryOp(data) => {
+            matches!(data.op, UnaryOp::USub | UnaryOp::UAdd) && is_constant(&data.operand)
+        }
+        Expr::Tuple(data) => data.elts.iter().all(is_constant),
+        _ => false,
+    }
+}
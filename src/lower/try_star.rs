@@ -0,0 +1,342 @@
+//! `except*` clauses, lowered to a regular `try` statement for Python 3.10 and older.
+
+use rustpython_ast::{
+    text_size::TextRange, Arg, ArgWithDefault, Arguments, BoolOp, CmpOp, Comprehension, Constant,
+    ExceptHandler, ExceptHandlerExceptHandler, Expr, ExprContext, ExprGeneratorExp, ExprIfExp,
+    ExprLambda, ExprList, ExprTuple, Identifier, OptionalRange, Stmt, StmtAssign, StmtExpr,
+    StmtRaise, StmtTry, StmtTryStar,
+};
+
+use super::{
+    assign, attribute, bool_op, call, compare, constant, if_stmt, int_constant, load, name, not,
+    str_constant, subscript, tuple, Lowering,
+};
+
+impl Lowering<'_> {
+    /// Rewrites `try`/`except*` into a `try` statement that catches everything,
+    /// wraps a naked exception in a `BaseExceptionGroup` and hands the part of the group
+    /// that each `except*` clause matches to it with `split()`.
+    /// Like `except*`, the exceptions the clauses raise are raised together with the
+    /// re-raised and unmatched parts of the group, which keep the group's structure.
+    /// The caught exception is raised as is if no clause matched. `else` and `finally` are kept.
+    pub(crate) fn lower_try_star(&mut self, node: Stmt<TextRange>) -> Vec<Stmt<TextRange>> {
+        let Stmt::TryStar(StmtTryStar {
+            range,
+            body,
+            handlers,
+            orelse,
+            finalbody,
+        }) = node
+        else {
+            return vec![node];
+        };
+        let base_exception_group = self.import("exceptiongroup", "BaseExceptionGroup");
+        let caught = self.fresh_name("_caught");
+        let group = self.fresh_name("_group");
+        let unhandled = self.fresh_name("_unhandled");
+        let handled = self.fresh_name("_handled");
+        let raised = self.fresh_name("_raised");
+        let reraised = self.fresh_name("_reraised");
+        let matching = self.fresh_name("_matching");
+        let handler_exception = self.fresh_name("_handler_exception");
+        let leaf = self.fresh_name("_leaf");
+        let part = self.fresh_name("_part");
+        let other = self.fresh_name("_other");
+
+        // _group = _caught if isinstance(_caught, BaseExceptionGroup) else BaseExceptionGroup('', [_caught])
+        let wrapped = Expr::IfExp(ExprIfExp {
+            range: TextRange::default(),
+            test: Box::new(call(
+                load("isinstance"),
+                vec![load(&caught), base_exception_group.clone()],
+                Vec::new(),
+            )),
+            body: Box::new(load(&caught)),
+            orelse: Box::new(call(
+                base_exception_group.clone(),
+                vec![str_constant(""), list(vec![load(&caught)])],
+                Vec::new(),
+            )),
+        });
+        let mut stmts = vec![
+            assign(&group, wrapped, TextRange::default()),
+            assign(&unhandled, load(&group), TextRange::default()),
+            assign(
+                &handled,
+                constant(Constant::Bool(false)),
+                TextRange::default(),
+            ),
+            assign(&raised, list(Vec::new()), TextRange::default()),
+            assign(&reraised, list(Vec::new()), TextRange::default()),
+        ];
+
+        for (index, handler) in handlers.into_iter().enumerate() {
+            let ExceptHandler::ExceptHandler(handler) = handler;
+            let Some(type_) = handler.type_ else {
+                continue;
+            };
+            // _matching, _unhandled = _unhandled.split(T) if _unhandled is not None else (None, None)
+            let mut split = call(
+                attribute(load(&unhandled), "split"),
+                vec![*type_],
+                Vec::new(),
+            );
+            if index > 0 {
+                split = Expr::IfExp(ExprIfExp {
+                    range: TextRange::default(),
+                    test: Box::new(is_not_none(load(&unhandled))),
+                    body: Box::new(split),
+                    orelse: Box::new(tuple(vec![
+                        constant(Constant::None),
+                        constant(Constant::None),
+                    ])),
+                });
+            }
+            stmts.push(Stmt::Assign(StmtAssign {
+                range: handler.range,
+                targets: vec![Expr::Tuple(ExprTuple {
+                    range: TextRange::default(),
+                    elts: vec![
+                        name(&matching, ExprContext::Store),
+                        name(&unhandled, ExprContext::Store),
+                    ],
+                    ctx: ExprContext::Store,
+                })],
+                value: Box::new(split),
+                type_comment: None,
+            }));
+
+            let mut handler_body = Vec::new();
+            if let Some(handler_name) = &handler.name {
+                handler_body.push(assign(
+                    handler_name.as_str(),
+                    load(&matching),
+                    TextRange::default(),
+                ));
+            }
+            let mut body = handler.body;
+            reraise_explicitly(&mut body, &matching);
+            handler_body.extend(body);
+            let collect = if_stmt(
+                compare(load(&handler_exception), CmpOp::Is, load(&matching)),
+                vec![append(&reraised, load(&handler_exception))],
+                vec![append(&raised, load(&handler_exception))],
+            );
+            let run_handler = Stmt::Try(StmtTry {
+                range: TextRange::default(),
+                body: handler_body,
+                handlers: vec![except_handler(
+                    load("BaseException"),
+                    &handler_exception,
+                    vec![collect],
+                )],
+                orelse: Vec::new(),
+                finalbody: Vec::new(),
+            });
+            stmts.push(if_stmt(
+                is_not_none(load(&matching)),
+                vec![
+                    assign(
+                        &handled,
+                        constant(Constant::Bool(true)),
+                        TextRange::default(),
+                    ),
+                    run_handler,
+                ],
+                Vec::new(),
+            ));
+        }
+
+        stmts.push(if_stmt(not(load(&handled)), vec![raise(None)], Vec::new()));
+        stmts.push(if_stmt(
+            is_not_none(load(&unhandled)),
+            vec![append(&reraised, load(&unhandled))],
+            Vec::new(),
+        ));
+        // _group.subgroup(lambda _leaf: not isinstance(_leaf, BaseExceptionGroup)
+        //     and any((_part.subgroup(lambda _other: _other is _leaf) is not None for _part in _reraised)))
+        let is_kept = Expr::GeneratorExp(ExprGeneratorExp {
+            range: TextRange::default(),
+            elt: Box::new(is_not_none(call(
+                attribute(load(&part), "subgroup"),
+                vec![lambda(
+                    &other,
+                    compare(load(&other), CmpOp::Is, load(&leaf)),
+                )],
+                Vec::new(),
+            ))),
+            generators: vec![Comprehension {
+                range: OptionalRange::default(),
+                target: name(&part, ExprContext::Store),
+                iter: load(&reraised),
+                ifs: Vec::new(),
+                is_async: false,
+            }],
+        });
+        let is_kept_leaf = bool_op(
+            BoolOp::And,
+            vec![
+                not(call(
+                    load("isinstance"),
+                    vec![load(&leaf), base_exception_group.clone()],
+                    Vec::new(),
+                )),
+                call(load("any"), vec![is_kept], Vec::new()),
+            ],
+        )
+        .unwrap();
+        stmts.push(if_stmt(
+            load(&reraised),
+            vec![append(
+                &raised,
+                call(
+                    attribute(load(&group), "subgroup"),
+                    vec![lambda(&leaf, is_kept_leaf)],
+                    Vec::new(),
+                ),
+            )],
+            Vec::new(),
+        ));
+        stmts.push(if_stmt(
+            compare(
+                call(load("len"), vec![load(&raised)], Vec::new()),
+                CmpOp::Eq,
+                int_constant(1),
+            ),
+            vec![raise(Some(subscript(load(&raised), vec![int_constant(0)])))],
+            Vec::new(),
+        ));
+        stmts.push(if_stmt(
+            load(&raised),
+            vec![raise(Some(call(
+                base_exception_group,
+                vec![str_constant(""), load(&raised)],
+                Vec::new(),
+            )))],
+            Vec::new(),
+        ));
+
+        vec![Stmt::Try(StmtTry {
+            range,
+            body,
+            handlers: vec![except_handler(load("BaseException"), &caught, stmts)],
+            orelse,
+            finalbody,
+        })]
+    }
+}
+
+fn is_not_none(value: Expr<TextRange>) -> Expr<TextRange> {
+    compare(value, CmpOp::IsNot, constant(Constant::None))
+}
+
+fn append(list: &str, value: Expr<TextRange>) -> Stmt<TextRange> {
+    Stmt::Expr(StmtExpr {
+        range: TextRange::default(),
+        value: Box::new(call(
+            attribute(load(list), "append"),
+            vec![value],
+            Vec::new(),
+        )),
+    })
+}
+
+fn lambda(param: &str, body: Expr<TextRange>) -> Expr<TextRange> {
+    Expr::Lambda(ExprLambda {
+        range: TextRange::default(),
+        args: Box::new(Arguments {
+            range: OptionalRange::default(),
+            posonlyargs: Vec::new(),
+            args: vec![ArgWithDefault {
+                range: OptionalRange::default(),
+                def: Arg {
+                    range: TextRange::default(),
+                    arg: Identifier::new(param),
+                    annotation: None,
+                    type_comment: None,
+                },
+                default: None,
+            }],
+            vararg: None,
+            kwonlyargs: Vec::new(),
+            kwarg: None,
+        }),
+        body: Box::new(body),
+    })
+}
+
+fn list(elts: Vec<Expr<TextRange>>) -> Expr<TextRange> {
+    Expr::List(ExprList {
+        range: TextRange::default(),
+        elts,
+        ctx: ExprContext::Load,
+    })
+}
+
+fn raise(exc: Option<Expr<TextRange>>) -> Stmt<TextRange> {
+    Stmt::Raise(StmtRaise {
+        range: TextRange::default(),
+        exc: exc.map(Box::new),
+        cause: None,
+    })
+}
+
+fn except_handler(
+    type_: Expr<TextRange>,
+    handler_name: &str,
+    body: Vec<Stmt<TextRange>>,
+) -> ExceptHandler<TextRange> {
+    ExceptHandler::ExceptHandler(ExceptHandlerExceptHandler {
+        range: TextRange::default(),
+        type_: Some(Box::new(type_)),
+        name: Some(Identifier::new(handler_name)),
+        body,
+    })
+}
+
+/// Replaces the bare `raise` statements of an `except*` clause, which would raise
+/// the caught exception in the lowered code, with `raise matching`.
+/// `raise` in nested `except` clauses and definitions refers to something else.
+fn reraise_explicitly(body: &mut [Stmt<TextRange>], matching: &str) {
+    for stmt in body {
+        match stmt {
+            Stmt::Raise(data) if data.exc.is_none() => {
+                data.exc = Some(Box::new(load(matching)));
+            }
+            Stmt::For(data) => {
+                reraise_explicitly(&mut data.body, matching);
+                reraise_explicitly(&mut data.orelse, matching);
+            }
+            Stmt::AsyncFor(data) => {
+                reraise_explicitly(&mut data.body, matching);
+                reraise_explicitly(&mut data.orelse, matching);
+            }
+            Stmt::While(data) => {
+                reraise_explicitly(&mut data.body, matching);
+                reraise_explicitly(&mut data.orelse, matching);
+            }
+            Stmt::If(data) => {
+                reraise_explicitly(&mut data.body, matching);
+                reraise_explicitly(&mut data.orelse, matching);
+            }
+            Stmt::With(data) => reraise_explicitly(&mut data.body, matching),
+            Stmt::AsyncWith(data) => reraise_explicitly(&mut data.body, matching),
+            Stmt::Match(data) => {
+                for case in &mut data.cases {
+                    reraise_explicitly(&mut case.body, matching);
+                }
+            }
+            Stmt::Try(data) => {
+                reraise_explicitly(&mut data.body, matching);
+                reraise_explicitly(&mut data.orelse, matching);
+                reraise_explicitly(&mut data.finalbody, matching);
+            }
+            Stmt::TryStar(data) => {
+                reraise_explicitly(&mut data.body, matching);
+                reraise_explicitly(&mut data.orelse, matching);
+                reraise_explicitly(&mut data.finalbody, matching);
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,134 @@
+//! PEP 484 type comments, converted into annotations and back.
+
+#[cfg(feature = "type-comments")]
+mod annotations;
+
+use rustpython_ast::{
+    text_size::TextRange, Arg, Arguments, Constant, Expr, Stmt, StmtAnnAssign, StmtAssign,
+};
+
+use super::{constant, for_each_body, load, Lowering};
+
+impl Lowering<'_> {
+    /// Replaces the annotations in `body` with type comments. Annotated assignments become
+    /// assignments with a type comment and annotated functions get a function type comment,
+    /// where parameters without an annotation are `Any`. Annotations without a value are kept.
+    pub(crate) fn annotations_to_type_comments(&mut self, body: &mut [Stmt<TextRange>]) {
+        self.typing_any = imported_name(body, "typing", "Any");
+        self.annotations_to_type_comments_in(body);
+    }
+
+    fn annotations_to_type_comments_in(&mut self, body: &mut [Stmt<TextRange>]) {
+        for stmt in body {
+            for_each_body(stmt, |nested_body| {
+                self.annotations_to_type_comments_in(nested_body)
+            });
+            match stmt {
+                Stmt::AnnAssign(StmtAnnAssign {
+                    range,
+                    target,
+                    annotation,
+                    value: Some(value),
+                    ..
+                }) => {
+                    *stmt = Stmt::Assign(StmtAssign {
+                        range: *range,
+                        targets: vec![(**target).clone()],
+                        value: value.clone(),
+                        type_comment: Some(self.unparse_type(annotation)),
+                    });
+                }
+                Stmt::FunctionDef(data) => {
+                    // an unannotated function keeps the type comment it may already have
+                    if let Some(type_comment) =
+                        self.function_type_comment(&data.name, &mut data.args, &mut data.returns)
+                    {
+                        data.type_comment = Some(type_comment);
+                    }
+                }
+                Stmt::AsyncFunctionDef(data) => {
+                    // an unannotated function keeps the type comment it may already have
+                    if let Some(type_comment) =
+                        self.function_type_comment(&data.name, &mut data.args, &mut data.returns)
+                    {
+                        data.type_comment = Some(type_comment);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Builds the type comment of a function from its annotations, which are removed,
+    /// `None` if it has none. An unannotated `self` or `cls` is left out.
+    fn function_type_comment(
+        &mut self,
+        function: &str,
+        args: &mut Arguments<TextRange>,
+        returns: &mut Option<Box<Expr<TextRange>>>,
+    ) -> Option<String> {
+        let mut params = parameters(args);
+        if params.iter().all(|(_, param)| param.annotation.is_none()) && returns.is_none() {
+            return None;
+        }
+        let skipped = params.first().is_some_and(|(_, param)| {
+            param.annotation.is_none() && matches!(param.arg.as_str(), "self" | "cls")
+        });
+        let mut argtypes = Vec::new();
+        for (prefix, param) in params.iter_mut().skip(usize::from(skipped)) {
+            let annotation = match param.annotation.take() {
+                Some(annotation) => *annotation,
+                None => self.any_type(),
+            };
+            argtypes.push(format!("{}{}", prefix, self.unparse_type(&annotation)));
+        }
+        let returns = match returns.take() {
+            Some(returns) => *returns,
+            None if function == "__init__" => constant(Constant::None),
+            None => self.any_type(),
+        };
+        Some(format!(
+            "({}) -> {}",
+            argtypes.join(", "),
+            self.unparse_type(&returns)
+        ))
+    }
+
+    /// Loads `typing.Any` with the module's own import of it if there is one,
+    /// otherwise imports it, under another name if `Any` is already taken.
+    fn any_type(&mut self) -> Expr<TextRange> {
+        match &self.typing_any {
+            Some(imported) => load(imported),
+            None => self.import_unshadowed("typing", "Any"),
+        }
+    }
+}
+
+/// The name `body` imports `name` from `module` as at its top level, if it does.
+fn imported_name(body: &[Stmt<TextRange>], module: &str, name: &str) -> Option<String> {
+    body.iter()
+        .filter_map(|stmt| stmt.as_import_from_stmt())
+        .filter(|import| {
+            import
+                .module
+                .as_ref()
+                .is_some_and(|imported| imported.as_str() == module)
+                && import.level.is_none_or(|level| level.to_u32() == 0)
+        })
+        .flat_map(|import| &import.names)
+        .find(|alias| alias.name.as_str() == name)
+        .map(|alias| alias.asname.as_ref().unwrap_or(&alias.name).to_string())
+}
+
+/// The parameters of a function in order, including `*args` and `**kwargs`,
+/// with the prefix their type has in a function type comment.
+fn parameters(args: &mut Arguments<TextRange>) -> Vec<(&'static str, &mut Arg<TextRange>)> {
+    args.posonlyargs
+        .iter_mut()
+        .chain(args.args.iter_mut())
+        .map(|param| ("", &mut param.def))
+        .chain(args.vararg.as_deref_mut().map(|param| ("*", param)))
+        .chain(args.kwonlyargs.iter_mut().map(|param| ("", &mut param.def)))
+        .chain(args.kwarg.as_deref_mut().map(|param| ("**", param)))
+        .collect()
+}
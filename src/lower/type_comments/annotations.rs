@@ -0,0 +1,312 @@
+//! PEP 484 type comments converted into annotations, which needs rustpython's parser.
+
+use rustpython_ast::{
+    text_size::{TextRange, TextSize},
+    Arguments, Constant, Expr, ExprConstant, ExprList, ExprTuple, ModFunctionType, Stmt,
+    StmtAnnAssign, WithItem,
+};
+use rustpython_parser::Parse;
+
+use super::parameters;
+use crate::error::{UnparseError, UnparseErrorKind};
+use crate::lower::{for_each_body, tuple, Lowering};
+
+impl Lowering<'_> {
+    /// Replaces the type comments in `body` with annotations. `x = []  # type: list[int]`
+    /// becomes `x: list[int] = []`, targets that can't be annotated where they're assigned
+    /// are declared before their statement and function type comments become
+    /// parameter and return annotations, keeping the annotations that already exist.
+    /// `# type: ignore` comments are kept.
+    pub(crate) fn type_comments_to_annotations(&mut self, body: &mut Vec<Stmt<TextRange>>) {
+        let stmts = std::mem::take(body);
+        for mut stmt in stmts {
+            for_each_body(&mut stmt, |nested_body| {
+                self.type_comments_to_annotations(nested_body)
+            });
+            body.extend(self.annotate_stmt(stmt));
+        }
+    }
+
+    /// Replaces the type comment of `node` with annotations, declaring the targets
+    /// that can't be annotated where they're assigned before it.
+    fn annotate_stmt(&mut self, node: Stmt<TextRange>) -> Vec<Stmt<TextRange>> {
+        match node {
+            Stmt::Assign(mut data) => {
+                let Some(annotation) = self.parse_type_comment(&data.type_comment, data.range)
+                else {
+                    return vec![Stmt::Assign(data)];
+                };
+                if let [target @ (Expr::Name(_) | Expr::Attribute(_) | Expr::Subscript(_))] =
+                    &data.targets[..]
+                {
+                    return vec![Stmt::AnnAssign(StmtAnnAssign {
+                        range: data.range,
+                        target: Box::new(target.clone()),
+                        annotation: Box::new(annotation),
+                        value: Some(data.value),
+                        simple: target.is_name_expr(),
+                    })];
+                }
+                let mut stmts = Vec::new();
+                for target in &data.targets {
+                    if !declare(target, annotation.clone(), &mut stmts) {
+                        self.invalid_type_comment(&data.type_comment, data.range);
+                        return vec![Stmt::Assign(data)];
+                    }
+                }
+                data.type_comment = None;
+                stmts.push(Stmt::Assign(data));
+                stmts
+            }
+            Stmt::For(mut data) => {
+                let Some(stmts) =
+                    self.declare_targets(&data.target, &data.type_comment, data.range)
+                else {
+                    return vec![Stmt::For(data)];
+                };
+                data.type_comment = None;
+                [stmts, vec![Stmt::For(data)]].concat()
+            }
+            Stmt::AsyncFor(mut data) => {
+                let Some(stmts) =
+                    self.declare_targets(&data.target, &data.type_comment, data.range)
+                else {
+                    return vec![Stmt::AsyncFor(data)];
+                };
+                data.type_comment = None;
+                [stmts, vec![Stmt::AsyncFor(data)]].concat()
+            }
+            Stmt::With(mut data) => {
+                let target = with_targets(&data.items);
+                let Some(stmts) = self.declare_targets(&target, &data.type_comment, data.range)
+                else {
+                    return vec![Stmt::With(data)];
+                };
+                data.type_comment = None;
+                [stmts, vec![Stmt::With(data)]].concat()
+            }
+            Stmt::AsyncWith(mut data) => {
+                let target = with_targets(&data.items);
+                let Some(stmts) = self.declare_targets(&target, &data.type_comment, data.range)
+                else {
+                    return vec![Stmt::AsyncWith(data)];
+                };
+                data.type_comment = None;
+                [stmts, vec![Stmt::AsyncWith(data)]].concat()
+            }
+            Stmt::FunctionDef(mut data) => {
+                self.annotate_function(
+                    &mut data.args,
+                    &mut data.returns,
+                    &mut data.type_comment,
+                    data.range,
+                );
+                vec![Stmt::FunctionDef(data)]
+            }
+            Stmt::AsyncFunctionDef(mut data) => {
+                self.annotate_function(
+                    &mut data.args,
+                    &mut data.returns,
+                    &mut data.type_comment,
+                    data.range,
+                );
+                vec![Stmt::AsyncFunctionDef(data)]
+            }
+            node => vec![node],
+        }
+    }
+
+    /// Declares `target` with the type of `type_comment` before a statement that assigns it,
+    /// `None` if the statement has no type comment to convert.
+    fn declare_targets(
+        &mut self,
+        target: &Expr<TextRange>,
+        type_comment: &Option<String>,
+        range: TextRange,
+    ) -> Option<Vec<Stmt<TextRange>>> {
+        let annotation = self.parse_type_comment(type_comment, range)?;
+        let mut stmts = Vec::new();
+        if declare(target, annotation, &mut stmts) {
+            Some(stmts)
+        } else {
+            self.invalid_type_comment(type_comment, range);
+            None
+        }
+    }
+
+    /// Replaces the type comments of a function and its parameters with annotations.
+    fn annotate_function(
+        &mut self,
+        args: &mut Arguments<TextRange>,
+        returns: &mut Option<Box<Expr<TextRange>>>,
+        type_comment: &mut Option<String>,
+        range: TextRange,
+    ) {
+        let mut params = parameters(args);
+        for (_, param) in &mut params {
+            if let Some(annotation) = self.parse_type_comment(&param.type_comment, param.range) {
+                param.annotation.get_or_insert(Box::new(annotation));
+                param.type_comment = None;
+            }
+        }
+        let Some(comment) = type_comment
+            .as_deref()
+            .filter(|comment| !is_ignore(comment))
+        else {
+            return;
+        };
+        let Some(function_type) = parse_function_type(comment, range.start()) else {
+            self.invalid_type_comment(type_comment, range);
+            return;
+        };
+        let argtypes = function_type.argtypes;
+        // `(...) -> R` only annotates the return type.
+        let is_ellipsis = matches!(
+            &argtypes[..],
+            [Expr::Constant(ExprConstant {
+                value: Constant::Ellipsis,
+                ..
+            })]
+        );
+        if !is_ellipsis {
+            // The type of the first parameter of a method can be left out.
+            let skipped = match params.len().checked_sub(argtypes.len()) {
+                Some(skipped @ (0 | 1)) => skipped,
+                _ => {
+                    self.invalid_type_comment(type_comment, range);
+                    return;
+                }
+            };
+            for ((_, param), annotation) in params.into_iter().skip(skipped).zip(argtypes) {
+                param.annotation.get_or_insert(Box::new(annotation));
+            }
+        }
+        returns.get_or_insert(function_type.returns);
+        *type_comment = None;
+    }
+
+    /// Parses `type_comment`, `None` if there is none, it's a `# type: ignore` comment
+    /// or it isn't a valid expression, which is reported.
+    fn parse_type_comment(
+        &mut self,
+        type_comment: &Option<String>,
+        range: TextRange,
+    ) -> Option<Expr<TextRange>> {
+        let comment = type_comment
+            .as_deref()
+            .filter(|comment| !is_ignore(comment))?;
+        let annotation =
+            Expr::parse_starts_at(comment.trim(), "<type_comment>", range.start()).ok();
+        if annotation.is_none() {
+            self.invalid_type_comment(type_comment, range);
+        }
+        annotation
+    }
+
+    fn invalid_type_comment(&mut self, type_comment: &Option<String>, range: TextRange) {
+        self.errors.push(UnparseError {
+            kind: UnparseErrorKind::InvalidTypeComment(type_comment.clone().unwrap_or_default()),
+            range,
+            location: None,
+        });
+    }
+}
+
+/// Whether a type comment is `# type: ignore`, optionally followed by error codes.
+fn is_ignore(type_comment: &str) -> bool {
+    type_comment.trim_start().starts_with("ignore")
+}
+
+/// The targets a `with` statement's type comment annotates, a tuple if there's more than one item.
+fn with_targets(items: &[WithItem<TextRange>]) -> Expr<TextRange> {
+    let mut targets: Vec<_> = items
+        .iter()
+        .filter_map(|item| item.optional_vars.as_deref().cloned())
+        .collect();
+    if targets.len() == 1 {
+        targets.remove(0)
+    } else {
+        tuple(targets)
+    }
+}
+
+/// Appends declarations of `target` with the type `annotation` to `stmts`, matching
+/// tuple targets with tuple types element by element.
+/// Returns whether `annotation` fits the target.
+fn declare(
+    target: &Expr<TextRange>,
+    annotation: Expr<TextRange>,
+    stmts: &mut Vec<Stmt<TextRange>>,
+) -> bool {
+    match target {
+        Expr::Name(_) | Expr::Attribute(_) | Expr::Subscript(_) => {
+            stmts.push(Stmt::AnnAssign(StmtAnnAssign {
+                range: TextRange::default(),
+                target: Box::new(target.clone()),
+                annotation: Box::new(annotation),
+                value: None,
+                simple: target.is_name_expr(),
+            }));
+            true
+        }
+        Expr::Starred(data) => declare(&data.value, annotation, stmts),
+        Expr::Tuple(ExprTuple { elts, .. }) | Expr::List(ExprList { elts, .. }) => match annotation
+        {
+            Expr::Tuple(annotation) if annotation.elts.len() == elts.len() => elts
+                .iter()
+                .zip(annotation.elts)
+                .all(|(target, annotation)| declare(target, annotation, stmts)),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Parses a function type comment like `(int, *str) -> bool`, which rustpython's parser
+/// has no mode for. `*` and `**` before parameter types are left out like CPython does.
+fn parse_function_type(type_comment: &str, offset: TextSize) -> Option<ModFunctionType<TextRange>> {
+    let [argtypes, returns] = split_top_level(type_comment, "->")[..] else {
+        return None;
+    };
+    let argtypes = argtypes.trim().strip_prefix('(')?.strip_suffix(')')?;
+    let argtypes = split_top_level(argtypes, ",")
+        .into_iter()
+        .map(str::trim)
+        .filter(|argtype| !argtype.is_empty())
+        .map(|argtype| {
+            let argtype = argtype.trim_start_matches('*');
+            Expr::parse_starts_at(argtype, "<type_comment>", offset).ok()
+        })
+        .collect::<Option<_>>()?;
+    let returns = Expr::parse_starts_at(returns.trim(), "<type_comment>", offset).ok()?;
+    Some(ModFunctionType {
+        range: TextRange::default().into(),
+        argtypes,
+        returns: Box::new(returns),
+    })
+}
+
+/// Splits `text` at every `separator` that isn't inside brackets or a string.
+fn split_top_level<'a>(text: &'a str, separator: &str) -> Vec<&'a str> {
+    let mut parts = Vec::new();
+    let mut depth = 0usize;
+    let mut quote = None;
+    let mut start = 0;
+    for (index, char) in text.char_indices() {
+        match (quote, char) {
+            (Some(open), char) if char == open => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(char),
+            (None, '(' | '[' | '{') => depth += 1,
+            (None, ')' | ']' | '}') => depth = depth.saturating_sub(1),
+            (None, _) => {
+                if depth == 0 && index >= start && text[index..].starts_with(separator) {
+                    parts.push(&text[start..index]);
+                    start = index + separator.len();
+                }
+            }
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
@@ -0,0 +1,491 @@
+//! PEP 695 type aliases and type parameters, lowered for Python 3.11 and older,
+//! and PEP 646 unpacking in annotations, lowered for Python 3.10 and older.
+
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+
+use rustpython_ast::{
+    fold, text_size::TextRange, Arguments, Constant, Expr, ExprContext, ExprName, ExprStarred,
+    Fold, Identifier, Ranged, Stmt, StmtAnnAssign, TypeParam,
+};
+
+use super::{
+    assign, bound_names, call, constant, for_each_body, load, name, str_constant, subscript, tuple,
+    Lowering,
+};
+use crate::options::{PythonVersion, TypeAliasLowering};
+use crate::unparser::Unparser;
+
+impl Lowering<'_> {
+    /// Renames type parameters whose declaration before their definition would overwrite
+    /// another binding, e.g. a module constant `T = 5` or a type parameter `T` with another
+    /// bound, together with their uses.
+    pub(crate) fn rename_type_params(&mut self, body: &mut [Stmt<TextRange>]) {
+        let mut renaming = TypeParamRenaming {
+            bound_names: bound_names(body),
+            declarations: HashMap::new(),
+        };
+        self.rename_type_params_in(body, &mut renaming);
+    }
+
+    fn rename_type_params_in(
+        &mut self,
+        body: &mut [Stmt<TextRange>],
+        renaming: &mut TypeParamRenaming,
+    ) {
+        for stmt in body {
+            let type_params = match stmt {
+                Stmt::FunctionDef(data) => &mut data.type_params,
+                Stmt::AsyncFunctionDef(data) => &mut data.type_params,
+                Stmt::ClassDef(data) => &mut data.type_params,
+                Stmt::TypeAlias(data) => &mut data.type_params,
+                _ => &mut Vec::new(),
+            };
+            let mut renamers = Vec::new();
+            for type_param in type_params {
+                let param_name = type_param_name(type_param).to_string();
+                let declaration = declaration_key(type_param);
+                let is_free = !renaming.bound_names.contains(&param_name)
+                    && renaming
+                        .declarations
+                        .get(&param_name)
+                        .is_none_or(|previous| *previous == declaration);
+                let new_name = if is_free {
+                    param_name.clone()
+                } else {
+                    let new_name = self.fresh_name(&param_name);
+                    set_type_param_name(type_param, &new_name);
+                    renamers.push(TypeParamRenamer {
+                        from: param_name,
+                        to: new_name.clone(),
+                    });
+                    new_name
+                };
+                renaming.declarations.insert(new_name, declaration);
+            }
+            for renamer in &mut renamers {
+                renamer.rename_scope(stmt);
+            }
+            for_each_body(stmt, |nested_body| {
+                self.rename_type_params_in(nested_body, renaming)
+            });
+        }
+    }
+
+    /// Declares the type parameters of generic functions, classes and type aliases
+    /// before them and makes generic classes inherit from `Generic`.
+    pub(crate) fn lower_type_params(&mut self, node: Stmt<TextRange>) -> Vec<Stmt<TextRange>> {
+        match node {
+            Stmt::FunctionDef(mut data) => {
+                let mut stmts = self.declare_type_params(&mut data.type_params);
+                stmts.push(Stmt::FunctionDef(data));
+                stmts
+            }
+            Stmt::AsyncFunctionDef(mut data) => {
+                let mut stmts = self.declare_type_params(&mut data.type_params);
+                stmts.push(Stmt::AsyncFunctionDef(data));
+                stmts
+            }
+            Stmt::ClassDef(mut data) => {
+                if data.type_params.is_empty() {
+                    return vec![Stmt::ClassDef(data)];
+                }
+                let params = data
+                    .type_params
+                    .iter()
+                    .map(|type_param| self.generic_argument(type_param))
+                    .collect();
+                let generic = self.typing("Generic", PythonVersion::PY37);
+                data.bases.push(subscript(generic, params));
+                let mut stmts = self.declare_type_params(&mut data.type_params);
+                stmts.push(Stmt::ClassDef(data));
+                stmts
+            }
+            Stmt::TypeAlias(mut data) => {
+                let params: Vec<_> = data
+                    .type_params
+                    .iter()
+                    .map(|type_param| load(type_param_name(type_param)))
+                    .collect();
+                let mut stmts = self.declare_type_params(&mut data.type_params);
+                let Expr::Name(alias_name) = *data.name else {
+                    stmts.push(Stmt::TypeAlias(data));
+                    return stmts;
+                };
+                // the value of a `type` statement is only evaluated when it's used,
+                // so it can refer to the alias itself and to names defined after it
+                let value = str_constant(&self.unparse_type(&data.value));
+                let alias = match self.options.type_alias_lowering {
+                    TypeAliasLowering::TypeAlias => Stmt::AnnAssign(StmtAnnAssign {
+                        range: data.range,
+                        target: Box::new(name(alias_name.id.as_str(), ExprContext::Store)),
+                        annotation: Box::new(self.typing("TypeAlias", PythonVersion::PY310)),
+                        value: Some(Box::new(value)),
+                        simple: true,
+                    }),
+                    TypeAliasLowering::TypeAliasType => {
+                        let type_alias_type = self.typing("TypeAliasType", PythonVersion::PY312);
+                        let mut keywords = Vec::new();
+                        if !params.is_empty() {
+                            keywords.push(("type_params", tuple(params)));
+                        }
+                        let value = call(
+                            type_alias_type,
+                            vec![str_constant(alias_name.id.as_str()), value],
+                            keywords,
+                        );
+                        assign(alias_name.id.as_str(), value, data.range)
+                    }
+                };
+                stmts.push(alias);
+                stmts
+            }
+            node => vec![node],
+        }
+    }
+
+    /// Takes `type_params` and returns the statements that declare them,
+    /// e.g. `T = TypeVar("T", bound="int", infer_variance=True)`.
+    fn declare_type_params(
+        &mut self,
+        type_params: &mut Vec<TypeParam<TextRange>>,
+    ) -> Vec<Stmt<TextRange>> {
+        std::mem::take(type_params)
+            .into_iter()
+            .map(|type_param| {
+                let range = type_param.range();
+                let param_name = type_param_name(&type_param).to_string();
+                let mut args = vec![str_constant(&param_name)];
+                let mut keywords = Vec::new();
+                let constructor = match type_param {
+                    TypeParam::TypeVar(data) => {
+                        // bounds are evaluated lazily, so they can refer to names defined later
+                        match data.bound.map(|bound| *bound) {
+                            // `T: (int, str)` constrains `T` to one of the types
+                            Some(Expr::Tuple(constraints)) => args.extend(
+                                constraints
+                                    .elts
+                                    .into_iter()
+                                    .map(|constraint| self.forward_reference(constraint)),
+                            ),
+                            Some(bound) => keywords.push(("bound", self.forward_reference(bound))),
+                            None => {}
+                        }
+                        // the variance of type parameters is inferred,
+                        // which `TypeVar` only supports since Python 3.12
+                        keywords.push(("infer_variance", constant(Constant::Bool(true))));
+                        self.typing("TypeVar", PythonVersion::PY312)
+                    }
+                    TypeParam::ParamSpec(_) => self.typing("ParamSpec", PythonVersion::PY310),
+                    TypeParam::TypeVarTuple(_) => self.typing("TypeVarTuple", PythonVersion::PY311),
+                };
+                assign(&param_name, call(constructor, args, keywords), range)
+            })
+            .collect()
+    }
+
+    /// `node` as a string, which `TypeVar` evaluates only when type hints are resolved.
+    fn forward_reference(&self, node: Expr<TextRange>) -> Expr<TextRange> {
+        match &node {
+            Expr::Constant(data) if data.value.is_str() => node,
+            _ => str_constant(&self.unparse_type(&node)),
+        }
+    }
+
+    /// How `type_param` is passed to `Generic`, `*Ts` or `Unpack[Ts]` for a `TypeVarTuple`.
+    fn generic_argument(&mut self, type_param: &TypeParam<TextRange>) -> Expr<TextRange> {
+        let param = load(type_param_name(type_param));
+        if !type_param.is_type_var_tuple() {
+            return param;
+        }
+        if !self.targets_before(PythonVersion::PY311) {
+            Expr::Starred(ExprStarred {
+                range: TextRange::default(),
+                value: Box::new(param),
+                ctx: ExprContext::Load,
+            })
+        } else {
+            self.unpack(param)
+        }
+    }
+
+    fn unpack(&mut self, node: Expr<TextRange>) -> Expr<TextRange> {
+        let unpack = self.typing("Unpack", PythonVersion::PY311);
+        subscript(unpack, vec![node])
+    }
+
+    /// Rewrites the unpacked `TypeVarTuple`s in the annotations of `node`, e.g. `*args: *Ts`
+    /// and `tuple[int, *Ts]`, into `Unpack[Ts]`.
+    pub(crate) fn lower_unpacks(&mut self, node: Stmt<TextRange>) -> Stmt<TextRange> {
+        let mut folder = UnpackFolder { lowering: self };
+        match node {
+            Stmt::FunctionDef(mut data) => {
+                folder.fold_signature(&mut data.args, &mut data.returns);
+                Stmt::FunctionDef(data)
+            }
+            Stmt::AsyncFunctionDef(mut data) => {
+                folder.fold_signature(&mut data.args, &mut data.returns);
+                Stmt::AsyncFunctionDef(data)
+            }
+            Stmt::ClassDef(mut data) => {
+                let Ok(bases) = data
+                    .bases
+                    .into_iter()
+                    .map(|base| folder.fold_expr(base))
+                    .collect();
+                data.bases = bases;
+                Stmt::ClassDef(data)
+            }
+            Stmt::AnnAssign(mut data) => {
+                let Ok(annotation) = folder.fold_expr(*data.annotation);
+                data.annotation = Box::new(annotation);
+                Stmt::AnnAssign(data)
+            }
+            Stmt::TypeAlias(mut data) => {
+                let Ok(value) = folder.fold_expr(*data.value);
+                data.value = Box::new(value);
+                Stmt::TypeAlias(data)
+            }
+            node => node,
+        }
+    }
+}
+
+/// The names a module binds and the type parameters declared so far.
+struct TypeParamRenaming {
+    bound_names: HashSet<String>,
+    /// How each declared type parameter is declared, see [`declaration_key`].
+    declarations: HashMap<String, String>,
+}
+
+/// What a type parameter is declared as apart from its name, so that type parameters
+/// with the same name and declaration can share it.
+fn declaration_key(type_param: &TypeParam<TextRange>) -> String {
+    let mut unparser = Unparser::new();
+    let kind = match type_param {
+        TypeParam::TypeVar(data) => {
+            if let Some(bound) = &data.bound {
+                unparser.unparse_expr(bound);
+            }
+            "TypeVar"
+        }
+        TypeParam::ParamSpec(_) => "ParamSpec",
+        TypeParam::TypeVarTuple(_) => "TypeVarTuple",
+    };
+    format!("{}({})", kind, unparser.source)
+}
+
+/// Renames a type parameter in the scope of the definition that declares it,
+/// except in nested definitions that declare a type parameter with the same name.
+struct TypeParamRenamer {
+    from: String,
+    to: String,
+}
+
+impl TypeParamRenamer {
+    /// Renames the uses in `node`, which isn't folded as a whole
+    /// since its decorators aren't in the scope of its type parameters.
+    fn rename_scope(&mut self, node: &mut Stmt<TextRange>) {
+        match node {
+            Stmt::FunctionDef(data) => {
+                self.rename_signature(&mut data.args, &mut data.returns);
+                self.rename_body(&mut data.body);
+            }
+            Stmt::AsyncFunctionDef(data) => {
+                self.rename_signature(&mut data.args, &mut data.returns);
+                self.rename_body(&mut data.body);
+            }
+            Stmt::ClassDef(data) => {
+                let Ok(bases) = std::mem::take(&mut data.bases)
+                    .into_iter()
+                    .map(|base| self.fold_expr(base))
+                    .collect();
+                data.bases = bases;
+                for keyword in &mut data.keywords {
+                    let Ok(renamed) = self.fold_expr(keyword.value.clone());
+                    keyword.value = renamed;
+                }
+                self.rename_body(&mut data.body);
+            }
+            Stmt::TypeAlias(data) => {
+                let Ok(renamed) = self.fold_expr(data.value.as_ref().clone());
+                *data.value = renamed;
+            }
+            _ => {}
+        }
+    }
+
+    fn rename_signature(
+        &mut self,
+        args: &mut Box<Arguments<TextRange>>,
+        returns: &mut Option<Box<Expr<TextRange>>>,
+    ) {
+        let Ok(renamed) = self.fold_arguments(args.as_ref().clone());
+        **args = renamed;
+        if let Some(returns) = returns {
+            let Ok(renamed) = self.fold_expr(returns.as_ref().clone());
+            **returns = renamed;
+        }
+    }
+
+    fn rename_body(&mut self, body: &mut Vec<Stmt<TextRange>>) {
+        let Ok(renamed) = std::mem::take(body)
+            .into_iter()
+            .map(|stmt| self.fold_stmt(stmt))
+            .collect();
+        *body = renamed;
+    }
+
+    fn declares(&self, type_params: &[TypeParam<TextRange>]) -> bool {
+        type_params
+            .iter()
+            .any(|type_param| type_param_name(type_param) == self.from)
+    }
+}
+
+impl Fold<TextRange> for TypeParamRenamer {
+    type TargetU = TextRange;
+    type Error = Infallible;
+    type UserContext = ();
+
+    fn will_map_user(&mut self, _user: &TextRange) -> Self::UserContext {}
+
+    fn map_user(
+        &mut self,
+        user: TextRange,
+        _context: Self::UserContext,
+    ) -> Result<Self::TargetU, Self::Error> {
+        Ok(user)
+    }
+
+    fn fold_stmt(&mut self, node: Stmt<TextRange>) -> Result<Stmt<TextRange>, Infallible> {
+        // a nested definition with a type parameter of the same name refers to that,
+        // but its decorators still refer to the renamed one
+        let shadows = match &node {
+            Stmt::FunctionDef(data) => self.declares(&data.type_params),
+            Stmt::AsyncFunctionDef(data) => self.declares(&data.type_params),
+            Stmt::ClassDef(data) => self.declares(&data.type_params),
+            Stmt::TypeAlias(data) => self.declares(&data.type_params),
+            _ => false,
+        };
+        if !shadows {
+            return fold::fold_stmt(self, node);
+        }
+        let mut node = node;
+        let decorator_list = match &mut node {
+            Stmt::FunctionDef(data) => &mut data.decorator_list,
+            Stmt::AsyncFunctionDef(data) => &mut data.decorator_list,
+            Stmt::ClassDef(data) => &mut data.decorator_list,
+            _ => return Ok(node),
+        };
+        let Ok(renamed) = std::mem::take(decorator_list)
+            .into_iter()
+            .map(|decorator| self.fold_expr(decorator))
+            .collect();
+        *decorator_list = renamed;
+        Ok(node)
+    }
+
+    fn fold_expr_name(
+        &mut self,
+        node: ExprName<TextRange>,
+    ) -> Result<ExprName<TextRange>, Infallible> {
+        if node.id.as_str() == self.from {
+            return Ok(ExprName {
+                id: Identifier::new(self.to.clone()),
+                ..node
+            });
+        }
+        Ok(node)
+    }
+}
+
+/// Rewrites unpacked expressions in subscripts into `Unpack[...]`.
+struct UnpackFolder<'l, 'o> {
+    lowering: &'l mut Lowering<'o>,
+}
+
+impl UnpackFolder<'_, '_> {
+    fn fold_signature(
+        &mut self,
+        args: &mut Arguments<TextRange>,
+        returns: &mut Option<Box<Expr<TextRange>>>,
+    ) {
+        if let Some(vararg) = &mut args.vararg {
+            if let Some(Expr::Starred(starred)) = vararg.annotation.as_deref() {
+                let unpacked = self.lowering.unpack(*starred.value.clone());
+                vararg.annotation = Some(Box::new(unpacked));
+            }
+        }
+        let Ok(renamed) = self.fold_arguments(args.clone());
+        *args = renamed;
+        if let Some(returns) = returns {
+            let Ok(renamed) = self.fold_expr(returns.as_ref().clone());
+            **returns = renamed;
+        }
+    }
+}
+
+impl Fold<TextRange> for UnpackFolder<'_, '_> {
+    type TargetU = TextRange;
+    type Error = Infallible;
+    type UserContext = ();
+
+    fn will_map_user(&mut self, _user: &TextRange) -> Self::UserContext {}
+
+    fn map_user(
+        &mut self,
+        user: TextRange,
+        _context: Self::UserContext,
+    ) -> Result<Self::TargetU, Self::Error> {
+        Ok(user)
+    }
+
+    fn fold_expr(&mut self, node: Expr<TextRange>) -> Result<Expr<TextRange>, Infallible> {
+        let mut node = fold::fold_expr(self, node)?;
+        if let Expr::Subscript(data) = &mut node {
+            // type checkers only understand `*Ts` in subscripts since PEP 646,
+            // even where it's valid syntax in parentheses
+            let has_starred = match data.slice.as_ref() {
+                Expr::Starred(_) => true,
+                Expr::Tuple(tuple) => tuple.elts.iter().any(|elt| elt.is_starred_expr()),
+                _ => false,
+            };
+            if has_starred {
+                *data.slice = match data.slice.as_ref().clone() {
+                    Expr::Starred(starred) => self.lowering.unpack(*starred.value),
+                    Expr::Tuple(mut tuple) => {
+                        tuple.elts = tuple
+                            .elts
+                            .into_iter()
+                            .map(|elt| match elt {
+                                Expr::Starred(starred) => self.lowering.unpack(*starred.value),
+                                elt => elt,
+                            })
+                            .collect();
+                        Expr::Tuple(tuple)
+                    }
+                    slice => slice,
+                };
+            }
+        }
+        Ok(node)
+    }
+}
+
+fn type_param_name(type_param: &TypeParam<TextRange>) -> &str {
+    match type_param {
+        TypeParam::TypeVar(data) => data.name.as_str(),
+        TypeParam::ParamSpec(data) => data.name.as_str(),
+        TypeParam::TypeVarTuple(data) => data.name.as_str(),
+    }
+}
+
+fn set_type_param_name(type_param: &mut TypeParam<TextRange>, new_name: &str) {
+    let param_name = match type_param {
+        TypeParam::TypeVar(data) => &mut data.name,
+        TypeParam::ParamSpec(data) => &mut data.name,
+        TypeParam::TypeVarTuple(data) => &mut data.name,
+    };
+    *param_name = Identifier::new(new_name);
+}
@@ -0,0 +1,273 @@
+use std::fmt;
+
+/// A Python version like `3.12`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PythonVersion {
+    pub major: u8,
+    pub minor: u8,
+}
+
+impl PythonVersion {
+    pub const PY37: PythonVersion = PythonVersion::new(3, 7);
+    pub const PY38: PythonVersion = PythonVersion::new(3, 8);
+    pub const PY39: PythonVersion = PythonVersion::new(3, 9);
+    pub const PY310: PythonVersion = PythonVersion::new(3, 10);
+    pub const PY311: PythonVersion = PythonVersion::new(3, 11);
+    pub const PY312: PythonVersion = PythonVersion::new(3, 12);
+    pub const PY313: PythonVersion = PythonVersion::new(3, 13);
+
+    pub const fn new(major: u8, minor: u8) -> Self {
+        PythonVersion { major, minor }
+    }
+}
+
+impl fmt::Display for PythonVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// Controls when tuples are wrapped in parentheses.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TupleParens {
+    /// Only emit parentheses where the grammar requires them,
+    /// e.g. `a, b = b, a`, `for k, v in items` or `x[1, 2]`.
+    #[default]
+    Minimal,
+    /// Always wrap non-empty tuples in parentheses,
+    /// except inside subscripts that contain slices where they would be invalid.
+    Always,
+}
+
+/// Controls which quote character string and bytes literals are written with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QuoteStyle {
+    Single,
+    Double,
+    /// Use whichever quote character needs fewer escapes.
+    /// Ties go to single quotes, or to double quotes for triple-quoted strings.
+    #[default]
+    FewestEscapes,
+}
+
+/// The radix `int` constants are written in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IntRadix {
+    #[default]
+    Decimal,
+    /// E.g. `0xff`.
+    Hexadecimal,
+    /// E.g. `0o755`.
+    Octal,
+    /// E.g. `0b1010`.
+    Binary,
+}
+
+/// What PEP 695 `type` statements are rewritten to for Python 3.11 and older.
+/// The value is written as a string, since it's only evaluated when the alias is used.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TypeAliasLowering {
+    /// `X: TypeAlias = 'int'`
+    #[default]
+    TypeAlias,
+    /// `X = TypeAliasType("X", 'int')`, which keeps the alias introspectable at runtime.
+    TypeAliasType,
+}
+
+/// What positional-only parameters are turned into when downleveling for Python 3.7.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PositionalOnlyLowering {
+    /// `def f(__a, b)`, the convention PEP 484 uses for positional-only parameters.
+    /// Callers can still pass `__a` by keyword.
+    #[default]
+    DunderPrefix,
+    /// `def f(*args, b)`, assigning `a` from `args` at the start of the function.
+    /// Only for functions without other positional parameters.
+    ArgsShim,
+}
+
+/// How PEP 484 type comments and annotations are converted into each other when downleveling.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TypeCommentConversion {
+    #[default]
+    Keep,
+    /// `x = []  # type: list[int]` becomes `x: list[int] = []`, `for x in xs:  # type: int`
+    /// declares `x: int` before the loop and function type comments become parameter
+    /// and return annotations.
+    ///
+    /// rustpython-parser 0.4 doesn't fill in `type_comment`, so this only converts the type
+    /// comments of ASTs built or filled in some other way. Needs the `type-comments` feature,
+    /// which parses the type comments with rustpython-parser.
+    #[cfg(feature = "type-comments")]
+    ToAnnotations,
+    /// `x: list[int] = []` becomes `x = []  # type: list[int]` and annotated functions
+    /// get a function type comment instead, for type checkers and Python versions
+    /// that predate variable annotations. Functions without annotations keep their type
+    /// comments.
+    ///
+    /// Annotations without a value like `x: int` have no type comment spelling and are
+    /// kept, so the output still needs Python 3.6 if the AST has any.
+    ToComments,
+}
+
+/// What f-strings are rewritten to when downleveling,
+/// for interpreters that predate them (Python 3.5 and older).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FStringLowering {
+    /// Keep f-strings.
+    #[default]
+    Keep,
+    /// `'{!r:>{}}'.format(x, width)`
+    Format,
+    /// `'%s and %r' % (a, b)` if every replacement field has a `!s`, `!r` or `!a` conversion
+    /// and no format spec, `str.format` calls otherwise.
+    PercentFormat,
+}
+
+/// What to do with identifiers that contain non-ASCII characters in ASCII-only output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NonAsciiIdentifiers {
+    /// Record an [`UnparseError`](crate::UnparseError).
+    #[default]
+    Error,
+    /// Replace every non-ASCII character with its code point, e.g. `café` becomes `caf_u00e9_`.
+    /// Note that this renames the identifier, so every use of it has to be unparsed the same way.
+    Mangle,
+}
+
+/// A coarse classification of statements for deciding on blank lines.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StmtKind {
+    /// A function or async function definition, including its decorators.
+    FunctionDef,
+    /// A class definition, including its decorators.
+    ClassDef,
+    /// An `import` or `from ... import` statement.
+    Import,
+    Other,
+}
+
+/// Describes the position between two statements of the same block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlankLinesContext {
+    /// The statement before the gap, `None` for the first statement of a block.
+    pub previous: Option<StmtKind>,
+    /// The statement after the gap.
+    pub next: StmtKind,
+    /// How deeply the statements are indented, `0` at module level.
+    pub depth: usize,
+    /// Whether the statements are directly inside a class body.
+    pub in_class: bool,
+}
+
+/// Controls how many blank lines are written between statements.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum BlankLines {
+    /// Never write blank lines.
+    #[default]
+    None,
+    /// Follow PEP 8, see [`pep8_blank_lines`].
+    Pep8,
+    /// Ask a custom function for the number of blank lines before each statement.
+    Custom(fn(&BlankLinesContext) -> usize),
+}
+
+impl BlankLines {
+    pub(crate) fn before(&self, context: &BlankLinesContext) -> usize {
+        match self {
+            BlankLines::None => 0,
+            BlankLines::Pep8 => pep8_blank_lines(context),
+            BlankLines::Custom(policy) => policy(context),
+        }
+    }
+}
+
+/// The PEP 8 layout: two blank lines around top-level functions and classes,
+/// one around nested ones and methods and one after a block of imports.
+/// Decorators are part of their definition, so they are never separated from it.
+pub fn pep8_blank_lines(context: &BlankLinesContext) -> usize {
+    let Some(previous) = context.previous else {
+        return 0;
+    };
+    let is_definition = |kind| matches!(kind, StmtKind::FunctionDef | StmtKind::ClassDef);
+    if is_definition(previous) || is_definition(context.next) {
+        if context.depth == 0 {
+            2
+        } else {
+            1
+        }
+    } else if previous == StmtKind::Import && context.next != StmtKind::Import {
+        1
+    } else {
+        0
+    }
+}
+
+/// Options that control how the [`Unparser`](crate::Unparser) formats its output.
+#[derive(Clone, Debug, Default)]
+pub struct UnparserOptions {
+    pub tuple_parens: TupleParens,
+    /// Wrap every compound expression in parentheses instead of only where precedence
+    /// requires them, e.g. `a + b * c` becomes `(a + (b * c))`.
+    /// Useful to make the structure of an AST visible when debugging.
+    pub fully_parenthesized: bool,
+    pub quote_style: QuoteStyle,
+    /// Write strings that contain line breaks as triple-quoted literals with real line breaks.
+    pub triple_quote_multiline: bool,
+    /// Write strings that contain backslashes as raw literals,
+    /// as long as no other character in them has to be escaped.
+    pub raw_strings: bool,
+    /// Re-indent docstrings to the block they are in and strip surrounding blank lines
+    /// as described in PEP 257. This changes the string value, but not its `inspect.cleandoc` form.
+    pub normalize_docstrings: bool,
+    /// Escape every non-ASCII character in string, bytes and f-string literals
+    /// so the output is pure ASCII.
+    pub ascii_only: bool,
+    /// How identifiers that can't be written in ASCII are handled when `ascii_only` is set.
+    pub non_ascii_identifiers: NonAsciiIdentifiers,
+    pub blank_lines: BlankLines,
+    /// Keep up to this many of the blank lines the original source had between two statements,
+    /// in addition to those required by `blank_lines`.
+    /// Only has an effect when the original source is given with
+    /// [`Unparser::with_original_source`](crate::Unparser::with_original_source).
+    pub keep_blank_lines: usize,
+    /// The radix of `int` constants that aren't written like in the original source.
+    pub int_radix: IntRadix,
+    /// Separate groups of this many digits in `int` constants with underscores,
+    /// e.g. `Some(3)` writes `1_000_000`.
+    pub int_digit_grouping: Option<usize>,
+    /// Split string, bytes and f-string literals that would exceed this line length into
+    /// parenthesised implicit concatenations, breaking after whitespace.
+    /// `with` statements that would exceed it are written with one item per line
+    /// in parentheses since Python 3.10, and as nested `with` statements otherwise.
+    pub max_line_length: Option<usize>,
+    /// The Python version the output has to run on. Without one, the output is written
+    /// to be compatible with as many versions as possible.
+    /// Syntax the target version can't parse, like a `match` statement for Python 3.9,
+    /// is reported as an [`UnparseErrorKind::UnsupportedFeature`](crate::UnparseErrorKind::UnsupportedFeature) error.
+    /// Since Python 3.12 f-strings are written like PEP 701 allows, e.g. reusing their quotes
+    /// inside replacement fields.
+    pub target_version: Option<PythonVersion>,
+    /// Rewrite syntax the target version can't parse into equivalent code for it
+    /// when unparsing a module with [`Unparser::unparse_suite`](crate::Unparser::unparse_suite),
+    /// see [`lower`](crate::lower()).
+    pub downlevel: bool,
+    /// What `type` statements are rewritten to when downleveling for Python 3.11 and older.
+    pub type_alias_lowering: TypeAliasLowering,
+    /// What positional-only parameters are turned into when downleveling for Python 3.7.
+    /// Functions that can't be rewritten that way are rewritten the other way if possible.
+    pub positional_only_lowering: PositionalOnlyLowering,
+    /// What f-strings are rewritten to when downleveling. Unlike the other rewrites,
+    /// this doesn't depend on the target version.
+    pub fstring_lowering: FStringLowering,
+    /// How type comments and annotations are converted into each other when downleveling.
+    /// Like `fstring_lowering`, this doesn't depend on the target version.
+    /// Note that type comments are only part of ASTs whose parser keeps them.
+    pub type_comments: TypeCommentConversion,
+    /// Write a stub (`.pyi`) of a module when unparsing it with
+    /// [`Unparser::unparse_suite`](crate::Unparser::unparse_suite), see [`stub`](crate::stub()).
+    /// Bodies that are just `...` are written on the line of their header.
+    pub stub: bool,
+    /// Keep names starting with an underscore in stubs. Without `__all__`, they're dropped.
+    pub stub_keep_private: bool,
+}
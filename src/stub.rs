@@ -0,0 +1,452 @@
+//! Turns a module into a stub (`.pyi`) that only keeps its interface.
+
+use std::collections::HashSet;
+use std::convert::Infallible;
+
+use rustpython_ast::{
+    text_size::TextRange, Arguments, Constant, ExceptHandler, Expr, ExprConstant, ExprContext,
+    ExprName, Fold, Identifier, Operator, Stmt, StmtAnnAssign, StmtExpr, UnaryOp,
+};
+
+use crate::lower::{constant, load};
+use crate::options::UnparserOptions;
+
+/// Functions whose result is a type variable. Their declarations are kept whatever
+/// their name, since signatures that use them need them.
+const TYPE_VARIABLE_FACTORIES: &[&str] = &["TypeVar", "ParamSpec", "TypeVarTuple"];
+
+/// Base classes of enums, whose members are kept with their values.
+const ENUM_BASES: &[&str] = &["Enum", "IntEnum", "StrEnum", "Flag", "IntFlag"];
+
+/// Rewrites the statements of a module into a stub of it. Imports, class and function
+/// signatures with their decorators, `type` aliases and annotated assignments are kept,
+/// function bodies and default values become `...` and everything else is dropped.
+///
+/// Other assignments declare their name, with the type of the value if it's a literal,
+/// e.g. `VERSION: str`, and enum members keep their values.
+///
+/// If the module defines `__all__`, only the names it lists are kept at the module level
+/// and imported names it lists are re-exported with `import x as x`. Otherwise, names
+/// starting with an underscore are dropped unless `options.stub_keep_private` is set.
+/// Either way, definitions that the kept code refers to, like private base classes
+/// and aliases, are kept.
+pub fn stub(body: &mut Vec<Stmt<TextRange>>, options: &UnparserOptions) {
+    let mut stubber = Stubber {
+        keep_private: options.stub_keep_private,
+        exports: dunder_all(body),
+        referenced: HashSet::new(),
+    };
+    loop {
+        let stubbed = stubber.stub_body(body.clone(), true);
+        let referenced = referenced_names(&stubbed);
+        if referenced.is_subset(&stubber.referenced) {
+            *body = stubbed;
+            return;
+        }
+        stubber.referenced.extend(referenced);
+    }
+}
+
+struct Stubber {
+    keep_private: bool,
+    /// The names in `__all__`, `None` if the module doesn't define it.
+    exports: Option<HashSet<String>>,
+    /// The names the stub refers to so far, whose definitions are kept.
+    referenced: HashSet<String>,
+}
+
+impl Stubber {
+    fn stub_body(&self, body: Vec<Stmt<TextRange>>, module_level: bool) -> Vec<Stmt<TextRange>> {
+        body.into_iter()
+            .filter_map(|stmt| self.stub_stmt(stmt, module_level))
+            .collect()
+    }
+
+    /// The stub of `node`, `None` if it isn't part of the interface.
+    fn stub_stmt(&self, node: Stmt<TextRange>, module_level: bool) -> Option<Stmt<TextRange>> {
+        match node {
+            Stmt::Import(mut data) if module_level => {
+                for alias in &mut data.names {
+                    // `import a.b as a.b` isn't valid
+                    if !alias.name.contains('.') {
+                        self.reexport(&alias.name, &mut alias.asname);
+                    }
+                }
+                Some(Stmt::Import(data))
+            }
+            Stmt::ImportFrom(mut data) if module_level => {
+                for alias in &mut data.names {
+                    if alias.name.as_str() != "*" {
+                        self.reexport(&alias.name, &mut alias.asname);
+                    }
+                }
+                Some(Stmt::ImportFrom(data))
+            }
+            Stmt::FunctionDef(mut data) if self.is_public(&data.name, module_level) => {
+                ellipsis_defaults(&mut data.args);
+                data.body = vec![ellipsis()];
+                Some(Stmt::FunctionDef(data))
+            }
+            Stmt::AsyncFunctionDef(mut data) if self.is_public(&data.name, module_level) => {
+                ellipsis_defaults(&mut data.args);
+                data.body = vec![ellipsis()];
+                Some(Stmt::AsyncFunctionDef(data))
+            }
+            Stmt::ClassDef(mut data) if self.is_public(&data.name, module_level) => {
+                let is_enum = data
+                    .bases
+                    .iter()
+                    .any(|base| ENUM_BASES.iter().any(|name| is_named(base, name)));
+                data.body = data
+                    .body
+                    .into_iter()
+                    .filter_map(|stmt| {
+                        if is_enum && self.is_enum_member(&stmt) {
+                            Some(stmt)
+                        } else {
+                            self.stub_stmt(stmt, false)
+                        }
+                    })
+                    .collect();
+                if data.body.is_empty() {
+                    data.body.push(ellipsis());
+                }
+                Some(Stmt::ClassDef(data))
+            }
+            Stmt::TypeAlias(data) => {
+                let name = data.name.as_name_expr()?;
+                self.is_public(&name.id, module_level)
+                    .then_some(Stmt::TypeAlias(data))
+            }
+            Stmt::AnnAssign(mut data) => {
+                let name = data.target.as_name_expr()?;
+                if module_level && name.id.as_str() == "__all__" {
+                    return Some(Stmt::AnnAssign(data));
+                }
+                if !self.is_public(&name.id, module_level) {
+                    return None;
+                }
+                // the value of an explicit type alias is its type
+                if !is_named(&data.annotation, "TypeAlias") {
+                    data.value = data.value.map(|_| Box::new(constant(Constant::Ellipsis)));
+                }
+                Some(Stmt::AnnAssign(data))
+            }
+            Stmt::Assign(mut data) => {
+                let [Expr::Name(ExprName { id, .. })] = &data.targets[..] else {
+                    return None;
+                };
+                let id = id.clone();
+                let is_type_variable = data.value.as_call_expr().is_some_and(|call| {
+                    TYPE_VARIABLE_FACTORIES
+                        .iter()
+                        .any(|factory| is_named(&call.func, factory))
+                });
+                let is_new_type = data
+                    .value
+                    .as_call_expr()
+                    .is_some_and(|call| is_named(&call.func, "NewType"));
+                if (module_level && id.as_str() == "__all__")
+                    || is_type_variable
+                    || (is_new_type && self.is_public(&id, module_level))
+                {
+                    return Some(Stmt::Assign(data));
+                }
+                if !self.is_public(&id, module_level) {
+                    return None;
+                }
+                if let Some(type_name) = literal_type(&data.value) {
+                    return Some(Stmt::AnnAssign(StmtAnnAssign {
+                        range: data.range,
+                        target: Box::new(data.targets.remove(0)),
+                        annotation: Box::new(load(type_name)),
+                        value: None,
+                        simple: true,
+                    }));
+                }
+                // an alias like `Json = dict[str, Any]` that the kept code refers to
+                if !self.referenced.contains(id.as_str()) {
+                    data.value = Box::new(constant(Constant::Ellipsis));
+                }
+                Some(Stmt::Assign(data))
+            }
+            Stmt::AugAssign(data)
+                if module_level
+                    && data.op == Operator::Add
+                    && data
+                        .target
+                        .as_name_expr()
+                        .is_some_and(|name| name.id.as_str() == "__all__") =>
+            {
+                Some(Stmt::AugAssign(data))
+            }
+            // e.g. `if sys.version_info >= (3, 8):` or `if TYPE_CHECKING:`
+            Stmt::If(mut data) => {
+                data.body = self.stub_body(data.body, module_level);
+                data.orelse = self.stub_body(data.orelse, module_level);
+                if data.body.is_empty() && data.orelse.is_empty() {
+                    return None;
+                }
+                if data.body.is_empty() {
+                    data.body.push(ellipsis());
+                }
+                Some(Stmt::If(data))
+            }
+            // e.g. `try: import ujson as json` with `except ImportError: import json`
+            Stmt::Try(mut data) => {
+                let stubbed = self.stub_blocks(
+                    &mut data.body,
+                    &mut data.handlers,
+                    &mut data.orelse,
+                    &mut data.finalbody,
+                    module_level,
+                );
+                stubbed.then_some(Stmt::Try(data))
+            }
+            Stmt::TryStar(mut data) => {
+                let stubbed = self.stub_blocks(
+                    &mut data.body,
+                    &mut data.handlers,
+                    &mut data.orelse,
+                    &mut data.finalbody,
+                    module_level,
+                );
+                stubbed.then_some(Stmt::TryStar(data))
+            }
+            _ => None,
+        }
+    }
+
+    /// Stubs the blocks of a `try` statement, `false` if none of them is left.
+    fn stub_blocks(
+        &self,
+        body: &mut Vec<Stmt<TextRange>>,
+        handlers: &mut [ExceptHandler<TextRange>],
+        orelse: &mut Vec<Stmt<TextRange>>,
+        finalbody: &mut Vec<Stmt<TextRange>>,
+        module_level: bool,
+    ) -> bool {
+        *body = self.stub_body(std::mem::take(body), module_level);
+        *orelse = self.stub_body(std::mem::take(orelse), module_level);
+        *finalbody = self.stub_body(std::mem::take(finalbody), module_level);
+        let mut is_empty = body.is_empty() && orelse.is_empty() && finalbody.is_empty();
+        for handler in handlers.iter_mut() {
+            let ExceptHandler::ExceptHandler(handler) = handler;
+            handler.body = self.stub_body(std::mem::take(&mut handler.body), module_level);
+            is_empty &= handler.body.is_empty();
+        }
+        if is_empty {
+            return false;
+        }
+        if body.is_empty() {
+            body.push(ellipsis());
+        }
+        for handler in handlers {
+            let ExceptHandler::ExceptHandler(handler) = handler;
+            if handler.body.is_empty() {
+                handler.body.push(ellipsis());
+            }
+        }
+        true
+    }
+
+    /// Whether `node` defines a member of an enum, like `RED = 1`.
+    fn is_enum_member(&self, node: &Stmt<TextRange>) -> bool {
+        node.as_assign_stmt().is_some_and(|assign| {
+            matches!(
+                &assign.targets[..],
+                [Expr::Name(ExprName { id, .. })] if self.is_public(id, false)
+            )
+        })
+    }
+
+    /// Whether a definition of `name` is part of the interface. At the module level,
+    /// that's decided by `__all__` if the module defines it.
+    fn is_public(&self, name: &str, module_level: bool) -> bool {
+        if self.referenced.contains(name) {
+            return true;
+        }
+        match &self.exports {
+            Some(exports) if module_level => exports.contains(name),
+            _ => self.keep_private || !is_private(name),
+        }
+    }
+
+    /// Imports `name` as itself if `__all__` lists it, which re-exports it from a stub.
+    fn reexport(&self, name: &Identifier, asname: &mut Option<Identifier>) {
+        if asname.is_none()
+            && self
+                .exports
+                .as_ref()
+                .is_some_and(|exports| exports.contains(name.as_str()))
+        {
+            *asname = Some(name.clone());
+        }
+    }
+}
+
+/// Whether `name` starts with an underscore without being a dunder name like `__init__`.
+fn is_private(name: &str) -> bool {
+    name.starts_with('_') && !(name.starts_with("__") && name.ends_with("__"))
+}
+
+/// Whether `node` is `name` or an attribute called `name`, e.g. `typing.TypeAlias`.
+fn is_named(node: &Expr<TextRange>, name: &str) -> bool {
+    match node {
+        Expr::Name(data) => data.id.as_str() == name,
+        Expr::Attribute(data) => data.attr.as_str() == name,
+        _ => false,
+    }
+}
+
+/// The name of the type of `node` if it's a literal, e.g. `str` for `"1.0"`.
+fn literal_type(node: &Expr<TextRange>) -> Option<&'static str> {
+    match node {
+        Expr::Constant(ExprConstant { value, .. }) => match value {
+            Constant::Bool(_) => Some("bool"),
+            Constant::Str(_) => Some("str"),
+            Constant::Bytes(_) => Some("bytes"),
+            Constant::Int(_) => Some("int"),
+            Constant::Float(_) => Some("float"),
+            Constant::Complex { .. } => Some("complex"),
+            Constant::None | Constant::Tuple(_) | Constant::Ellipsis => None,
+        },
+        Expr::UnaryOp(data) if matches!(data.op, UnaryOp::USub | UnaryOp::UAdd) => {
+            literal_type(&data.operand).filter(|name| *name != "str" && *name != "bytes")
+        }
+        _ => None,
+    }
+}
+
+/// The names `body` refers to, including the words of its strings, which can be
+/// forward references like `"Node"`. `__all__` is left out.
+fn referenced_names(body: &[Stmt<TextRange>]) -> HashSet<String> {
+    let mut collector = ReferenceCollector::default();
+    for stmt in body {
+        let is_dunder_all = match stmt {
+            Stmt::Assign(data) => data
+                .targets
+                .iter()
+                .any(|target| is_named(target, "__all__")),
+            Stmt::AnnAssign(data) => is_named(&data.target, "__all__"),
+            Stmt::AugAssign(data) => is_named(&data.target, "__all__"),
+            _ => false,
+        };
+        if !is_dunder_all {
+            let Ok(_) = collector.fold_stmt(stmt.clone());
+        }
+    }
+    collector.names
+}
+
+#[derive(Default)]
+struct ReferenceCollector {
+    names: HashSet<String>,
+}
+
+impl Fold<TextRange> for ReferenceCollector {
+    type TargetU = TextRange;
+    type Error = Infallible;
+    type UserContext = ();
+
+    fn will_map_user(&mut self, _user: &TextRange) -> Self::UserContext {}
+
+    fn map_user(
+        &mut self,
+        user: TextRange,
+        _context: Self::UserContext,
+    ) -> Result<Self::TargetU, Self::Error> {
+        Ok(user)
+    }
+
+    fn fold_expr_name(
+        &mut self,
+        node: ExprName<TextRange>,
+    ) -> Result<ExprName<TextRange>, Infallible> {
+        if node.ctx == ExprContext::Load {
+            self.names.insert(node.id.to_string());
+        }
+        Ok(node)
+    }
+
+    fn fold_expr_constant(
+        &mut self,
+        node: ExprConstant<TextRange>,
+    ) -> Result<ExprConstant<TextRange>, Infallible> {
+        if let Constant::Str(value) = &node.value {
+            let words = value
+                .split(|char: char| !(char.is_alphanumeric() || char == '_'))
+                .filter(|word| !word.is_empty());
+            self.names.extend(words.map(str::to_string));
+        }
+        Ok(node)
+    }
+}
+
+fn ellipsis() -> Stmt<TextRange> {
+    Stmt::Expr(StmtExpr {
+        range: TextRange::default(),
+        value: Box::new(constant(Constant::Ellipsis)),
+    })
+}
+
+fn ellipsis_defaults(args: &mut Arguments<TextRange>) {
+    for arg in args
+        .posonlyargs
+        .iter_mut()
+        .chain(&mut args.args)
+        .chain(&mut args.kwonlyargs)
+    {
+        if arg.default.is_some() {
+            arg.default = Some(Box::new(constant(Constant::Ellipsis)));
+        }
+    }
+}
+
+/// The names a module lists in `__all__`, `None` if it doesn't define it
+/// or its value isn't made of string literals.
+fn dunder_all(body: &[Stmt<TextRange>]) -> Option<HashSet<String>> {
+    let mut exports = None;
+    for stmt in body {
+        let (target, value, extends) = match stmt {
+            Stmt::Assign(data) if data.targets.len() == 1 => {
+                (&data.targets[0], &*data.value, false)
+            }
+            Stmt::AnnAssign(data) => match &data.value {
+                Some(value) => (&*data.target, &**value, false),
+                None => continue,
+            },
+            Stmt::AugAssign(data) if data.op == Operator::Add => {
+                (&*data.target, &*data.value, true)
+            }
+            _ => continue,
+        };
+        if target
+            .as_name_expr()
+            .is_none_or(|name| name.id.as_str() != "__all__")
+        {
+            continue;
+        }
+        let elts = match value {
+            Expr::List(data) => &data.elts,
+            Expr::Tuple(data) => &data.elts,
+            _ => return None,
+        };
+        let names = elts
+            .iter()
+            .map(|elt| match elt {
+                Expr::Constant(data) => match &data.value {
+                    Constant::Str(name) => Some(name.clone()),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let exports = exports.get_or_insert_with(HashSet::new);
+        if !extends {
+            exports.clear();
+        }
+        exports.extend(names);
+    }
+    exports
+}
@@ -1,21 +1,37 @@
 use std::ops::Deref;
+use std::sync::Arc;
 
 use rustpython_ast::{
-    text_size::TextRange, Alias, Arg, Arguments, BoolOp, CmpOp, Comprehension, ExceptHandler,
-    ExceptHandlerExceptHandler, Expr, ExprAttribute, ExprAwait, ExprBinOp, ExprBoolOp, ExprCall,
-    ExprCompare, ExprConstant, ExprDict, ExprDictComp, ExprFormattedValue, ExprGeneratorExp,
-    ExprIfExp, ExprJoinedStr, ExprLambda, ExprList, ExprListComp, ExprName, ExprNamedExpr, ExprSet,
-    ExprSetComp, ExprSlice, ExprStarred, ExprSubscript, ExprTuple, ExprUnaryOp, ExprYield,
-    ExprYieldFrom, Keyword, MatchCase, Operator, Pattern, PatternMatchAs, PatternMatchClass,
-    PatternMatchMapping, PatternMatchOr, PatternMatchSequence, PatternMatchSingleton,
-    PatternMatchStar, PatternMatchValue, Stmt, StmtAnnAssign, StmtAssert, StmtAssign, StmtAsyncFor,
+    text_size::{TextRange, TextSize},
+    Alias, Arg, Arguments, BoolOp, CmpOp, Comprehension, ExceptHandler, ExceptHandlerExceptHandler,
+    Expr, ExprAttribute, ExprAwait, ExprBinOp, ExprBoolOp, ExprCall, ExprCompare, ExprConstant,
+    ExprDict, ExprDictComp, ExprFormattedValue, ExprGeneratorExp, ExprIfExp, ExprJoinedStr,
+    ExprLambda, ExprList, ExprListComp, ExprName, ExprNamedExpr, ExprSet, ExprSetComp, ExprSlice,
+    ExprStarred, ExprSubscript, ExprTuple, ExprUnaryOp, ExprYield, ExprYieldFrom, Keyword,
+    MatchCase, Operator, Pattern, PatternMatchAs, PatternMatchClass, PatternMatchMapping,
+    PatternMatchOr, PatternMatchSequence, PatternMatchSingleton, PatternMatchStar,
+    PatternMatchValue, Stmt, StmtAnnAssign, StmtAssert, StmtAssign, StmtAsyncFor,
     StmtAsyncFunctionDef, StmtAsyncWith, StmtAugAssign, StmtBreak, StmtClassDef, StmtContinue,
     StmtDelete, StmtExpr, StmtFor, StmtFunctionDef, StmtGlobal, StmtIf, StmtImport, StmtImportFrom,
     StmtMatch, StmtNonlocal, StmtPass, StmtRaise, StmtReturn, StmtTry, StmtTryStar, StmtTypeAlias,
     StmtWhile, StmtWith, TypeParam, TypeParamParamSpec, TypeParamTypeVar, TypeParamTypeVarTuple,
     UnaryOp, WithItem,
 };
-use rustpython_ast::{Constant, ConversionFlag, Int};
+use rustpython_ast::{Constant, ConversionFlag, Int, Ranged};
+
+use crate::error::{UnparseError, UnparseErrorKind};
+use crate::literal::{
+    bytes_literal, docstring_literal, fstring_literal, fstring_quote, int_literal,
+    is_number_token_for, match_string_tokens, normalize_docstring, open_brackets, other_quote,
+    split_words, str_literal, FStringPart, FieldPart,
+};
+use crate::lower::lower;
+use crate::options::{
+    BlankLinesContext, NonAsciiIdentifiers, PythonVersion, QuoteStyle, StmtKind, TupleParens,
+    UnparserOptions,
+};
+use crate::stub::stub;
+use crate::version::{has_starred_slice, is_dotted_name_decorator, Feature};
 
 enum Precedence {
     NamedExpr = 1,
@@ -45,7 +61,6 @@ impl Precedence {
     }
 }
 
-#[allow(dead_code)]
 const EXPR_PRECEDENCE: usize = 9;
 
 fn get_precedence(node: &Expr<TextRange>) -> usize {
@@ -87,16 +102,106 @@ fn get_precedence(node: &Expr<TextRange>) -> usize {
     }
 }
 
+/// Whether `node` is written with a lambda at its end that isn't enclosed in parentheses.
+fn ends_with_lambda(node: &Expr<TextRange>) -> bool {
+    match node {
+        Expr::Lambda(_) => true,
+        Expr::IfExp(data) => ends_with_lambda(&data.orelse),
+        _ => false,
+    }
+}
+
+/// Where a statement starts in the original source, including its decorators.
+fn stmt_start(node: &Stmt<TextRange>) -> TextSize {
+    let decorator_list = match node {
+        Stmt::FunctionDef(data) => &data.decorator_list,
+        Stmt::AsyncFunctionDef(data) => &data.decorator_list,
+        Stmt::ClassDef(data) => &data.decorator_list,
+        _ => return node.start(),
+    };
+    decorator_list
+        .first()
+        .map_or(node.start(), |decorator| decorator.start())
+}
+
+/// Counts the lines between two statements that only contain whitespace,
+/// given the source text from the end of the first to the start of the second.
+fn count_blank_lines(gap: &str) -> usize {
+    let lines: Vec<&str> = gap.split('\n').collect();
+    if lines.len() < 3 {
+        return 0;
+    }
+    lines[1..lines.len() - 1]
+        .iter()
+        .filter(|line| line.trim().is_empty())
+        .count()
+}
+
+/// Python's `repr` of a float, with infinity spelled as a literal that overflows to it
+/// and NaN as the difference of two infinities, which has no literal.
+fn float_repr(value: f64) -> String {
+    if value.is_nan() {
+        "(1e309-1e309)".to_string()
+    } else if value.is_infinite() {
+        let inf = if value.is_sign_negative() {
+            "-1e309"
+        } else {
+            "1e309"
+        };
+        inf.to_string()
+    } else {
+        rustpython_literal::float::to_string(value)
+    }
+}
+
 pub struct Unparser {
     pub source: String,
+    options: UnparserOptions,
+    /// The source the AST was parsed from, whose ranges are used to keep its formatting.
+    original_source: Option<Arc<str>>,
+    errors: Vec<UnparseError>,
     indent: usize,
+    /// Whether the next statement is the first one of a module, class or function body,
+    /// where a string expression is a docstring.
+    docstring_position: bool,
+    /// The previous statement of the current block, `None` at the start of a block.
+    previous_stmt: Option<StmtKind>,
+    /// Where the previous statement of the current block ended in the original source.
+    previous_stmt_end: Option<TextSize>,
+    /// Where the statement being written starts in `source`.
+    stmt_start: usize,
+    in_class: bool,
+    /// Whether this unparser writes a replacement field of an f-string.
+    in_fstring_field: bool,
+    /// Whether this unparser writes the value of a pattern, where only plain literals are valid.
+    in_pattern: bool,
     in_try_star: bool,
     precedence_level: usize,
 }
 
+impl Default for Unparser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Unparser {
     pub fn new() -> Self {
+        Self::with_options(UnparserOptions::default())
+    }
+
+    pub fn with_options(options: UnparserOptions) -> Self {
         Unparser {
+            options,
+            original_source: None,
+            errors: Vec::new(),
+            docstring_position: true,
+            previous_stmt: None,
+            previous_stmt_end: None,
+            stmt_start: 0,
+            in_class: false,
+            in_fstring_field: false,
+            in_pattern: false,
             in_try_star: false,
             indent: 0,
             precedence_level: Precedence::Test.value(),
@@ -104,8 +209,140 @@ impl Unparser {
         }
     }
 
+    /// Sets the source the unparsed AST was parsed from. Its ranges are then used to
+    /// keep formatting that isn't part of the AST, like blank lines between statements.
+    pub fn with_original_source(mut self, source: impl Into<Arc<str>>) -> Self {
+        self.original_source = Some(source.into());
+        self
+    }
+
+    /// Errors for parts of the AST that couldn't be unparsed with the given options.
+    /// The output is still written, but may not be valid for the requested options:
+    /// whenever this isn't empty, `source` must not be used as Python code. Callers of
+    /// [`Unparser::unparse_suite`], [`Unparser::unparse_stmt`] and
+    /// [`Unparser::unparse_expr`] have to check it, the `try_` variants do so for them.
+    /// If the original source is set, the errors carry their line and column in it.
+    pub fn errors(&self) -> &[UnparseError] {
+        &self.errors
+    }
+
+    /// Like [`Unparser::unparse_suite`], but fails with all errors that occurred while
+    /// unparsing `body`. On failure, nothing is added to `source` and the errors are
+    /// removed from [`Unparser::errors`].
+    pub fn try_unparse_suite(&mut self, body: &[Stmt<TextRange>]) -> Result<(), Vec<UnparseError>> {
+        self.try_unparse(|unparser| unparser.unparse_suite(body))
+    }
+
+    /// Like [`Unparser::unparse_stmt`], but fails with all errors that occurred while
+    /// unparsing `node`, see [`Unparser::try_unparse_suite`].
+    pub fn try_unparse_stmt(&mut self, node: &Stmt<TextRange>) -> Result<(), Vec<UnparseError>> {
+        self.try_unparse(|unparser| unparser.unparse_stmt(node))
+    }
+
+    /// Like [`Unparser::unparse_expr`], but fails with all errors that occurred while
+    /// unparsing `node`, see [`Unparser::try_unparse_suite`].
+    pub fn try_unparse_expr(&mut self, node: &Expr<TextRange>) -> Result<(), Vec<UnparseError>> {
+        self.try_unparse(|unparser| unparser.unparse_expr(node))
+    }
+
+    fn try_unparse(&mut self, unparse: impl FnOnce(&mut Self)) -> Result<(), Vec<UnparseError>> {
+        let start = (self.source.len(), self.errors.len());
+        unparse(self);
+        if self.errors.len() == start.1 {
+            return Ok(());
+        }
+        let errors = self.errors.split_off(start.1);
+        self.truncate(start);
+        Err(errors)
+    }
+
+    fn error(&mut self, kind: UnparseErrorKind, range: TextRange) {
+        let mut error = UnparseError {
+            kind,
+            range,
+            location: None,
+        };
+        if let Some(source) = &self.original_source {
+            error.locate(source);
+        }
+        self.errors.push(error);
+    }
+
+    /// Records an error if the target version doesn't support `feature`, unless downleveling
+    /// already reported that it can't rewrite this use of it.
+    fn require(&mut self, feature: Feature, range: TextRange) {
+        if let Some(target) = self.options.target_version {
+            if target < feature.version() && !self.cannot_downlevel(feature, range) {
+                self.error(
+                    UnparseErrorKind::UnsupportedFeature { feature, target },
+                    range,
+                );
+            }
+        }
+    }
+
+    /// Whether downleveling reported that it can't rewrite the use of `feature` at `range`.
+    fn cannot_downlevel(&self, feature: Feature, range: TextRange) -> bool {
+        self.errors.iter().any(|error| {
+            error.range == range
+                && matches!(
+                    error.kind,
+                    UnparseErrorKind::CannotDownlevel { feature: reported, .. } if reported == feature
+                )
+        })
+    }
+
+    /// Unparses `node` on its own to embed it into a replacement field of an f-string.
+    fn unparse_inner_expr(&mut self, node: &Expr<TextRange>) -> String {
+        self.unparse_inner(|inner_self| inner_self.unparse_expr(node))
+    }
+
+    /// Returns what `f` writes with a separate unparser for a replacement field of an f-string.
+    fn unparse_inner<F>(&mut self, f: F) -> String
+    where
+        F: FnOnce(&mut Self),
+    {
+        let mut inner_unparser = Unparser::with_options(self.options.clone());
+        inner_unparser.original_source = self.original_source.clone();
+        inner_unparser.in_fstring_field = true;
+        // share the errors so far, e.g. to know which uses downleveling already reported
+        inner_unparser.errors = std::mem::take(&mut self.errors);
+        f(&mut inner_unparser);
+        self.errors = inner_unparser.errors;
+        inner_unparser.source
+    }
+
+    fn write_identifier(&mut self, name: &str, range: TextRange) {
+        if !self.options.ascii_only || name.is_ascii() {
+            self.write_str(name);
+            return;
+        }
+        match self.options.non_ascii_identifiers {
+            NonAsciiIdentifiers::Error => {
+                self.error(
+                    UnparseErrorKind::NonAsciiIdentifier(name.to_string()),
+                    range,
+                );
+                self.write_str(name);
+            }
+            NonAsciiIdentifiers::Mangle => {
+                let mangled: String = name
+                    .chars()
+                    .map(|ch| {
+                        if ch.is_ascii() {
+                            ch.to_string()
+                        } else {
+                            format!("_u{:04x}_", ch as u32)
+                        }
+                    })
+                    .collect();
+                self.write_str(&mangled);
+            }
+        }
+    }
+
     fn fill(&mut self, str_: &str) {
-        if self.source.len() > 0 {
+        if !self.source.is_empty() {
             self.write_str(&("\n".to_owned() + &" ".repeat(self.indent * 4) + str_))
         } else {
             self.write_str(str_);
@@ -118,25 +355,76 @@ impl Unparser {
 
     fn write_type_comment(&mut self, type_comment: &Option<String>) {
         if let Some(str_) = type_comment {
-            self.write_str("  # type: ignore");
+            self.write_str("  # type: ");
             self.write_str(str_);
         }
     }
 
+    /// Writes ` ...` after the header of a definition whose body is just `...` when writing
+    /// a stub, like typeshed does. Returns whether it did, in which case the body is written.
+    /// A type comment has to end the header line, so the body is then written as a block.
+    fn writes_stub_body(
+        &mut self,
+        body: &[Stmt<TextRange>],
+        type_comment: &Option<String>,
+    ) -> bool {
+        let is_ellipsis = matches!(
+            body,
+            [Stmt::Expr(StmtExpr { value, .. })]
+                if matches!(**value, Expr::Constant(ExprConstant { value: Constant::Ellipsis, .. }))
+        );
+        if self.options.stub && is_ellipsis && type_comment.is_none() {
+            self.write_str(" ...");
+            true
+        } else {
+            false
+        }
+    }
+
     fn block<F>(&mut self, f: F)
     where
         F: FnOnce(&mut Self),
     {
+        let previous_stmt = self.previous_stmt.take();
+        let previous_stmt_end = self.previous_stmt_end.take();
+        let in_class = std::mem::take(&mut self.in_class);
         self.indent += 1;
         f(self);
         self.indent -= 1;
+        // an empty body leaves the docstring position open
+        self.docstring_position = false;
+        self.previous_stmt = previous_stmt;
+        self.previous_stmt_end = previous_stmt_end;
+        self.in_class = in_class;
+    }
+
+    fn write_blank_lines(&mut self, next: StmtKind, next_start: TextSize) {
+        let context = BlankLinesContext {
+            previous: self.previous_stmt,
+            next,
+            depth: self.indent,
+            in_class: self.in_class,
+        };
+        let mut blank_lines = self.options.blank_lines.before(&context);
+        if let (Some(original_source), Some(previous_end)) =
+            (&self.original_source, self.previous_stmt_end)
+        {
+            // synthesized statements may have ranges that don't fit the source
+            let gap = original_source.get(usize::from(previous_end)..usize::from(next_start));
+            let original_blank_lines = gap.map_or(0, count_blank_lines);
+            blank_lines = blank_lines.max(original_blank_lines.min(self.options.keep_blank_lines));
+        }
+        if !self.source.is_empty() {
+            self.write_str(&"\n".repeat(blank_lines));
+        }
     }
 
     fn delimit_precedence<F>(&mut self, node: &Expr<TextRange>, f: F)
     where
         F: FnOnce(&mut Self),
     {
-        let should_delimit = self.precedence_level > get_precedence(node);
+        let should_delimit =
+            self.options.fully_parenthesized || self.precedence_level > get_precedence(node);
         if should_delimit {
             self.write_str("(");
         }
@@ -166,7 +454,68 @@ impl Unparser {
         self.precedence_level = prev_prec;
     }
 
+    /// Unparses `node` in a position where the grammar accepts a tuple without parentheses.
+    /// Tuples with starred elements keep their parentheses unless `allow_starred` is set,
+    /// because e.g. `return *a, b` is only valid since Python 3.8.
+    fn unparse_tuple_value(&mut self, node: &Expr<TextRange>, allow_starred: bool) {
+        let has_starred = match node {
+            Expr::Tuple(data) => data.elts.iter().any(|elt| elt.is_starred_expr()),
+            _ => false,
+        };
+        let prec = if has_starred && !allow_starred {
+            Precedence::Test
+        } else {
+            Precedence::Tuple
+        };
+        self.with_precedence(prec, |prec_self| {
+            prec_self.unparse_expr(node);
+        });
+    }
+
+    /// Unparses the statements of a module. With [`UnparserOptions::stub`], only a stub
+    /// of the module is written. With [`UnparserOptions::downlevel`], syntax the
+    /// target version can't parse is rewritten into equivalent code for it first.
+    ///
+    /// Parts of the AST that can't be written with the given options are still written,
+    /// possibly as invalid code, and recorded in [`Unparser::errors`].
+    /// Use [`Unparser::try_unparse_suite`] to get them as a `Result` instead.
+    pub fn unparse_suite(&mut self, body: &[Stmt<TextRange>]) {
+        if !self.options.stub && !self.options.downlevel {
+            for stmt in body {
+                self.unparse_stmt(stmt);
+            }
+            return;
+        }
+        let mut body = body.to_vec();
+        if self.options.stub {
+            stub(&mut body, &self.options);
+        }
+        if self.options.downlevel {
+            let mut errors = lower(&mut body, &self.options);
+            if let Some(source) = &self.original_source {
+                for error in &mut errors {
+                    error.locate(source);
+                }
+            }
+            self.errors.append(&mut errors);
+        }
+        for stmt in &body {
+            self.unparse_stmt(stmt);
+        }
+    }
+
+    /// Unparses a single statement. Like with [`Unparser::unparse_suite`], errors are
+    /// only recorded in [`Unparser::errors`], see [`Unparser::try_unparse_stmt`].
     pub fn unparse_stmt(&mut self, node: &Stmt<TextRange>) {
+        let is_docstring_position = std::mem::take(&mut self.docstring_position);
+        let kind = match node {
+            Stmt::FunctionDef(_) | Stmt::AsyncFunctionDef(_) => StmtKind::FunctionDef,
+            Stmt::ClassDef(_) => StmtKind::ClassDef,
+            Stmt::Import(_) | Stmt::ImportFrom(_) => StmtKind::Import,
+            _ => StmtKind::Other,
+        };
+        self.write_blank_lines(kind, stmt_start(node));
+        self.stmt_start = self.source.len();
         match node {
             Stmt::FunctionDef(data) => self.unparse_stmt_function_def(data),
             Stmt::AsyncFunctionDef(data) => self.unparse_stmt_async_function_def(data),
@@ -192,11 +541,13 @@ impl Unparser {
             Stmt::ImportFrom(data) => self.unparse_stmt_import_from(data),
             Stmt::Global(data) => self.unparse_stmt_global(data),
             Stmt::Nonlocal(data) => self.unparse_stmt_nonlocal(data),
-            Stmt::Expr(data) => self.unparse_stmt_expr(data),
+            Stmt::Expr(data) => self.unparse_stmt_expr(data, is_docstring_position),
             Stmt::Pass(data) => self.unparse_stmt_pass(data),
             Stmt::Break(data) => self.unparse_stmt_break(data),
             Stmt::Continue(data) => self.unparse_stmt_continue(data),
         }
+        self.previous_stmt = Some(kind);
+        self.previous_stmt_end = Some(node.end());
     }
 
     fn unparse_stmt_pass(&mut self, _node: &StmtPass<TextRange>) {
@@ -211,25 +562,22 @@ impl Unparser {
         self.fill("continue")
     }
 
-    fn unparse_stmt_function_def(&mut self, node: &StmtFunctionDef<TextRange>) {
-        for decorator in &node.decorator_list {
+    fn unparse_decorators(&mut self, decorator_list: &[Expr<TextRange>]) {
+        for decorator in decorator_list {
+            if !is_dotted_name_decorator(decorator) {
+                self.require(Feature::RelaxedDecorators, decorator.range());
+            }
             self.fill("@");
-            self.unparse_expr(&decorator);
+            self.unparse_expr(decorator);
         }
+    }
+
+    fn unparse_stmt_function_def(&mut self, node: &StmtFunctionDef<TextRange>) {
+        self.unparse_decorators(&node.decorator_list);
         self.fill("def ");
-        self.write_str(&node.name);
+        self.write_identifier(&node.name, node.range);
 
-        if node.type_params.len() > 0 {
-            self.write_str("[");
-            let mut type_params_iter = node.type_params.iter().peekable();
-            while let Some(type_param) = type_params_iter.next() {
-                self.unparse_type_param(type_param);
-                if type_params_iter.peek().is_some() {
-                    self.write_str(", ");
-                }
-            }
-            self.write_str("]");
-        }
+        self.unparse_type_params(&node.type_params);
         self.write_str("(");
 
         self.unparse_arguments(&node.args);
@@ -237,35 +585,26 @@ impl Unparser {
         self.write_str(")");
         if let Some(returns) = &node.returns {
             self.write_str(" -> ");
-            self.unparse_expr(&returns);
+            self.unparse_expr(returns);
         }
         self.write_str(":");
+        if self.writes_stub_body(&node.body, &node.type_comment) {
+            return;
+        }
         self.write_type_comment(&node.type_comment);
         self.block(|block_self| {
+            block_self.docstring_position = true;
             for value in &node.body {
-                block_self.unparse_stmt(&value);
+                block_self.unparse_stmt(value);
             }
         });
     }
 
     fn unparse_stmt_async_function_def(&mut self, node: &StmtAsyncFunctionDef<TextRange>) {
-        for decorator in &node.decorator_list {
-            self.fill("@");
-            self.unparse_expr(&decorator);
-        }
+        self.unparse_decorators(&node.decorator_list);
         self.fill("async def ");
-        self.write_str(&node.name);
-        if node.type_params.len() > 0 {
-            self.write_str("[");
-            let mut type_params_iter = node.type_params.iter().peekable();
-            while let Some(type_param) = type_params_iter.next() {
-                self.unparse_type_param(type_param);
-                if type_params_iter.peek().is_some() {
-                    self.write_str(", ");
-                }
-            }
-            self.write_str("]");
-        }
+        self.write_identifier(&node.name, node.range);
+        self.unparse_type_params(&node.type_params);
         self.write_str("(");
 
         self.unparse_arguments(&node.args);
@@ -273,37 +612,28 @@ impl Unparser {
         self.write_str(")");
         if let Some(returns) = &node.returns {
             self.write_str(" -> ");
-            self.unparse_expr(&returns);
+            self.unparse_expr(returns);
         }
         self.write_str(":");
+        if self.writes_stub_body(&node.body, &node.type_comment) {
+            return;
+        }
         self.write_type_comment(&node.type_comment);
         self.block(|block_self| {
+            block_self.docstring_position = true;
             for value in &node.body {
-                block_self.unparse_stmt(&value);
+                block_self.unparse_stmt(value);
             }
         });
     }
 
     fn unparse_stmt_class_def(&mut self, node: &StmtClassDef<TextRange>) {
-        for decorator in &node.decorator_list {
-            self.fill("@");
-            self.unparse_expr(decorator);
-        }
+        self.unparse_decorators(&node.decorator_list);
 
         self.fill("class ");
-        self.write_str(&node.name);
+        self.write_identifier(&node.name, node.range);
 
-        if node.type_params.len() > 0 {
-            self.write_str("[");
-            let mut type_params_iter = node.type_params.iter().peekable();
-            while let Some(type_param) = type_params_iter.next() {
-                self.unparse_type_param(type_param);
-                if type_params_iter.peek().is_some() {
-                    self.write_str(", ");
-                }
-            }
-            self.write_str("]");
-        }
+        self.unparse_type_params(&node.type_params);
 
         let mut bases_iter = node.bases.iter().peekable();
         let mut keywords_iter = node.keywords.iter().peekable();
@@ -328,18 +658,24 @@ impl Unparser {
             self.write_str(")");
         }
         self.write_str(":");
+        if self.writes_stub_body(&node.body, &None) {
+            return;
+        }
 
         self.block(|block_self| {
+            block_self.docstring_position = true;
+            block_self.in_class = true;
             for value in &node.body {
-                block_self.unparse_stmt(&value);
+                block_self.unparse_stmt(value);
             }
         });
     }
 
     fn unparse_stmt_return(&mut self, node: &StmtReturn<TextRange>) {
-        self.fill("return ");
+        self.fill("return");
         if let Some(value) = &node.value {
-            self.unparse_expr(&value);
+            self.write_str(" ");
+            self.unparse_tuple_value(value, false);
         }
     }
     fn unparse_stmt_delete(&mut self, node: &StmtDelete<TextRange>) {
@@ -367,24 +703,15 @@ impl Unparser {
             }
         }
         self.write_str(" = ");
-        self.unparse_expr(&node.value);
+        self.unparse_tuple_value(&node.value, true);
         self.write_type_comment(&node.type_comment);
     }
 
     fn unparse_stmt_type_alias(&mut self, node: &StmtTypeAlias<TextRange>) {
+        self.require(Feature::TypeAlias, node.range);
         self.fill("type ");
         self.unparse_expr(&node.name);
-        if node.type_params.len() > 0 {
-            self.write_str("[");
-            let mut type_params_iter = node.type_params.iter().peekable();
-            while let Some(type_param) = type_params_iter.next() {
-                self.unparse_type_param(type_param);
-                if type_params_iter.peek().is_some() {
-                    self.write_str(", ");
-                }
-            }
-            self.write_str("]");
-        }
+        self.unparse_type_params(&node.type_params);
         self.write_str(" = ");
         self.unparse_expr(&node.value);
     }
@@ -395,7 +722,7 @@ impl Unparser {
         self.write_str(" ");
         self.unparse_operator(&node.op);
         self.write_str("= ");
-        self.unparse_expr(&node.value);
+        self.unparse_tuple_value(&node.value, false);
     }
 
     fn unparse_stmt_ann_assign(&mut self, node: &StmtAnnAssign<TextRange>) {
@@ -411,7 +738,9 @@ impl Unparser {
 
     fn unparse_stmt_for(&mut self, node: &StmtFor<TextRange>) {
         self.fill("for ");
-        self.unparse_expr(&node.target);
+        self.with_precedence(Precedence::Tuple, |prec_self| {
+            prec_self.unparse_expr(&node.target);
+        });
         self.write_str(" in ");
         self.unparse_expr(&node.iter);
         self.write_str(":");
@@ -421,7 +750,7 @@ impl Unparser {
                 block_self.unparse_stmt(value);
             }
         });
-        if node.orelse.len() > 0 {
+        if !node.orelse.is_empty() {
             self.fill("else:");
             self.block(|block_self| {
                 for stmt in &node.orelse {
@@ -432,7 +761,9 @@ impl Unparser {
     }
     fn unparse_stmt_async_for(&mut self, node: &StmtAsyncFor<TextRange>) {
         self.fill("async for ");
-        self.unparse_expr(&node.target);
+        self.with_precedence(Precedence::Tuple, |prec_self| {
+            prec_self.unparse_expr(&node.target);
+        });
         self.write_str(" in ");
         self.unparse_expr(&node.iter);
         self.write_str(":");
@@ -442,7 +773,7 @@ impl Unparser {
                 block_self.unparse_stmt(value);
             }
         });
-        if node.orelse.len() > 0 {
+        if !node.orelse.is_empty() {
             self.fill("else:");
             self.block(|block_self| {
                 for stmt in &node.orelse {
@@ -461,7 +792,7 @@ impl Unparser {
             }
         });
 
-        if node.orelse.len() > 0 {
+        if !node.orelse.is_empty() {
             self.fill("else:");
             self.block(|block_self| {
                 for stmt in &node.orelse {
@@ -502,39 +833,91 @@ impl Unparser {
     }
 
     fn unparse_stmt_with(&mut self, node: &StmtWith<TextRange>) {
-        self.fill("with ");
-        let mut items_iter = node.items.iter().peekable();
-        while let Some(item) = items_iter.next() {
-            self.unparse_withitem(item);
-            if items_iter.peek().is_some() {
-                self.write_str(", ");
-            }
-        }
-        self.write_str(":");
-        self.block(|block_self| {
-            for stmt in &node.body {
-                block_self.unparse_stmt(stmt);
-            }
-        });
+        self.unparse_with("with ", &node.items, &node.type_comment, &node.body);
     }
+
     fn unparse_stmt_async_with(&mut self, node: &StmtAsyncWith<TextRange>) {
-        self.fill("async with ");
-        let mut items_iter = node.items.iter().peekable();
-        while let Some(item) = items_iter.next() {
-            self.unparse_withitem(item);
-            if items_iter.peek().is_some() {
+        self.unparse_with("async with ", &node.items, &node.type_comment, &node.body);
+    }
+
+    /// Writes the items of a `with` statement on one line. If that line would be longer
+    /// than `max_line_length`, the items are written one per line in parentheses
+    /// if the target version supports it, or otherwise split into nested `with` statements
+    /// with as many items as fit on each line. A statement with a type comment isn't split
+    /// into nested statements, since the comment annotates the targets of all items.
+    fn unparse_with(
+        &mut self,
+        keyword: &str,
+        items: &[WithItem<TextRange>],
+        type_comment: &Option<String>,
+        body: &[Stmt<TextRange>],
+    ) {
+        self.fill(keyword);
+        let header_start = (self.source.len(), self.errors.len());
+        let mut line_items = items.len();
+        for (index, item) in items.iter().enumerate() {
+            let item_start = (self.source.len(), self.errors.len());
+            if index > 0 {
                 self.write_str(", ");
             }
+            self.unparse_withitem(item);
+            // leave room for the colon
+            if index > 0 && self.exceeds_max_line_length(1) {
+                if self.supports_parenthesized_with_items() {
+                    self.truncate(header_start);
+                    self.write_str("(");
+                    self.block(|block_self| {
+                        for item in items {
+                            block_self.fill("");
+                            block_self.unparse_withitem(item);
+                            block_self.write_str(",");
+                        }
+                    });
+                    self.fill(")");
+                } else if type_comment.is_none() {
+                    self.truncate(item_start);
+                    line_items = index;
+                }
+                break;
+            }
         }
         self.write_str(":");
+        self.write_type_comment(type_comment);
         self.block(|block_self| {
-            for stmt in &node.body {
-                block_self.unparse_stmt(stmt);
+            if line_items < items.len() {
+                block_self.unparse_with(keyword, &items[line_items..], &None, body);
+            } else {
+                for stmt in body {
+                    block_self.unparse_stmt(stmt);
+                }
             }
         });
     }
 
+    /// Whether the current line plus `extra` characters exceeds `max_line_length`.
+    fn exceeds_max_line_length(&self, extra: usize) -> bool {
+        let Some(max_line_length) = self.options.max_line_length else {
+            return false;
+        };
+        let line_start = self.source.rfind('\n').map_or(0, |index| index + 1);
+        self.source[line_start..].chars().count() + extra > max_line_length
+    }
+
+    /// Removes the output and errors written since `position` was taken.
+    fn truncate(&mut self, (source_len, errors_len): (usize, usize)) {
+        self.source.truncate(source_len);
+        self.errors.truncate(errors_len);
+    }
+
+    /// Whether `with` items can be wrapped in parentheses, which Python 3.10 documents.
+    fn supports_parenthesized_with_items(&self) -> bool {
+        self.options
+            .target_version
+            .is_some_and(|version| version >= Feature::ParenthesizedContextManagers.version())
+    }
+
     fn unparse_stmt_match(&mut self, node: &StmtMatch<TextRange>) {
+        self.require(Feature::MatchStatement, node.range);
         self.fill("match ");
         self.unparse_expr(&node.subject);
         self.write_str(":");
@@ -570,7 +953,7 @@ impl Unparser {
             self.unparse_excepthandler(handler);
         }
 
-        if node.orelse.len() > 0 {
+        if !node.orelse.is_empty() {
             self.fill("else:");
             self.block(|block_self| {
                 for stmt in &node.orelse {
@@ -579,7 +962,7 @@ impl Unparser {
             });
         }
 
-        if node.finalbody.len() > 0 {
+        if !node.finalbody.is_empty() {
             self.fill("finally:");
             self.block(|block_self| {
                 for stmt in &node.finalbody {
@@ -590,6 +973,7 @@ impl Unparser {
         self.in_try_star = prev_try_star;
     }
     fn unparse_stmt_try_star(&mut self, node: &StmtTryStar<TextRange>) {
+        self.require(Feature::ExceptStar, node.range);
         let prev_try_star = self.in_try_star;
         self.in_try_star = true;
         self.fill("try:");
@@ -603,7 +987,7 @@ impl Unparser {
             self.unparse_excepthandler(handler);
         }
 
-        if node.orelse.len() > 0 {
+        if !node.orelse.is_empty() {
             self.fill("else:");
             self.block(|block_self| {
                 for stmt in &node.orelse {
@@ -612,7 +996,7 @@ impl Unparser {
             });
         }
 
-        if node.finalbody.len() > 0 {
+        if !node.finalbody.is_empty() {
             self.fill("finally:");
             self.block(|block_self| {
                 for stmt in &node.finalbody {
@@ -645,11 +1029,10 @@ impl Unparser {
         self.fill("from ");
         let level = node.level.unwrap_or(Int::new(0));
         self.write_str(&".".repeat(level.to_usize()));
-        let module = match &node.module {
-            Some(name) => name.to_string(),
-            None => "".to_string(),
-        };
-        self.write_str(&(module + " import "));
+        if let Some(module) = &node.module {
+            self.write_identifier(module, node.range);
+        }
+        self.write_str(" import ");
         let mut iter = node.names.iter().peekable();
         while let Some(name) = iter.next() {
             self.unparse_alias(name);
@@ -662,7 +1045,7 @@ impl Unparser {
         self.fill("global ");
         let mut iter = node.names.iter().peekable();
         while let Some(name) = iter.next() {
-            self.write_str(name);
+            self.write_identifier(name, node.range);
             if iter.peek().is_some() {
                 self.write_str(", ");
             }
@@ -672,19 +1055,53 @@ impl Unparser {
         self.fill("nonlocal ");
         let mut iter = node.names.iter().peekable();
         while let Some(name) = iter.next() {
-            self.write_str(name);
+            self.write_identifier(name, node.range);
             if iter.peek().is_some() {
                 self.write_str(", ");
             }
         }
     }
-    fn unparse_stmt_expr(&mut self, node: &StmtExpr<TextRange>) {
+    fn unparse_stmt_expr(&mut self, node: &StmtExpr<TextRange>, is_docstring_position: bool) {
+        if is_docstring_position {
+            if let Expr::Constant(ExprConstant {
+                value: Constant::Str(value),
+                kind,
+                ..
+            }) = node.value.as_ref()
+            {
+                if self.options.normalize_docstrings {
+                    self.unparse_docstring(value, kind.as_deref());
+                } else if let Some(literal) = node
+                    .value
+                    .as_constant_expr()
+                    .and_then(|constant| self.original_string_literal(constant))
+                {
+                    self.fill(&literal);
+                } else {
+                    self.unparse_docstring(value, kind.as_deref());
+                }
+                return;
+            }
+        }
         self.fill("");
         self.with_precedence(Precedence::Yield, |block_self| {
             block_self.unparse_expr(&node.value);
         });
     }
 
+    fn unparse_docstring(&mut self, value: &str, kind: Option<&str>) {
+        let value = if self.options.normalize_docstrings {
+            normalize_docstring(value, &" ".repeat(self.indent * 4))
+        } else {
+            value.to_string()
+        };
+        let prefix = if kind == Some("u") { "u" } else { "" };
+        let literal = docstring_literal(&value, prefix, &self.options);
+        self.fill(&literal);
+    }
+
+    /// Unparses a single expression. Like with [`Unparser::unparse_suite`], errors are
+    /// only recorded in [`Unparser::errors`], see [`Unparser::try_unparse_expr`].
     pub fn unparse_expr(&mut self, node: &Expr<TextRange>) {
         match node {
             Expr::BoolOp(data) => self.unparse_expr_bool_op(data),
@@ -705,7 +1122,7 @@ impl Unparser {
             Expr::Compare(data) => self.unparse_expr_compare(data),
             Expr::Call(data) => self.unparse_expr_call(data),
             Expr::FormattedValue(data) => self.unparse_expr_formatted_value(data),
-            Expr::JoinedStr(data) => self.unparse_expr_joined_str(data, false),
+            Expr::JoinedStr(data) => self.unparse_expr_joined_str(data),
             Expr::Constant(data) => self.unparse_expr_constant(data),
             Expr::Attribute(data) => self.unparse_expr_attribute(data),
             Expr::Subscript(data) => self.unparse_expr_subscript(data),
@@ -740,6 +1157,7 @@ impl Unparser {
     }
 
     fn unparse_expr_named_expr(&mut self, node: &ExprNamedExpr<TextRange>) {
+        self.require(Feature::AssignmentExpression, node.range);
         let enum_member = Expr::NamedExpr(node.to_owned());
         self.delimit_precedence(&enum_member, |block_self| {
             block_self.with_precedence(Precedence::Atom, |prec_self| {
@@ -753,12 +1171,23 @@ impl Unparser {
     fn unparse_expr_bin_op(&mut self, node: &ExprBinOp<TextRange>) {
         let enum_member = Expr::BinOp(node.to_owned());
 
+        let operator_precedence = get_precedence(&enum_member);
+        // `**` is right associative, every other binary operator is left associative
+        let (left_precedence, right_precedence) = match node.op {
+            Operator::Pow => (operator_precedence + 1, operator_precedence),
+            _ => (operator_precedence, operator_precedence + 1),
+        };
+
         self.delimit_precedence(&enum_member, |block_self| {
-            block_self.unparse_expr(&node.left);
+            block_self.with_precedence_num(left_precedence, |prec_self| {
+                prec_self.unparse_expr(&node.left);
+            });
             block_self.write_str(" ");
             block_self.unparse_operator(&node.op);
             block_self.write_str(" ");
-            block_self.unparse_expr(&node.right);
+            block_self.with_precedence_num(right_precedence, |prec_self| {
+                prec_self.unparse_expr(&node.right);
+            });
         })
     }
 
@@ -771,29 +1200,39 @@ impl Unparser {
             UnaryOp::USub => "-",
         };
 
+        let operator_precedence = get_precedence(&enum_member);
+
         self.delimit_precedence(&enum_member, |block_self| {
-            block_self.write_str(&operator);
-            block_self.unparse_expr(&node.operand)
+            block_self.write_str(operator);
+            block_self.with_precedence_num(operator_precedence, |prec_self| {
+                prec_self.unparse_expr(&node.operand);
+            });
         })
     }
     fn unparse_expr_lambda(&mut self, node: &ExprLambda<TextRange>) {
         let enum_member = Expr::Lambda(node.to_owned());
 
         self.delimit_precedence(&enum_member, |block_self| {
-            block_self.write_str("lambda ");
-            block_self.unparse_arguments(&node.args);
-            block_self.write_str(": ");
-            block_self.unparse_expr(&node.body);
+            block_self.with_precedence(Precedence::Test, |prec_self| {
+                prec_self.write_str("lambda ");
+                prec_self.unparse_arguments(&node.args);
+                prec_self.write_str(": ");
+                prec_self.unparse_expr(&node.body);
+            });
         })
     }
     fn unparse_expr_if_exp(&mut self, node: &ExprIfExp<TextRange>) {
         let enum_member = Expr::IfExp(node.to_owned());
         self.delimit_precedence(&enum_member, |block_self| {
-            block_self.unparse_expr(&node.body);
-            block_self.write_str(" if ");
-            block_self.unparse_expr(&node.test);
+            block_self.with_precedence_num(Precedence::Test.value() + 1, |prec_self| {
+                prec_self.unparse_expr(&node.body);
+                prec_self.write_str(" if ");
+                prec_self.unparse_expr(&node.test);
+            });
             block_self.write_str(" else ");
-            block_self.unparse_expr(&node.orelse);
+            block_self.with_precedence(Precedence::Test, |prec_self| {
+                prec_self.unparse_expr(&node.orelse);
+            });
         })
     }
 
@@ -804,14 +1243,19 @@ impl Unparser {
         while let Some((key, value)) = zipped.next() {
             match key {
                 Some(key_value) => {
-                    self.unparse_expr(key_value);
-                    self.write_str(": ");
+                    self.with_precedence(Precedence::Test, |prec_self| {
+                        prec_self.unparse_expr(key_value);
+                        prec_self.write_str(": ");
+                        prec_self.unparse_expr(value);
+                    });
                 }
                 None => {
                     self.write_str("**");
+                    self.with_precedence_num(EXPR_PRECEDENCE, |prec_self| {
+                        prec_self.unparse_expr(value);
+                    });
                 }
             }
-            self.unparse_expr(value);
             if zipped.peek().is_some() {
                 self.write_str(", ");
             }
@@ -820,11 +1264,13 @@ impl Unparser {
     }
 
     fn unparse_expr_set(&mut self, node: &ExprSet<TextRange>) {
-        if node.elts.len() > 0 {
+        if !node.elts.is_empty() {
             self.write_str("{");
             let mut elts_iter = node.elts.iter().peekable();
             while let Some(expr) = elts_iter.next() {
-                self.unparse_expr(expr);
+                self.with_precedence(Precedence::Test, |prec_self| {
+                    prec_self.unparse_expr(expr);
+                });
                 if elts_iter.peek().is_some() {
                     self.write_str(", ");
                 }
@@ -837,7 +1283,9 @@ impl Unparser {
 
     fn unparse_expr_list_comp(&mut self, node: &ExprListComp<TextRange>) {
         self.write_str("[");
-        self.unparse_expr(&node.elt);
+        self.with_precedence(Precedence::Test, |prec_self| {
+            prec_self.unparse_expr(&node.elt);
+        });
         for generator in &node.generators {
             self.unparse_comprehension(generator);
         }
@@ -846,7 +1294,9 @@ impl Unparser {
 
     fn unparse_expr_set_comp(&mut self, node: &ExprSetComp<TextRange>) {
         self.write_str("{");
-        self.unparse_expr(&node.elt);
+        self.with_precedence(Precedence::Test, |prec_self| {
+            prec_self.unparse_expr(&node.elt);
+        });
 
         for generator in &node.generators {
             self.unparse_comprehension(generator);
@@ -856,9 +1306,11 @@ impl Unparser {
 
     fn unparse_expr_dict_comp(&mut self, node: &ExprDictComp<TextRange>) {
         self.write_str("{");
-        self.unparse_expr(&node.key);
-        self.write_str(": ");
-        self.unparse_expr(&node.value);
+        self.with_precedence(Precedence::Test, |prec_self| {
+            prec_self.unparse_expr(&node.key);
+            prec_self.write_str(": ");
+            prec_self.unparse_expr(&node.value);
+        });
 
         for generator in &node.generators {
             self.unparse_comprehension(generator);
@@ -868,7 +1320,9 @@ impl Unparser {
 
     fn unparse_expr_generator_exp(&mut self, node: &ExprGeneratorExp<TextRange>) {
         self.write_str("(");
-        self.unparse_expr(&node.elt);
+        self.with_precedence(Precedence::Test, |prec_self| {
+            prec_self.unparse_expr(&node.elt);
+        });
 
         for generator in &node.generators {
             self.unparse_comprehension(generator);
@@ -892,9 +1346,7 @@ impl Unparser {
             block_self.write_str("yield");
             if let Some(expr) = &node.value {
                 block_self.write_str(" ");
-                block_self.with_precedence(Precedence::Atom, |prec_self| {
-                    prec_self.unparse_expr(expr);
-                });
+                block_self.unparse_tuple_value(expr, false);
             }
         })
     }
@@ -904,7 +1356,7 @@ impl Unparser {
         self.delimit_precedence(&enum_member, |block_self| {
             block_self.write_str("yield from ");
 
-            block_self.with_precedence(Precedence::Atom, |prec_self| {
+            block_self.with_precedence(Precedence::Test, |prec_self| {
                 prec_self.unparse_expr(&node.value);
             });
         })
@@ -914,7 +1366,9 @@ impl Unparser {
         let enum_member = Expr::Compare(node.to_owned());
         let zipped = node.ops.iter().zip(node.comparators.iter());
         self.delimit_precedence(&enum_member, |block_self| {
-            block_self.unparse_expr(&node.left);
+            block_self.with_precedence_num(Precedence::Cmp.value() + 1, |prec_self| {
+                prec_self.unparse_expr(&node.left);
+            });
             for (op, comp) in zipped {
                 let operator = match op {
                     CmpOp::Eq => " == ",
@@ -928,19 +1382,25 @@ impl Unparser {
                     CmpOp::NotEq => " != ",
                     CmpOp::NotIn => " not in ",
                 };
-                block_self.write_str(&operator);
-                block_self.unparse_expr(comp);
+                block_self.write_str(operator);
+                block_self.with_precedence_num(Precedence::Cmp.value() + 1, |prec_self| {
+                    prec_self.unparse_expr(comp);
+                });
             }
         })
     }
 
     fn unparse_expr_call(&mut self, node: &ExprCall<TextRange>) {
-        self.unparse_expr(&node.func);
+        self.with_precedence(Precedence::Atom, |prec_self| {
+            prec_self.unparse_expr(&node.func);
+        });
         let mut args_iter = node.args.iter().peekable();
         let mut keywords_iter = node.keywords.iter().peekable();
         self.write_str("(");
         while let Some(arg) = args_iter.next() {
-            self.unparse_expr(arg);
+            self.with_precedence(Precedence::Test, |prec_self| {
+                prec_self.unparse_expr(arg);
+            });
             if args_iter.peek().is_some() || keywords_iter.peek().is_some() {
                 self.write_str(", ");
             }
@@ -955,71 +1415,387 @@ impl Unparser {
     }
 
     fn unparse_expr_formatted_value(&mut self, node: &ExprFormattedValue<TextRange>) {
-        self.write_str("{");
-        let mut inner_unparser = Unparser::new();
-        inner_unparser.unparse_expr(&node.value);
-        let inner_expr = inner_unparser.source.as_str();
-        if inner_expr.starts_with("{") {
-            self.write_str(" ");
+        // only reached for replacement fields outside of an f-string, so there is no quote to escape
+        for part in self.field_parts(node, None) {
+            match part {
+                FieldPart::Code(text) | FieldPart::Spec(text) => self.write_str(&text),
+            }
         }
-        self.write_str(inner_expr);
-        if node.conversion != ConversionFlag::None {
-            self.write_str("!");
-            let buf = &[node.conversion as u8];
-            let c = std::str::from_utf8(buf).unwrap();
-            self.write_str(c);
+    }
+
+    /// Returns the pieces of the replacement field for `node`. `self_documenting` is the text
+    /// of a self-documenting field up to its `=`, which implies `!r` unless there is a format spec.
+    fn field_parts(
+        &mut self,
+        node: &ExprFormattedValue<TextRange>,
+        self_documenting: Option<&str>,
+    ) -> Vec<FieldPart> {
+        let mut code = "{".to_string();
+        let implied_conversion = match self_documenting {
+            Some(text) => {
+                code += text;
+                if node.format_spec.is_some() {
+                    ConversionFlag::None
+                } else {
+                    ConversionFlag::Repr
+                }
+            }
+            None => {
+                let expr = self.field_expr(&node.value);
+                // a space is needed to tell a dict or set apart from the braces of the field
+                if expr.starts_with('{') {
+                    code.push(' ');
+                }
+                code += &expr;
+                ConversionFlag::None
+            }
+        };
+        if node.conversion != implied_conversion {
+            code += match node.conversion {
+                ConversionFlag::Str => "!s",
+                ConversionFlag::Ascii => "!a",
+                ConversionFlag::Repr => "!r",
+                ConversionFlag::None => "",
+            };
         }
+
+        let mut parts = Vec::new();
         if let Some(format_spec) = &node.format_spec {
-            self.write_str(":");
+            code.push(':');
             match format_spec.deref() {
                 Expr::JoinedStr(joined_str) => {
-                    if joined_str.values.len() > 0 {
-                        self.unparse_expr_joined_str(joined_str, true);
+                    for value in &joined_str.values {
+                        match value {
+                            Expr::Constant(ExprConstant {
+                                value: Constant::Str(text),
+                                ..
+                            }) => self.push_spec_literal(&mut parts, &mut code, text),
+                            Expr::FormattedValue(nested) => {
+                                parts.push(FieldPart::Code(std::mem::take(&mut code)));
+                                parts.extend(self.field_parts(nested, None));
+                            }
+                            _ => code += &format!("{{{}}}", self.field_expr(value)),
+                        }
                     }
                 }
-                _ => self.unparse_expr(&format_spec),
-            };
+                // the parser always produces a joined str, but any expression can be nested
+                _ => code += &format!("{{{}}}", self.field_expr(format_spec)),
+            }
+        }
+        code.push('}');
+        parts.push(FieldPart::Code(code));
+        parts.retain(|part| !matches!(part, FieldPart::Code(code) if code.is_empty()));
+        parts
+    }
+
+    /// Adds literal text of a format spec to `parts`. Braces in a format spec always start
+    /// a nested replacement field, so literal ones are written as fields with a string.
+    fn push_spec_literal(&mut self, parts: &mut Vec<FieldPart>, code: &mut String, text: &str) {
+        let mut rest = text;
+        while !rest.is_empty() {
+            let brace_index = rest.find(['{', '}']).unwrap_or(rest.len());
+            if brace_index > 0 {
+                parts.push(FieldPart::Code(std::mem::take(code)));
+                parts.push(FieldPart::Spec(rest[..brace_index].to_string()));
+            }
+            if let Some(brace) = rest[brace_index..].chars().next() {
+                *code += &format!("{{{}}}", str_literal(&brace.to_string(), "", &self.options));
+                rest = &rest[brace_index + 1..];
+            } else {
+                rest = "";
+            }
         }
-        self.write_str("}");
     }
 
-    fn unparse_expr_joined_str(&mut self, node: &ExprJoinedStr<TextRange>, is_spec: bool) {
-        if !is_spec {
-            self.write_str("f");
+    /// Unparses the expression of a replacement field.
+    fn field_expr(&mut self, node: &Expr<TextRange>) -> String {
+        let expr = self.unparse_inner_expr(node);
+        // the colon of a lambda would start the format spec
+        if ends_with_lambda(node) {
+            format!("({})", expr)
+        } else {
+            expr
         }
-        let mut expr_source = String::new();
-        for expr in node.values.iter() {
-            let mut inner_unparser = Unparser::new();
+    }
+
+    fn unparse_expr_joined_str(&mut self, node: &ExprJoinedStr<TextRange>) {
+        let (parts, quote) = if self.supports_pep701() {
+            let parts = self.fstring_parts(node);
+            let quote = fstring_quote(&parts, self.options.quote_style);
+            (parts, quote)
+        } else {
+            // before Python 3.12 the expressions of replacement fields can't contain backslashes,
+            // line breaks, comments or the quote of the f-string,
+            // so strings in them have to use the other quote
+            let is_valid = |parts: &[FStringPart], quote: char| {
+                parts.iter().all(|part| match part {
+                    FStringPart::Literal(_) => true,
+                    FStringPart::Field(field_parts) => {
+                        field_parts.iter().all(|field_part| match field_part {
+                            FieldPart::Code(code) => !code.contains([quote, '\\', '\n', '#']),
+                            FieldPart::Spec(_) => true,
+                        })
+                    }
+                })
+            };
+            let spec_text = |spec: &Expr<TextRange>| -> Vec<FieldPart> {
+                let Expr::JoinedStr(spec) = spec else {
+                    return vec![];
+                };
+                spec.values
+                    .iter()
+                    .filter_map(|value| match value {
+                        Expr::Constant(ExprConstant {
+                            value: Constant::Str(str_),
+                            ..
+                        }) => Some(FieldPart::Spec(str_.to_string())),
+                        _ => None,
+                    })
+                    .collect()
+            };
+            let literal_parts: Vec<FStringPart> = node
+                .values
+                .iter()
+                .map(|expr| match expr {
+                    Expr::Constant(ExprConstant {
+                        value: Constant::Str(str_),
+                        ..
+                    }) => FStringPart::Literal(str_.to_string()),
+                    Expr::FormattedValue(ExprFormattedValue {
+                        format_spec: Some(format_spec),
+                        ..
+                    }) => FStringPart::Field(spec_text(format_spec)),
+                    _ => FStringPart::Field(vec![]),
+                })
+                .collect();
+            let mut quote = fstring_quote(&literal_parts, self.options.quote_style);
+            let errors_before = self.errors.len();
+            let mut parts = self.fstring_parts_with_field_quote(node, other_quote(quote));
+            if !is_valid(&parts, quote) {
+                self.errors.truncate(errors_before);
+                quote = other_quote(quote);
+                parts = self.fstring_parts_with_field_quote(node, other_quote(quote));
+                // without a target version, the output has to run before Python 3.12 too
+                if !is_valid(&parts, quote) {
+                    self.error(UnparseErrorKind::FStringNeedsPep701, node.range);
+                }
+            }
+            (parts, quote)
+        };
+
+        let literal = fstring_literal(&parts, quote, &self.options);
+        // replacement fields are never split
+        let atoms: Vec<FStringPart> = parts
+            .into_iter()
+            .flat_map(|part| match part {
+                FStringPart::Literal(text) => {
+                    let chars: Vec<char> = text.chars().collect();
+                    split_words(&chars, |ch| ch.is_whitespace())
+                        .into_iter()
+                        .map(|word| FStringPart::Literal(word.iter().collect()))
+                        .collect()
+                }
+                field => vec![field],
+            })
+            .collect();
+        self.write_splittable_literal(literal, &atoms, |atoms, options| {
+            fstring_literal(atoms, quote, options)
+        });
+    }
+
+    /// Whether f-strings can be written like PEP 701 allows since Python 3.12.
+    fn supports_pep701(&self) -> bool {
+        self.options
+            .target_version
+            .is_some_and(|version| version >= PythonVersion::PY312)
+    }
+
+    fn fstring_parts(&mut self, node: &ExprJoinedStr<TextRange>) -> Vec<FStringPart> {
+        let escape_braces = |text: &str| text.replace('{', "{{").replace('}', "}}");
+        let mut parts = Vec::new();
+        let mut previous_literal: Option<&str> = None;
+        for expr in &node.values {
             match expr {
-                Expr::Constant(ExprConstant { value, .. }) => {
-                    if let Constant::Str(str_) = value {
-                        let escaped = str_.replace('{', "{{").replace('}', "}}");
-                        inner_unparser.write_str(&escaped);
-                    } else {
-                        unreachable!()
+                Expr::Constant(ExprConstant {
+                    value: Constant::Str(str_),
+                    ..
+                }) => {
+                    parts.push(FStringPart::Literal(escape_braces(str_)));
+                    previous_literal = Some(str_);
+                    continue;
+                }
+                Expr::FormattedValue(formatted_value) => {
+                    match previous_literal
+                        .and_then(|text| self.self_documenting_field(text, formatted_value))
+                    {
+                        Some((remaining_literal, field)) => {
+                            parts.pop();
+                            if !remaining_literal.is_empty() {
+                                parts.push(FStringPart::Literal(escape_braces(remaining_literal)));
+                            }
+                            parts.push(FStringPart::Field(field));
+                        }
+                        None => {
+                            let field = self.field_parts(formatted_value, None);
+                            parts.push(FStringPart::Field(field));
+                        }
                     }
                 }
+                // not produced by the parser, but any expression can be used as a field
                 _ => {
-                    inner_unparser.unparse_expr(expr);
+                    let field = format!("{{{}}}", self.field_expr(expr));
+                    parts.push(FStringPart::Field(vec![FieldPart::Code(field)]));
                 }
             }
+            previous_literal = None;
+        }
+        parts
+    }
 
-            expr_source += inner_unparser.source.as_str();
+    /// The parser turns `f"{x=}"` into the literal `x=` followed by `{x!r}`. If `literal` ends
+    /// like that for `node`, returns the rest of the literal and the compact replacement field.
+    fn self_documenting_field<'a>(
+        &mut self,
+        literal: &'a str,
+        node: &ExprFormattedValue<TextRange>,
+    ) -> Option<(&'a str, Vec<FieldPart>)> {
+        // without a format spec the conversion defaults to `!r`
+        if node.format_spec.is_none() && node.conversion == ConversionFlag::None {
+            return None;
+        }
+
+        let trailing_spaces = |text: &str| text.len() - text.trim_end_matches(' ').len();
+        let after_equals = trailing_spaces(literal);
+        let before_equals_end = literal[..literal.len() - after_equals].strip_suffix('=')?;
+        let before_equals = trailing_spaces(before_equals_end);
+        let expr_end = &before_equals_end[..before_equals_end.len() - before_equals];
+
+        let errors_before = self.errors.len();
+        let unparsed_expr = self.field_expr(&node.value);
+        // a lambda has to be parenthesized, which its range doesn't include
+        let original_expr = self
+            .original_text(node.value.range())
+            .filter(|_| !ends_with_lambda(&node.value))
+            .map(str::to_owned);
+        let Some(expr_text) = original_expr
+            .into_iter()
+            .chain([unparsed_expr])
+            .find(|expr_text| expr_end.ends_with(expr_text.as_str()))
+        else {
+            self.errors.truncate(errors_before);
+            return None;
+        };
+        let rest = &expr_end[..expr_end.len() - expr_text.len()];
+        let leading_spaces = trailing_spaces(rest);
+        // a space is needed to tell a dict or set apart from the braces of the field
+        if leading_spaces == 0 && expr_text.starts_with('{') {
+            self.errors.truncate(errors_before);
+            return None;
+        }
+        let field_start = rest.len() - leading_spaces;
+        // the text of the field is code, which can't be escaped, so the text before `=`
+        // is written as an escaped literal instead
+        if self.options.ascii_only && !literal[field_start..].is_ascii() {
+            self.errors.truncate(errors_before);
+            return None;
         }
 
-        if is_spec {
-            self.write_str(&expr_source);
+        let field = self.field_parts(node, Some(&literal[field_start..]));
+        Some((&literal[..field_start], field))
+    }
+
+    /// Like [`Unparser::fstring_parts`], but writes strings in replacement fields with `quote`.
+    fn fstring_parts_with_field_quote(
+        &mut self,
+        node: &ExprJoinedStr<TextRange>,
+        quote: char,
+    ) -> Vec<FStringPart> {
+        let field_quote_style = if quote == '"' {
+            QuoteStyle::Double
         } else {
-            let escaped_source = rustpython_literal::escape::UnicodeEscape::new_repr(&expr_source)
-                .str_repr()
-                .to_string()
-                .unwrap();
-            self.write_str(&escaped_source);
+            QuoteStyle::Single
+        };
+        let quote_style = std::mem::replace(&mut self.options.quote_style, field_quote_style);
+        let parts = self.fstring_parts(node);
+        self.options.quote_style = quote_style;
+        parts
+    }
+
+    fn write_str_literal(&mut self, value: &str, prefix: &str) {
+        let literal = str_literal(value, prefix, &self.options);
+        let chars: Vec<char> = value.chars().collect();
+        let words = split_words(&chars, |ch| ch.is_whitespace());
+        self.write_splittable_literal(literal, &words, |words, options| {
+            str_literal(&words.concat().iter().collect::<String>(), prefix, options)
+        });
+    }
+
+    /// Writes `literal`, or if it doesn't fit into the maximum line length, the literals `render`
+    /// returns for consecutive `atoms` as an implicit concatenation with one literal per line.
+    fn write_splittable_literal<A>(
+        &mut self,
+        literal: String,
+        atoms: &[A],
+        render: impl Fn(&[A], &UnparserOptions) -> String,
+    ) {
+        let line_start = self.source.rfind('\n').map_or(0, |index| index + 1);
+        let column = self.source[line_start..].chars().count();
+        let Some(max_line_length) = self.options.max_line_length else {
+            self.write_str(&literal);
+            return;
+        };
+        if column + literal.chars().count() <= max_line_length
+            || self.in_fstring_field
+            || self.in_pattern
+            || literal.contains('\n')
+        {
+            self.write_str(&literal);
+            return;
+        }
+
+        // every chunk uses the quote of the whole literal and is raw only if it is, so each
+        // atom is escaped the same way whichever chunk it ends up in
+        let prefix = &literal[..literal.find(['\'', '"']).unwrap_or(0)];
+        let options = UnparserOptions {
+            quote_style: match literal.chars().find(|ch| *ch == '\'' || *ch == '"') {
+                Some('"') => QuoteStyle::Double,
+                _ => QuoteStyle::Single,
+            },
+            raw_strings: self.options.raw_strings && prefix.contains(['r', 'R']),
+            ..self.options.clone()
+        };
+        let escaped_length = |rendered: String| {
+            let opening = rendered.find(['\'', '"']).unwrap_or(0);
+            rendered[opening..].chars().count().saturating_sub(2)
+        };
+
+        // leave room for the parentheses, the prefix and the quotes
+        let width = max_line_length.saturating_sub(column + 2 + prefix.chars().count() + 2);
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        let mut length = 0;
+        for (index, atom) in atoms.iter().enumerate() {
+            let atom_length = escaped_length(render(std::slice::from_ref(atom), &options));
+            if index > start && length + atom_length > width {
+                chunks.push(render(&atoms[start..index], &options));
+                start = index;
+                length = 0;
+            }
+            length += atom_length;
+        }
+        if start < atoms.len() {
+            chunks.push(render(&atoms[start..], &options));
+        }
+        if chunks.len() < 2 {
+            self.write_str(&literal);
+            return;
         }
+        let separator = "\n".to_string() + &" ".repeat(column + 1);
+        self.write_str(&format!("({})", chunks.join(&separator)));
     }
 
     fn _unparse_constant(&mut self, constant: &Constant) {
-        return match constant {
+        match constant {
             Constant::Tuple(values) => {
                 self.write_str("(");
                 let mut values_iter = values.iter().peekable();
@@ -1040,60 +1816,128 @@ impl Unparser {
                 }
             }
             Constant::Bytes(value) => {
-                let utf8 = String::from_utf8(value.to_owned());
-                self.write_str("b");
-                let escaped = rustpython_literal::escape::UnicodeEscape::new_repr(&utf8.unwrap())
-                    .str_repr()
-                    .to_string()
-                    .unwrap();
-                self.write_str(&escaped);
-            }
-            Constant::Int(value) => self.write_str(&value.to_string()),
-            Constant::Str(value) => {
-                let escaped = rustpython_literal::escape::UnicodeEscape::new_repr(value)
-                    .str_repr()
-                    .to_string()
-                    .unwrap();
-
-                self.write_str(&escaped);
+                let literal = bytes_literal(value, &self.options);
+                let words = split_words(value, |byte| byte.is_ascii_whitespace());
+                self.write_splittable_literal(literal, &words, |words, options| {
+                    bytes_literal(&words.concat(), options)
+                });
+            }
+            Constant::Int(value) => {
+                let literal = int_literal(value, &self.options);
+                self.write_str(&literal);
             }
+            Constant::Str(value) => self.write_str_literal(value, ""),
             Constant::None => self.write_str("None"),
-            Constant::Complex { real, imag: _ } => self.write_str(&real.to_string()),
-            Constant::Float(value) => self.write_str(&value.to_string()),
-        };
+            Constant::Complex { real, imag } => {
+                let imag = if imag.is_nan() {
+                    "(1e309j-1e309j)".to_string()
+                } else {
+                    float_repr(*imag) + "j"
+                };
+                if *real == 0.0 {
+                    self.write_str(&imag);
+                } else {
+                    let sign = if imag.starts_with('-') { "" } else { "+" };
+                    self.write_str(&format!("({}{}{})", float_repr(*real), sign, imag));
+                }
+            }
+            Constant::Float(value) => self.write_str(&float_repr(*value)),
+        }
+    }
+
+    /// The text of `range` in the original source, if it was given and `range` fits it.
+    fn original_text(&self, range: TextRange) -> Option<&str> {
+        self.original_source
+            .as_deref()
+            .and_then(|source| source.get(usize::from(range.start())..usize::from(range.end())))
+    }
+
+    /// The original spelling of a string or bytes constant if its value hasn't changed,
+    /// with implicitly concatenated literals on multiple lines wrapped in parentheses.
+    fn original_string_literal(&self, constant: &ExprConstant<TextRange>) -> Option<String> {
+        let text = self
+            .original_text(constant.range)
+            .filter(|text| !self.options.ascii_only || text.is_ascii())
+            // replacement fields can't contain backslashes or line breaks before Python 3.12
+            .filter(|text| {
+                !self.in_fstring_field || self.supports_pep701() || !text.contains(['\\', '\n'])
+            })?;
+        match match_string_tokens(text, &constant.value)? {
+            true if open_brackets(&self.source[self.stmt_start..]) > 0 => Some(text.to_string()),
+            // a parenthesized literal isn't valid as a mapping key of a pattern
+            true if self.in_pattern => None,
+            true => Some(format!("({})", text)),
+            false => Some(text.to_string()),
+        }
     }
 
     fn unparse_expr_constant(&mut self, node: &ExprConstant<TextRange>) {
-        if node.kind.as_deref().is_some_and(|kind| kind == "u") {
-            self.write_str("u");
+        if let Some(text) = self
+            .original_text(node.range)
+            .filter(|text| is_number_token_for(text, &node.value))
+            .map(str::to_owned)
+            .or_else(|| self.original_string_literal(node))
+        {
+            self.write_str(&text);
+            return;
+        }
+        match &node.value {
+            Constant::Str(value) if node.kind.as_deref().is_some_and(|kind| kind == "u") => {
+                self.write_str_literal(value, "u");
+            }
+            value => self._unparse_constant(value),
         }
-        self._unparse_constant(&node.value)
     }
 
     fn unparse_expr_attribute(&mut self, node: &ExprAttribute<TextRange>) {
-        self.unparse_expr(&node.value);
+        self.with_precedence(Precedence::Atom, |prec_self| {
+            prec_self.unparse_expr(&node.value);
+        });
+        // `1.real` would be lexed as the float `1.` followed by a name
+        if let Expr::Constant(ExprConstant {
+            value: Constant::Int(_),
+            ..
+        }) = node.value.as_ref()
+        {
+            self.write_str(" ");
+        }
         self.write_str(".");
-        self.write_str(&node.attr);
+        self.write_identifier(&node.attr, node.range);
     }
     fn unparse_expr_subscript(&mut self, node: &ExprSubscript<TextRange>) {
-        self.unparse_expr(&node.value);
+        self.with_precedence(Precedence::Atom, |prec_self| {
+            prec_self.unparse_expr(&node.value);
+        });
+        if has_starred_slice(&node.slice) {
+            self.require(Feature::StarredSubscript, node.slice.range());
+        }
         self.write_str("[");
-        self.unparse_expr(&node.slice);
+        match node.slice.as_ref() {
+            // slices are only valid directly inside the brackets, never inside parentheses
+            Expr::Tuple(tuple) if tuple.elts.iter().any(|elt| elt.is_slice_expr()) => {
+                self.unparse_tuple_elts(tuple);
+            }
+            slice => self.unparse_tuple_value(slice, false),
+        }
         self.write_str("]");
     }
     fn unparse_expr_starred(&mut self, node: &ExprStarred<TextRange>) {
         self.write_str("*");
-        self.unparse_expr(&node.value)
+        self.with_precedence_num(EXPR_PRECEDENCE, |prec_self| {
+            prec_self.unparse_expr(&node.value);
+        });
     }
 
     fn unparse_expr_name(&mut self, node: &ExprName<TextRange>) {
-        self.write_str(&node.id.as_str())
+        self.write_identifier(node.id.as_str(), node.range)
     }
     fn unparse_expr_list(&mut self, node: &ExprList<TextRange>) {
         let mut elts_iter = node.elts.iter().peekable();
         self.write_str("[");
         while let Some(expr) = elts_iter.next() {
-            self.unparse_expr(expr);
+            self.with_precedence(Precedence::Test, |prec_self| {
+                prec_self.unparse_expr(expr);
+            });
             if elts_iter.peek().is_some() {
                 self.write_str(", ");
             }
@@ -1102,29 +1946,47 @@ impl Unparser {
     }
 
     fn unparse_expr_tuple(&mut self, node: &ExprTuple<TextRange>) {
+        let should_delimit = node.elts.is_empty()
+            || self.options.tuple_parens == TupleParens::Always
+            || self.options.fully_parenthesized
+            || self.precedence_level > Precedence::Tuple.value();
+        if should_delimit {
+            self.write_str("(");
+        }
+        self.unparse_tuple_elts(node);
+        if should_delimit {
+            self.write_str(")");
+        }
+    }
+
+    fn unparse_tuple_elts(&mut self, node: &ExprTuple<TextRange>) {
         let mut elts_iter = node.elts.iter().peekable();
-        self.write_str("(");
         while let Some(expr) = elts_iter.next() {
-            self.unparse_expr(expr);
-            if elts_iter.peek().is_some() || node.elts.len() == 1 {
+            self.with_precedence(Precedence::Test, |prec_self| {
+                prec_self.unparse_expr(expr);
+            });
+            if elts_iter.peek().is_some() {
                 self.write_str(", ");
+            } else if node.elts.len() == 1 {
+                self.write_str(",");
             }
         }
-        self.write_str(")");
     }
 
     fn unparse_expr_slice(&mut self, node: &ExprSlice<TextRange>) {
-        if let Some(lower) = &node.lower {
-            self.unparse_expr(lower);
-        }
-        self.write_str(":");
-        if let Some(upper) = &node.upper {
-            self.unparse_expr(upper);
-        }
-        if let Some(step) = &node.step {
-            self.write_str(":");
-            self.unparse_expr(step);
-        }
+        self.with_precedence(Precedence::Test, |prec_self| {
+            if let Some(lower) = &node.lower {
+                prec_self.unparse_expr(lower);
+            }
+            prec_self.write_str(":");
+            if let Some(upper) = &node.upper {
+                prec_self.unparse_expr(upper);
+            }
+            if let Some(step) = &node.step {
+                prec_self.write_str(":");
+                prec_self.unparse_expr(step);
+            }
+        });
     }
 
     fn unparse_operator(&mut self, node: &Operator) {
@@ -1151,12 +2013,19 @@ impl Unparser {
         } else {
             self.write_str(" for ");
         }
-        self.unparse_expr(&node.target);
+        self.with_precedence(Precedence::Tuple, |prec_self| {
+            prec_self.unparse_expr(&node.target);
+        });
         self.write_str(" in ");
-        self.unparse_expr(&node.iter);
-        for if_ in &node.ifs {
-            self.unparse_expr(if_);
-        }
+        // iterators and conditions are disjunctions, so anything binding
+        // looser than `or` (lambdas, conditional and named expressions) needs parentheses
+        self.with_precedence_num(Precedence::Test.value() + 1, |prec_self| {
+            prec_self.unparse_expr(&node.iter);
+            for if_ in &node.ifs {
+                prec_self.write_str(" if ");
+                prec_self.unparse_expr(if_);
+            }
+        });
     }
 
     fn unparse_excepthandler(&mut self, node: &ExceptHandler<TextRange>) {
@@ -1180,7 +2049,7 @@ impl Unparser {
         }
         if let Some(name) = &node.name {
             self.write_str(" as ");
-            self.write_str(name);
+            self.write_identifier(name, node.range);
         }
 
         self.write_str(":");
@@ -1195,6 +2064,9 @@ impl Unparser {
         let mut posonly_iter = node.posonlyargs.iter().peekable();
         let mut args_iter = node.args.iter().peekable();
         let mut kw_iter = node.kwonlyargs.iter().peekable();
+        if let Some(first) = node.posonlyargs.first() {
+            self.require(Feature::PositionalOnlyParameters, first.def.range);
+        }
         while let Some(posonly) = posonly_iter.next() {
             self.unparse_arg(posonly.as_arg());
             if let Some(default) = &posonly.default {
@@ -1207,7 +2079,7 @@ impl Unparser {
             }
         }
 
-        if node.posonlyargs.len() > 0 {
+        if !node.posonlyargs.is_empty() {
             self.write_str(", /,");
         }
 
@@ -1228,16 +2100,19 @@ impl Unparser {
 
         if let Some(vararg) = &node.vararg {
             self.write_str("*");
-            self.write_str(&vararg.arg);
+            self.write_identifier(&vararg.arg, vararg.range);
 
             if let Some(annotation) = &vararg.annotation {
+                if annotation.is_starred_expr() {
+                    self.require(Feature::StarredAnnotation, annotation.range());
+                }
                 self.write_str(": ");
                 self.unparse_expr(annotation);
             }
             if kw_iter.peek().is_some() || node.kwarg.is_some() {
                 self.write_str(", ");
             }
-        } else if node.kwonlyargs.len() > 0 {
+        } else if !node.kwonlyargs.is_empty() {
             self.write_str("*, ");
         }
 
@@ -1254,16 +2129,16 @@ impl Unparser {
 
         if let Some(kwarg) = &node.kwarg {
             self.write_str("**");
-            self.write_str(&kwarg.arg);
+            self.write_identifier(&kwarg.arg, kwarg.range);
             if let Some(annotation) = &kwarg.annotation {
                 self.write_str(": ");
-                self.unparse_expr(&annotation);
+                self.unparse_expr(annotation);
             }
         }
     }
 
     fn unparse_arg(&mut self, node: &Arg<TextRange>) {
-        self.write_str(node.arg.as_str());
+        self.write_identifier(node.arg.as_str(), node.range);
         if let Some(annotation) = &node.annotation {
             self.write_str(": ");
             self.unparse_expr(annotation);
@@ -1272,19 +2147,22 @@ impl Unparser {
 
     fn unparse_keyword(&mut self, node: &Keyword<TextRange>) {
         if let Some(arg) = &node.arg {
-            self.write_str(arg.as_str());
+            self.write_identifier(arg.as_str(), node.range);
             self.write_str("=");
         } else {
             self.write_str("**");
         }
 
-        self.unparse_expr(&node.value);
+        self.with_precedence(Precedence::Test, |prec_self| {
+            prec_self.unparse_expr(&node.value);
+        });
     }
 
     fn unparse_alias(&mut self, node: &Alias<TextRange>) {
-        self.write_str(node.name.as_str());
-        if node.asname.is_some() {
-            self.write_str(&format!(" as {}", node.asname.as_ref().unwrap()));
+        self.write_identifier(node.name.as_str(), node.range);
+        if let Some(asname) = &node.asname {
+            self.write_str(" as ");
+            self.write_identifier(asname, node.range);
         }
     }
 
@@ -1301,7 +2179,7 @@ impl Unparser {
         self.unparse_pattern(&node.pattern);
         if let Some(guard) = &node.guard {
             self.write_str(" if ");
-            self.unparse_expr(&guard);
+            self.unparse_expr(guard);
         }
         self.write_str(":");
         self.block(|block_self| {
@@ -1324,8 +2202,20 @@ impl Unparser {
         }
     }
 
+    /// Value patterns only accept literals and dotted names. Wrapping a part of
+    /// a complex literal like `-1 + 2j` or a split string in parentheses
+    /// would turn it into a group pattern, which isn't valid as a mapping key.
+    fn unparse_pattern_value_expr(&mut self, node: &Expr<TextRange>) {
+        let prev_fully_parenthesized = self.options.fully_parenthesized;
+        self.options.fully_parenthesized = false;
+        let prev_in_pattern = std::mem::replace(&mut self.in_pattern, true);
+        self.unparse_expr(node);
+        self.options.fully_parenthesized = prev_fully_parenthesized;
+        self.in_pattern = prev_in_pattern;
+    }
+
     fn unparse_pattern_match_value(&mut self, node: &PatternMatchValue<TextRange>) {
-        self.unparse_expr(&node.value)
+        self.unparse_pattern_value_expr(&node.value)
     }
 
     fn unparse_pattern_match_singleton(&mut self, node: &PatternMatchSingleton<TextRange>) {
@@ -1348,7 +2238,7 @@ impl Unparser {
         let mut pairs_iter = node.keys.iter().zip(node.patterns.iter()).peekable();
         self.write_str("{");
         while let Some((key, pattern)) = pairs_iter.next() {
-            self.unparse_expr(key);
+            self.unparse_pattern_value_expr(key);
             self.write_str(": ");
             self.unparse_pattern(pattern);
             if pairs_iter.peek().is_some() {
@@ -1356,11 +2246,11 @@ impl Unparser {
             }
         }
         if let Some(rest) = &node.rest {
-            if node.keys.len() > 0 {
+            if !node.keys.is_empty() {
                 self.write_str(", ");
             }
             self.write_str("**");
-            self.write_str(rest.as_str());
+            self.write_identifier(rest.as_str(), node.range);
         }
 
         self.write_str("}");
@@ -1382,7 +2272,7 @@ impl Unparser {
             }
         }
         while let Some((attr, pattern)) = kwd_iter.next() {
-            self.write_str(attr.as_str());
+            self.write_identifier(attr.as_str(), node.range);
             self.write_str("=");
             self.unparse_pattern(pattern);
             if kwd_iter.peek().is_some() {
@@ -1399,7 +2289,7 @@ impl Unparser {
             None => "_",
         };
         self.write_str("*");
-        self.write_str(name);
+        self.write_identifier(name, node.range);
     }
 
     fn unparse_pattern_match_as(&mut self, node: &PatternMatchAs<TextRange>) {
@@ -1414,14 +2304,14 @@ impl Unparser {
                         prec_self.unparse_pattern(pattern);
                     });
                     self.write_str(" as ");
-                    self.write_str(name);
+                    self.write_identifier(name, node.range);
 
                     if with_parens {
                         self.write_str(")");
                     }
                 }
                 None => {
-                    self.write_str(name);
+                    self.write_identifier(name, node.range);
                 }
             },
             None => {
@@ -1440,6 +2330,21 @@ impl Unparser {
         }
     }
 
+    fn unparse_type_params(&mut self, type_params: &[TypeParam<TextRange>]) {
+        if let Some(first) = type_params.first() {
+            self.require(Feature::TypeParameters, first.range());
+            self.write_str("[");
+            let mut type_params_iter = type_params.iter().peekable();
+            while let Some(type_param) = type_params_iter.next() {
+                self.unparse_type_param(type_param);
+                if type_params_iter.peek().is_some() {
+                    self.write_str(", ");
+                }
+            }
+            self.write_str("]");
+        }
+    }
+
     fn unparse_type_param(&mut self, node: &TypeParam<TextRange>) {
         match node {
             TypeParam::TypeVar(data) => self.unparse_type_param_type_var(data),
@@ -1449,7 +2354,7 @@ impl Unparser {
     }
 
     fn unparse_type_param_type_var(&mut self, node: &TypeParamTypeVar<TextRange>) {
-        self.write_str(&node.name);
+        self.write_identifier(&node.name, node.range);
         if let Some(bound) = &node.bound {
             self.write_str(": ");
             self.unparse_expr(bound);
@@ -1458,11 +2363,11 @@ impl Unparser {
 
     fn unparse_type_param_param_spec(&mut self, node: &TypeParamParamSpec<TextRange>) {
         self.write_str("**");
-        self.write_str(&node.name);
+        self.write_identifier(&node.name, node.range);
     }
 
     fn unparse_type_param_type_var_tuple(&mut self, node: &TypeParamTypeVarTuple<TextRange>) {
         self.write_str("*");
-        self.write_str(&node.name);
+        self.write_identifier(&node.name, node.range);
     }
 }
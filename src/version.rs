@@ -0,0 +1,616 @@
+use std::fmt;
+
+use rustpython_ast::{
+    text_size::TextRange, Arguments, Comprehension, ExceptHandler, Expr, Ranged, Stmt, TypeParam,
+    WithItem,
+};
+
+use crate::error::UnparseErrorKind;
+use crate::options::{PythonVersion, UnparserOptions};
+use crate::unparser::Unparser;
+
+/// The oldest Python version the unparser writes code for.
+pub const OLDEST_SUPPORTED_VERSION: PythonVersion = PythonVersion::PY37;
+
+/// A syntax feature that needs a newer Python version than [`OLDEST_SUPPORTED_VERSION`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Feature {
+    /// `(x := value)`, PEP 572.
+    AssignmentExpression,
+    /// `def f(a, /)`, PEP 570.
+    PositionalOnlyParameters,
+    /// Decorators that aren't a dotted name or a call of one, PEP 614.
+    RelaxedDecorators,
+    /// The `match` statement, PEP 634.
+    MatchStatement,
+    /// Context managers in parentheses like `with (open(a) as f, open(b) as g):`.
+    ParenthesizedContextManagers,
+    /// `except*`, PEP 654.
+    ExceptStar,
+    /// Starred expressions in subscripts with slices like `x[1:2, *a]`, PEP 646.
+    StarredSubscript,
+    /// Starred annotations like `*args: *Ts`, PEP 646.
+    StarredAnnotation,
+    /// The `type` statement, PEP 695.
+    TypeAlias,
+    /// Type parameters of generic functions and classes, PEP 695.
+    TypeParameters,
+    /// f-strings that can only be written with the syntax of PEP 701,
+    /// e.g. because a replacement field contains a string with both kinds of quotes.
+    Pep701FString,
+}
+
+impl Feature {
+    /// The first Python version that supports the feature.
+    pub fn version(self) -> PythonVersion {
+        match self {
+            Feature::AssignmentExpression | Feature::PositionalOnlyParameters => {
+                PythonVersion::PY38
+            }
+            Feature::RelaxedDecorators => PythonVersion::PY39,
+            Feature::MatchStatement | Feature::ParenthesizedContextManagers => PythonVersion::PY310,
+            Feature::ExceptStar | Feature::StarredSubscript | Feature::StarredAnnotation => {
+                PythonVersion::PY311
+            }
+            Feature::TypeAlias | Feature::TypeParameters | Feature::Pep701FString => {
+                PythonVersion::PY312
+            }
+        }
+    }
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Feature::AssignmentExpression => "assignment expression",
+            Feature::PositionalOnlyParameters => "positional-only parameters",
+            Feature::RelaxedDecorators => "relaxed decorator",
+            Feature::MatchStatement => "match statement",
+            Feature::ParenthesizedContextManagers => "parenthesized context managers",
+            Feature::ExceptStar => "except*",
+            Feature::StarredSubscript => "starred subscript",
+            Feature::StarredAnnotation => "starred annotation",
+            Feature::TypeAlias => "type alias statement",
+            Feature::TypeParameters => "type parameters",
+            Feature::Pep701FString => "PEP 701 f-string",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A use of a [`Feature`] in an AST.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VersionRequirement {
+    pub feature: Feature,
+    /// Where the feature is used, e.g. `body[1].body[0].value`,
+    /// using the field names of Python's `ast` module.
+    pub path: String,
+    pub range: TextRange,
+}
+
+impl VersionRequirement {
+    pub fn version(&self) -> PythonVersion {
+        self.feature.version()
+    }
+}
+
+impl fmt::Display for VersionRequirement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} requires Python {} (at {})",
+            self.feature,
+            self.version(),
+            self.path
+        )
+    }
+}
+
+/// The features an AST uses that need a newer Python version than [`OLDEST_SUPPORTED_VERSION`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VersionReport {
+    /// Every use of such a feature, in source order.
+    pub requirements: Vec<VersionRequirement>,
+}
+
+impl VersionReport {
+    /// The oldest Python version that can run the unparsed AST.
+    pub fn minimum_version(&self) -> PythonVersion {
+        self.requirements
+            .iter()
+            .map(VersionRequirement::version)
+            .max()
+            .unwrap_or(OLDEST_SUPPORTED_VERSION)
+    }
+
+    /// The requirements that raise the minimum version when going through the AST in source order,
+    /// i.e. the first use of each feature that needs a newer version than everything before it.
+    pub fn bumps(&self) -> Vec<&VersionRequirement> {
+        let mut version = OLDEST_SUPPORTED_VERSION;
+        self.requirements
+            .iter()
+            .filter(|requirement| {
+                let bumps = requirement.version() > version;
+                version = version.max(requirement.version());
+                bumps
+            })
+            .collect()
+    }
+}
+
+/// Finds the features `stmts` use that need a newer Python version than [`OLDEST_SUPPORTED_VERSION`].
+///
+/// Parenthesized context managers leave no trace in the AST,
+/// use [`analyze_version_with_source`] to find them too.
+pub fn analyze_version(stmts: &[Stmt<TextRange>]) -> VersionReport {
+    let mut analyzer = VersionAnalyzer::default();
+    analyzer.visit_stmts("body", stmts);
+    VersionReport {
+        requirements: analyzer.requirements,
+    }
+}
+
+/// Like [`analyze_version`], but also looks at the `source` `stmts` were parsed from
+/// for syntax the AST doesn't record, i.e. [`Feature::ParenthesizedContextManagers`].
+pub fn analyze_version_with_source(stmts: &[Stmt<TextRange>], source: &str) -> VersionReport {
+    let mut analyzer = VersionAnalyzer {
+        source: Some(source),
+        ..Default::default()
+    };
+    analyzer.visit_stmts("body", stmts);
+    VersionReport {
+        requirements: analyzer.requirements,
+    }
+}
+
+/// Like [`analyze_version`], but for a single expression.
+pub fn analyze_expr_version(expr: &Expr<TextRange>) -> VersionReport {
+    let mut analyzer = VersionAnalyzer::default();
+    analyzer.visit_expr(expr);
+    VersionReport {
+        requirements: analyzer.requirements,
+    }
+}
+
+/// Whether `node` is a decorator that was already valid before PEP 614.
+pub(crate) fn is_dotted_name_decorator(node: &Expr<TextRange>) -> bool {
+    fn is_dotted_name(node: &Expr<TextRange>) -> bool {
+        match node {
+            Expr::Name(_) => true,
+            Expr::Attribute(data) => is_dotted_name(&data.value),
+            _ => false,
+        }
+    }
+    match node {
+        Expr::Call(data) => is_dotted_name(&data.func),
+        _ => is_dotted_name(node),
+    }
+}
+
+/// How many more parentheses `text` opens than it closes, ignoring comments.
+fn paren_depth_change(text: &str) -> i32 {
+    text.lines()
+        .flat_map(|line| line.split('#').next().unwrap_or("").chars())
+        .map(|ch| match ch {
+            '(' => 1,
+            ')' => -1,
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Whether the subscript `slice` is written with starred expressions that aren't inside
+/// parentheses, which needs PEP 646. `x[*a, b]` is written as `x[(*a, b)]`, which is valid
+/// before it, but slices like in `x[1:2, *a]` can't be parenthesized.
+pub(crate) fn has_starred_slice(slice: &Expr<TextRange>) -> bool {
+    match slice {
+        Expr::Starred(_) => true,
+        Expr::Tuple(tuple) => {
+            tuple.elts.iter().any(|elt| elt.is_starred_expr())
+                && tuple.elts.iter().any(|elt| elt.is_slice_expr())
+        }
+        _ => false,
+    }
+}
+
+#[derive(Default)]
+struct VersionAnalyzer<'a> {
+    path: Vec<String>,
+    requirements: Vec<VersionRequirement>,
+    in_fstring: bool,
+    /// The source the AST was parsed from, if it is known.
+    source: Option<&'a str>,
+}
+
+impl VersionAnalyzer<'_> {
+    fn require(&mut self, feature: Feature, range: TextRange) {
+        self.requirements.push(VersionRequirement {
+            feature,
+            path: self.path.join("."),
+            range,
+        });
+    }
+
+    fn with_path<F>(&mut self, segment: String, f: F)
+    where
+        F: FnOnce(&mut Self),
+    {
+        self.path.push(segment);
+        f(self);
+        self.path.pop();
+    }
+
+    fn visit_stmts(&mut self, field: &str, stmts: &[Stmt<TextRange>]) {
+        for (index, stmt) in stmts.iter().enumerate() {
+            self.with_path(format!("{}[{}]", field, index), |path_self| {
+                path_self.visit_stmt(stmt);
+            });
+        }
+    }
+
+    fn visit_exprs(&mut self, field: &str, exprs: &[Expr<TextRange>]) {
+        for (index, expr) in exprs.iter().enumerate() {
+            self.with_path(format!("{}[{}]", field, index), |path_self| {
+                path_self.visit_expr(expr);
+            });
+        }
+    }
+
+    fn visit_field(&mut self, field: &str, expr: &Expr<TextRange>) {
+        self.with_path(field.to_string(), |path_self| path_self.visit_expr(expr));
+    }
+
+    fn visit_optional_field(&mut self, field: &str, expr: Option<&Expr<TextRange>>) {
+        if let Some(expr) = expr {
+            self.visit_field(field, expr);
+        }
+    }
+
+    fn visit_decorators(&mut self, decorator_list: &[Expr<TextRange>]) {
+        for (index, decorator) in decorator_list.iter().enumerate() {
+            self.with_path(format!("decorator_list[{}]", index), |path_self| {
+                if !is_dotted_name_decorator(decorator) {
+                    path_self.require(Feature::RelaxedDecorators, decorator.range());
+                }
+                path_self.visit_expr(decorator);
+            });
+        }
+    }
+
+    fn visit_type_params(&mut self, type_params: &[TypeParam<TextRange>]) {
+        for (index, type_param) in type_params.iter().enumerate() {
+            self.with_path(format!("type_params[{}]", index), |path_self| {
+                if index == 0 {
+                    path_self.require(Feature::TypeParameters, type_param.range());
+                }
+                if let TypeParam::TypeVar(data) = type_param {
+                    path_self.visit_optional_field("bound", data.bound.as_deref());
+                }
+            });
+        }
+    }
+
+    fn visit_stmt(&mut self, node: &Stmt<TextRange>) {
+        match node {
+            Stmt::FunctionDef(data) => {
+                self.visit_decorators(&data.decorator_list);
+                self.visit_type_params(&data.type_params);
+                self.with_path("args".to_string(), |path_self| {
+                    path_self.visit_arguments(&data.args);
+                });
+                self.visit_optional_field("returns", data.returns.as_deref());
+                self.visit_stmts("body", &data.body);
+            }
+            Stmt::AsyncFunctionDef(data) => {
+                self.visit_decorators(&data.decorator_list);
+                self.visit_type_params(&data.type_params);
+                self.with_path("args".to_string(), |path_self| {
+                    path_self.visit_arguments(&data.args);
+                });
+                self.visit_optional_field("returns", data.returns.as_deref());
+                self.visit_stmts("body", &data.body);
+            }
+            Stmt::ClassDef(data) => {
+                self.visit_decorators(&data.decorator_list);
+                self.visit_type_params(&data.type_params);
+                self.visit_exprs("bases", &data.bases);
+                for (index, keyword) in data.keywords.iter().enumerate() {
+                    self.visit_field(&format!("keywords[{}].value", index), &keyword.value);
+                }
+                self.visit_stmts("body", &data.body);
+            }
+            Stmt::Return(data) => self.visit_optional_field("value", data.value.as_deref()),
+            Stmt::Delete(data) => self.visit_exprs("targets", &data.targets),
+            Stmt::Assign(data) => {
+                self.visit_exprs("targets", &data.targets);
+                self.visit_field("value", &data.value);
+            }
+            Stmt::TypeAlias(data) => {
+                self.require(Feature::TypeAlias, data.range);
+                self.visit_type_params(&data.type_params);
+                self.visit_field("value", &data.value);
+            }
+            Stmt::AugAssign(data) => {
+                self.visit_field("target", &data.target);
+                self.visit_field("value", &data.value);
+            }
+            Stmt::AnnAssign(data) => {
+                self.visit_field("target", &data.target);
+                self.visit_field("annotation", &data.annotation);
+                self.visit_optional_field("value", data.value.as_deref());
+            }
+            Stmt::For(data) => {
+                self.visit_field("target", &data.target);
+                self.visit_field("iter", &data.iter);
+                self.visit_stmts("body", &data.body);
+                self.visit_stmts("orelse", &data.orelse);
+            }
+            Stmt::AsyncFor(data) => {
+                self.visit_field("target", &data.target);
+                self.visit_field("iter", &data.iter);
+                self.visit_stmts("body", &data.body);
+                self.visit_stmts("orelse", &data.orelse);
+            }
+            Stmt::While(data) => {
+                self.visit_field("test", &data.test);
+                self.visit_stmts("body", &data.body);
+                self.visit_stmts("orelse", &data.orelse);
+            }
+            Stmt::If(data) => {
+                self.visit_field("test", &data.test);
+                self.visit_stmts("body", &data.body);
+                self.visit_stmts("orelse", &data.orelse);
+            }
+            Stmt::With(data) => {
+                self.visit_with_items(data.range, &data.items);
+                self.visit_stmts("body", &data.body);
+            }
+            Stmt::AsyncWith(data) => {
+                self.visit_with_items(data.range, &data.items);
+                self.visit_stmts("body", &data.body);
+            }
+            Stmt::Match(data) => {
+                self.require(Feature::MatchStatement, data.range);
+                self.visit_field("subject", &data.subject);
+                for (index, case) in data.cases.iter().enumerate() {
+                    self.with_path(format!("cases[{}]", index), |path_self| {
+                        path_self.visit_optional_field("guard", case.guard.as_deref());
+                        path_self.visit_stmts("body", &case.body);
+                    });
+                }
+            }
+            Stmt::Raise(data) => {
+                self.visit_optional_field("exc", data.exc.as_deref());
+                self.visit_optional_field("cause", data.cause.as_deref());
+            }
+            Stmt::Try(data) => {
+                self.visit_stmts("body", &data.body);
+                self.visit_handlers(&data.handlers);
+                self.visit_stmts("orelse", &data.orelse);
+                self.visit_stmts("finalbody", &data.finalbody);
+            }
+            Stmt::TryStar(data) => {
+                self.require(Feature::ExceptStar, data.range);
+                self.visit_stmts("body", &data.body);
+                self.visit_handlers(&data.handlers);
+                self.visit_stmts("orelse", &data.orelse);
+                self.visit_stmts("finalbody", &data.finalbody);
+            }
+            Stmt::Assert(data) => {
+                self.visit_field("test", &data.test);
+                self.visit_optional_field("msg", data.msg.as_deref());
+            }
+            Stmt::Expr(data) => self.visit_field("value", &data.value),
+            Stmt::Import(_)
+            | Stmt::ImportFrom(_)
+            | Stmt::Global(_)
+            | Stmt::Nonlocal(_)
+            | Stmt::Pass(_)
+            | Stmt::Break(_)
+            | Stmt::Continue(_) => {}
+        }
+    }
+
+    fn visit_with_items(&mut self, range: TextRange, items: &[WithItem<TextRange>]) {
+        if self.has_parenthesized_items(range, items) {
+            self.require(Feature::ParenthesizedContextManagers, range);
+        }
+        for (index, item) in items.iter().enumerate() {
+            self.with_path(format!("items[{}]", index), |path_self| {
+                path_self.visit_field("context_expr", &item.context_expr);
+                path_self.visit_optional_field("optional_vars", item.optional_vars.as_deref());
+            });
+        }
+    }
+
+    /// Whether the `with` statement at `range` wraps its items in parentheses in the source.
+    /// A single item without a target like `with (a):` was already valid before.
+    fn has_parenthesized_items(&self, range: TextRange, items: &[WithItem<TextRange>]) -> bool {
+        let Some(source) = self.source else {
+            return false;
+        };
+        if items.len() == 1 && items[0].optional_vars.is_none() {
+            return false;
+        }
+        // the ranges of the items leave out their outer parentheses,
+        // which are in the gaps between them together with commas and `as`
+        let mut bounds = vec![range.start()];
+        for item in items {
+            bounds.push(item.context_expr.range().start());
+            bounds.push(item.context_expr.range().end());
+            if let Some(optional_vars) = &item.optional_vars {
+                bounds.push(optional_vars.range().start());
+                bounds.push(optional_vars.range().end());
+            }
+        }
+        // the parentheses of the statement are open from before the first item to after the last
+        let mut depth = 0;
+        bounds.chunks_exact(2).all(|gap| {
+            let Some(text) = source.get(usize::from(gap[0])..usize::from(gap[1])) else {
+                return false;
+            };
+            depth += paren_depth_change(text);
+            depth > 0
+        })
+    }
+
+    fn visit_handlers(&mut self, handlers: &[ExceptHandler<TextRange>]) {
+        for (index, handler) in handlers.iter().enumerate() {
+            let ExceptHandler::ExceptHandler(handler) = handler;
+            self.with_path(format!("handlers[{}]", index), |path_self| {
+                path_self.visit_optional_field("type", handler.type_.as_deref());
+                path_self.visit_stmts("body", &handler.body);
+            });
+        }
+    }
+
+    fn visit_arguments(&mut self, node: &Arguments<TextRange>) {
+        if let Some(first) = node.posonlyargs.first() {
+            self.require(Feature::PositionalOnlyParameters, first.def.range);
+        }
+        for (field, args) in [
+            ("posonlyargs", &node.posonlyargs),
+            ("args", &node.args),
+            ("kwonlyargs", &node.kwonlyargs),
+        ] {
+            for (index, arg) in args.iter().enumerate() {
+                self.with_path(format!("{}[{}]", field, index), |path_self| {
+                    path_self.visit_optional_field("annotation", arg.def.annotation.as_deref());
+                    path_self.visit_optional_field("default", arg.default.as_deref());
+                });
+            }
+        }
+        if let Some(vararg) = &node.vararg {
+            self.with_path("vararg".to_string(), |path_self| {
+                if let Some(annotation) = &vararg.annotation {
+                    if annotation.is_starred_expr() {
+                        path_self.require(Feature::StarredAnnotation, annotation.range());
+                    }
+                    path_self.visit_field("annotation", annotation);
+                }
+            });
+        }
+        if let Some(kwarg) = &node.kwarg {
+            self.with_path("kwarg".to_string(), |path_self| {
+                path_self.visit_optional_field("annotation", kwarg.annotation.as_deref());
+            });
+        }
+    }
+
+    fn visit_comprehensions(&mut self, generators: &[Comprehension<TextRange>]) {
+        for (index, generator) in generators.iter().enumerate() {
+            self.with_path(format!("generators[{}]", index), |path_self| {
+                path_self.visit_field("target", &generator.target);
+                path_self.visit_field("iter", &generator.iter);
+                path_self.visit_exprs("ifs", &generator.ifs);
+            });
+        }
+    }
+
+    fn visit_expr(&mut self, node: &Expr<TextRange>) {
+        match node {
+            Expr::BoolOp(data) => self.visit_exprs("values", &data.values),
+            Expr::NamedExpr(data) => {
+                self.require(Feature::AssignmentExpression, data.range);
+                self.visit_field("target", &data.target);
+                self.visit_field("value", &data.value);
+            }
+            Expr::BinOp(data) => {
+                self.visit_field("left", &data.left);
+                self.visit_field("right", &data.right);
+            }
+            Expr::UnaryOp(data) => self.visit_field("operand", &data.operand),
+            Expr::Lambda(data) => {
+                self.with_path("args".to_string(), |path_self| {
+                    path_self.visit_arguments(&data.args);
+                });
+                self.visit_field("body", &data.body);
+            }
+            Expr::IfExp(data) => {
+                self.visit_field("test", &data.test);
+                self.visit_field("body", &data.body);
+                self.visit_field("orelse", &data.orelse);
+            }
+            Expr::Dict(data) => {
+                for (index, key) in data.keys.iter().enumerate() {
+                    self.visit_optional_field(&format!("keys[{}]", index), key.as_ref());
+                }
+                self.visit_exprs("values", &data.values);
+            }
+            Expr::Set(data) => self.visit_exprs("elts", &data.elts),
+            Expr::ListComp(data) => {
+                self.visit_field("elt", &data.elt);
+                self.visit_comprehensions(&data.generators);
+            }
+            Expr::SetComp(data) => {
+                self.visit_field("elt", &data.elt);
+                self.visit_comprehensions(&data.generators);
+            }
+            Expr::DictComp(data) => {
+                self.visit_field("key", &data.key);
+                self.visit_field("value", &data.value);
+                self.visit_comprehensions(&data.generators);
+            }
+            Expr::GeneratorExp(data) => {
+                self.visit_field("elt", &data.elt);
+                self.visit_comprehensions(&data.generators);
+            }
+            Expr::Await(data) => self.visit_field("value", &data.value),
+            Expr::Yield(data) => self.visit_optional_field("value", data.value.as_deref()),
+            Expr::YieldFrom(data) => self.visit_field("value", &data.value),
+            Expr::Compare(data) => {
+                self.visit_field("left", &data.left);
+                self.visit_exprs("comparators", &data.comparators);
+            }
+            Expr::Call(data) => {
+                self.visit_field("func", &data.func);
+                self.visit_exprs("args", &data.args);
+                for (index, keyword) in data.keywords.iter().enumerate() {
+                    self.visit_field(&format!("keywords[{}].value", index), &keyword.value);
+                }
+            }
+            Expr::FormattedValue(data) => {
+                self.visit_field("value", &data.value);
+                self.visit_optional_field("format_spec", data.format_spec.as_deref());
+            }
+            Expr::JoinedStr(data) => {
+                if !self.in_fstring && Self::needs_pep701(node) {
+                    self.require(Feature::Pep701FString, data.range);
+                }
+                let in_fstring = std::mem::replace(&mut self.in_fstring, true);
+                self.visit_exprs("values", &data.values);
+                self.in_fstring = in_fstring;
+            }
+            Expr::Constant(_) | Expr::Name(_) => {}
+            Expr::Attribute(data) => self.visit_field("value", &data.value),
+            Expr::Subscript(data) => {
+                if has_starred_slice(&data.slice) {
+                    self.require(Feature::StarredSubscript, data.slice.range());
+                }
+                self.visit_field("value", &data.value);
+                self.visit_field("slice", &data.slice);
+            }
+            Expr::Starred(data) => self.visit_field("value", &data.value),
+            Expr::List(data) => self.visit_exprs("elts", &data.elts),
+            Expr::Tuple(data) => self.visit_exprs("elts", &data.elts),
+            Expr::Slice(data) => {
+                self.visit_optional_field("lower", data.lower.as_deref());
+                self.visit_optional_field("upper", data.upper.as_deref());
+                self.visit_optional_field("step", data.step.as_deref());
+            }
+        }
+    }
+
+    /// Whether the unparser can only write the f-string `node` with the syntax of PEP 701.
+    fn needs_pep701(node: &Expr<TextRange>) -> bool {
+        let mut unparser = Unparser::with_options(UnparserOptions {
+            target_version: Some(PythonVersion::PY311),
+            ..Default::default()
+        });
+        unparser.unparse_expr(node);
+        unparser
+            .errors()
+            .iter()
+            .any(|error| error.kind == UnparseErrorKind::FStringNeedsPep701)
+    }
+}
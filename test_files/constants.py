@@ -1,3 +1,13 @@
 escaped_string_expr = "'\"'''\"\"\"{}\\"
 escaped_unicode_expr = u"'\"'''\"\"\"{}\\"  # fmt: skip
 escaped_bytes_expr = b"'\"'''\"\"\"{}\\"
+floats = (1.0, 1.5, 1e100, 1e309, 0.1)
+complex_numbers = (2j, 1.5j, 1e309j)
+multi_line = "first line\nsecond 'line'\n"
+multi_line_quotes = 'ends with a quote"\n""'
+backslashes = "C:\\Users\\name"
+backslashes_with_quotes = "C:\\Users\\'name'"
+trailing_backslash = "a\\"
+control_characters = "\t\r\x00\x7f"
+non_printable = "\u2028 \U0001f600 é"
+non_ascii_bytes = b"\x00\xff\\"
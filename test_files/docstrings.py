@@ -0,0 +1,44 @@
+"""Module docstring.
+
+With a second paragraph.
+"""
+
+import os
+
+
+class Documented:
+    """Class docstring with "quotes" and a trailing quote\""""
+
+    def method(self):
+        """Summary line.
+
+        Details that are indented
+            and nested further.
+        """
+        "not a docstring"
+        return os.sep
+
+
+def one_line():
+    """One line."""
+
+
+def raw_docstring():
+    r"""Backslashes \d+ and tabs	and unicode é."""
+
+
+def not_a_docstring():
+    f"""{os.sep}"""
+    b"""bytes aren't docstrings either"""
+    return "not a docstring"
+
+
+def unicode_prefix():
+    u"""Unicode prefix."""
+
+
+def ends_with_backslash():
+    """Ends with a backslash\\"""
+
+
+"a string that isn't a module docstring"
@@ -0,0 +1,25 @@
+width = 10
+precision = 3
+value = 12.34567
+items = {"a": 1}
+f"{value:{width}.{precision}}"
+f"{value:{width}.{precision}f}|{value!r:>{width}}"
+f"{value:{'{'}>{width}}"
+f"{{literal}} {{{value}}}"
+f"{value!a} {value!s} {value!r}"
+f"{value!a:>20}"
+f"{ {'a': 1}['a']}"
+f"{ {1, 2}}"
+f"{ {x for x in range(3)}}"
+f"{(lambda x: x * 2)(3)}"
+f"{(lambda: 1)}"
+f"{value if value else (lambda: 0)}"
+f"{(y := 10)}"
+f"{items['a']:>{width}}"
+f"{value:'>10}"
+f'{value:">10}'
+f"{value:#x}" if False else None
+f"{value=}"
+f"{value = :.2f}"
+f"{value=!s:>{width}}"
+f"{width + precision=}"
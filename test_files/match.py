@@ -20,3 +20,9 @@ match {name_5: name_2}:
         print(field1_value, field2_value, **kwargs)  # type: ignore
     case _ if lambda *name_4, **name_2: name_5:  # type: ignore
         pass
+
+match name_2:
+    case -1 | 1 + 2j | -1.5 - 2j:
+        pass
+    case {-1: _, 1 + 2j: _}:
+        pass
@@ -0,0 +1,229 @@
+from collections.abc import Mapping
+from dataclasses import dataclass
+from enum import Enum
+
+
+class Color(Enum):
+    RED = 1
+    GREEN = 2
+
+
+@dataclass
+class Point:
+    x: int
+    y: int
+
+
+class Circle:
+    __match_args__ = ("center", "radius")
+
+    def __init__(self, center, radius):
+        self.center = center
+        self.radius = radius
+
+    def __repr__(self):
+        return "Circle(" + repr(self.center) + ", " + repr(self.radius) + ")"
+
+
+def describe(subject):
+    match subject:
+        case None:
+            return "none"
+        case True:
+            return "true"
+        case 0 | 0.0:
+            return "zero"
+        case Color.RED:
+            return "red"
+        case "hello" | b"hello":
+            return "greeting"
+        case int(n) if n < 0:
+            return f"negative {n}"
+        case int() | float() as number if number > 100:
+            return f"big {number}"
+        case str(text):
+            return f"text {text!r}"
+        case []:
+            return "empty sequence"
+        case [x]:
+            return f"one item {x}"
+        case [1, *middle, 9]:
+            return f"one to nine via {middle}"
+        case [first, *_]  if first == "skip":
+            return "skipped"
+        case [first, second, *rest] if len(rest) > 2:
+            return f"long {first} {second} {rest}"
+        case (a, b) | [a, b, _]:
+            return f"pair {a} {b}"
+        case {"action": "move", "to": Point(x=0, y=0)}:
+            return "move home"
+        case {"action": "move", "to": Point(x, y=y_value)}:
+            return f"move {x} {y_value}"
+        case {"action": action, **options} if options:
+            return f"{action} with {sorted(options.items())}"
+        case {"action": action}:
+            return f"just {action}"
+        case Circle(Point(x=cx, y=cy), radius=r) if r > 0:
+            return f"circle at {cx},{cy} r={r}"
+        case Circle(center, radius):
+            return f"degenerate circle {center} {radius}"
+        case Point(x=0) | Point(y=0) as axis_point:
+            return f"on an axis {axis_point}"
+        case {1: [*values]} | {2: values}:
+            return f"values {values}"
+        case other:
+            return f"other {type(other).__name__}"
+
+
+def first_even(numbers):
+    found = []
+    for number in numbers:
+        match number:
+            case int(value) if value % 2:
+                continue
+            case 100:
+                break
+            case value:
+                found.append(value)
+    return found
+
+
+def nested(subject):
+    match subject:
+        case [inner]:
+            match inner:
+                case {"kind": kind}:
+                    return f"nested {kind}"
+                case _:
+                    return "nested something"
+        case _:
+            return "not nested"
+
+
+def no_match(subject):
+    result = "unmatched"
+    match subject:
+        case 1:
+            result = "one"
+        case 2:
+            result = "two"
+    return result
+
+
+class NoArgs:
+    pass
+
+
+class Counted:
+    __match_args__ = ("value",)
+    reads = 0
+
+    @property
+    def value(self):
+        Counted.reads += 1
+        return [Counted.reads, 2]
+
+
+def positional_errors(subject):
+    try:
+        match subject:
+            case NoArgs(x):
+                return f"no args {x}"
+            case Circle(a, b, c):
+                return "three"
+    except TypeError as error:
+        return f"TypeError: {error}"
+    return "no error"
+
+
+def counted(subject):
+    match subject:
+        case Counted([first, second] as pair) if first > 5:
+            return f"never {pair}"
+        case Counted(value=[first, *_]):
+            return f"read {first}, {Counted.reads} reads"
+
+
+class CountedKeys(type):
+    reads = 0
+
+    @property
+    def NAME(cls):
+        CountedKeys.reads += 1
+        return "name"
+
+
+class Keys(metaclass=CountedKeys):
+    pass
+
+
+class CountedMapping(Mapping):
+    def __init__(self, items):
+        self.items = items
+        self.lookups = 0
+
+    def __getitem__(self, key):
+        self.lookups += 1
+        return self.items[key]
+
+    def __iter__(self):
+        return iter(self.items)
+
+    def __len__(self):
+        return len(self.items)
+
+
+def lookups(subject):
+    match subject:
+        case {Keys.NAME: value, **rest}:
+            return f"{value} {rest}, {subject.lookups} lookups, {CountedKeys.reads} key reads"
+
+
+subjects = [
+    None,
+    True,
+    False,
+    0,
+    0.0,
+    Color.RED,
+    Color.GREEN,
+    "hello",
+    b"hello",
+    -5,
+    500,
+    250.5,
+    7,
+    "text",
+    [],
+    (),
+    [42],
+    (1, 2, 3, 9),
+    [1, 9],
+    ["skip", 2, 3],
+    [1, 2, 3, 4, 5, 6],
+    (1, 2),
+    [3, 4, 5],
+    [3, 4, 5, 6],
+    {"action": "move", "to": Point(0, 0)},
+    {"action": "move", "to": Point(3, 4)},
+    {"action": "jump", "height": 2, "speed": 3},
+    {"action": "jump"},
+    Circle(Point(1, 2), 3),
+    Circle(Point(1, 2), 0),
+    Point(0, 5),
+    Point(5, 0),
+    Point(5, 5),
+    {1: [1, 2]},
+    {2: "two"},
+    {3: 3},
+    range(2),
+    bytearray(b"ab"),
+]
+for subject in subjects:
+    print(repr(subject), "->", describe(subject))
+print(first_even([1, 2, 3, 4, 100, 6]))
+print(nested([{"kind": "leaf"}]), nested([1]), nested(1))
+print(no_match(1), no_match(2), no_match(3))
+print(positional_errors(NoArgs()), positional_errors(Circle(1, 2)), positional_errors(1))
+print(counted(Counted()))
+print(lookups(CountedMapping({"name": "x", "size": 1})))
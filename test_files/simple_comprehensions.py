@@ -5,3 +5,23 @@ generator = (value for value in random.choices(string.ascii_letters))
 set_ = {def_ for def_ in random.choices(string.ascii_letters)}
 dict_ = {k: v for k, v in enumerate(random.choices(string.ascii_letters))}
 list_ = [value for value in random.choices(string.ascii_letters)]
+filtered = [value for value in random.choices(string.ascii_letters) if value]
+multi_filtered = [value for value in range(10) if value > 2 if value % 2 if not value == 7]
+nested = [(x, y) for x in range(3) for y in range(x) if x != y]
+tuple_target = {k: v for (k, v) in enumerate("abc") if k and v}
+walrus_element = [(y := value * 2) for value in range(3)]
+walrus_generator = ((y := value) for value in range(3))
+walrus_condition = [value for value in range(5) if (y := value % 2)]
+walrus_iter = [value for value in (values := range(5))]
+conditional_element = [value if value else None for value in range(3)]
+conditional_iter = [value for value in (range(3) if True else range(5))]
+conditional_condition = [value for value in range(3) if (value if value else False)]
+lambda_element = [lambda: value for value in range(3)]
+lambda_condition = [value for value in range(3) if (lambda: value)]
+boolop_condition = [value for value in range(3) if value or not value and True]
+generator_element = [(inner for inner in range(value)) for value in range(3)]
+tuple_element = [(value, value) for value in range(3)]
+
+
+async def async_comprehension():
+    return [value async for value in aiter_() if await value]  # type: ignore # noqa: F821
@@ -0,0 +1,40 @@
+import random
+
+a, b = 1, 2
+a, b = b, a
+(a, b), c = (1, 2), 3
+(single,) = (1,)
+first, *rest = range(5)
+values = *rest, first
+empty = ()
+a += 1, 2
+for index, value in enumerate(rest):
+    pass
+for (index, value), other in zip(enumerate(rest), rest):
+    pass
+matrix = {}
+matrix[1, 2] = 3
+matrix[1:2, ::3] = matrix[(1, 2)]
+matrix[(*rest, 1)] = None
+print((1, 2), [(1, 2)], {(1, 2): (3, 4)}, (1, 2)[0], not (a, b))
+nested = ((1, 2), (3, (4, 5)))
+comparison = (1, 2) < (3, 4)
+lambda_tuple = lambda: (1, 2)
+conditional = (1, 2) if random.random() else (3, 4)
+del (a, b)
+del a, b  # type: ignore # noqa: F821
+
+
+def returns_tuple():
+    return 1, 2
+
+
+def returns_starred():
+    return (*rest, first)
+
+
+def yields_tuple():
+    yield 1, 2
+    yield (*rest, first)
+    received = yield 1, 2
+    return received